@@ -0,0 +1,263 @@
+// Minimal admin UI for route96, uses a NIP-07 extension to sign NIP-98 auth events
+const API = "/v1/admin";
+const PAGE_SIZE = 50;
+const pages = { files: 0, users: 0, audit: 0, reports: 0 };
+let plans = [];
+
+async function req(path, method = "GET") {
+  const url = `${location.origin}${API}${path}`;
+  const ev = await window.nostr.signEvent({
+    kind: 27235,
+    created_at: Math.floor(Date.now() / 1000),
+    content: "",
+    tags: [
+      ["u", url],
+      ["method", method],
+    ],
+  });
+  const rsp = await fetch(url, {
+    method,
+    headers: {
+      accept: "application/json",
+      authorization: `Nostr ${btoa(JSON.stringify(ev))}`,
+    },
+  });
+  const json = await rsp.json();
+  if (json.status !== "success") {
+    throw new Error(json.message ?? `Request failed: ${rsp.status}`);
+  }
+  return json.data;
+}
+
+function formatBytes(n) {
+  const units = ["B", "KiB", "MiB", "GiB", "TiB"];
+  let i = 0;
+  while (n >= 1024 && i < units.length - 1) {
+    n /= 1024;
+    i++;
+  }
+  return `${n.toFixed(i === 0 ? 0 : 2)} ${units[i]}`;
+}
+
+function el(tag, props = {}, children = []) {
+  const e = Object.assign(document.createElement(tag), props);
+  e.append(...children);
+  return e;
+}
+
+function showError(e) {
+  document.getElementById("error").textContent = e?.message ?? String(e);
+}
+
+async function loadStats() {
+  const s = await req("/stats");
+  const rows = [
+    ["Files", s.files],
+    ["Total size", formatBytes(s.total_size)],
+    ["Users", s.users],
+  ];
+  if (s.free_space !== undefined) {
+    rows.push(["Free space", formatBytes(s.free_space)]);
+  }
+  document
+    .getElementById("stats")
+    .replaceChildren(
+      ...rows.flatMap(([k, v]) => [
+        el("dt", { textContent: k }),
+        el("dd", { textContent: v }),
+      ]),
+    );
+}
+
+function preview(ev) {
+  const tag = (k) => ev.tags.find((t) => t[0] === k)?.[1];
+  const url = tag("url");
+  const mime = tag("m") ?? "";
+  let inner;
+  if (mime.startsWith("image/")) {
+    inner = el("img", { src: url, loading: "lazy", alt: ev.content });
+  } else if (mime.startsWith("video/")) {
+    inner = el("video", { src: url, preload: "metadata", muted: true });
+  } else {
+    inner = el("span", { textContent: `${mime} ${ev.content}` });
+  }
+  const remove = el("button", {
+    textContent: "Remove",
+    onclick: () => takedown(tag("x")).catch(showError),
+  });
+  return el("div", { className: "file" }, [
+    el("a", { href: url, target: "_blank", title: tag("x") }, [inner]),
+    remove,
+  ]);
+}
+
+async function takedown(id) {
+  const reason = prompt(`Reason for removing ${id}`);
+  if (reason === null) {
+    return;
+  }
+  const q = new URLSearchParams();
+  if (reason) {
+    q.set("reason", reason);
+  }
+  if (confirm("Send the uploader a DM about this removal?")) {
+    q.set("notify", "true");
+  }
+  await req(`/file/${id}?${q}`, "DELETE");
+  await Promise.all([loadFiles(), loadAudit(), loadReports()]);
+}
+
+async function dismissReport(id) {
+  await req(`/report/${id}`, "DELETE");
+  await Promise.all([loadReports(), loadAudit()]);
+}
+
+function reportRow(r) {
+  return el("tr", {}, [
+    el("td", {}, [
+      el("a", {
+        href: `${location.origin}/${r.file}`,
+        target: "_blank",
+        textContent: r.file.slice(0, 12),
+        title: r.file,
+      }),
+    ]),
+    el("td", { textContent: r.reason }),
+    el("td", { textContent: r.reporter }),
+    el("td", { textContent: new Date(r.created).toLocaleString() }),
+    el("td", {}, [
+      el("button", {
+        textContent: "Remove",
+        onclick: () => takedown(r.file).catch(showError),
+      }),
+      el("button", {
+        textContent: "Dismiss",
+        onclick: () => dismissReport(r.id).catch(showError),
+      }),
+    ]),
+  ]);
+}
+
+async function loadReports() {
+  const data = await req(`/reports?page=${pages.reports}&count=${PAGE_SIZE}`);
+  document
+    .querySelector("#reports tbody")
+    .replaceChildren(...data.files.map(reportRow));
+  renderPager("reports", data);
+}
+
+async function loadFiles() {
+  const data = await req(`/files?page=${pages.files}&count=${PAGE_SIZE}`);
+  document.getElementById("files").replaceChildren(...data.files.map(preview));
+  renderPager("files", data);
+}
+
+async function setPlan(pubkey, plan) {
+  const q = plan ? `?plan=${encodeURIComponent(plan)}` : "";
+  await req(`/user/${pubkey}/plan${q}`, "PUT");
+}
+
+function userRow(u) {
+  const select = el(
+    "select",
+    {
+      onchange: (e) => setPlan(u.pubkey, e.target.value).catch(showError),
+    },
+    [
+      el("option", { value: "", textContent: "(default)" }),
+      ...plans.map((p) =>
+        el("option", { value: p, textContent: p, selected: u.plan === p }),
+      ),
+    ],
+  );
+  return el("tr", {}, [
+    el("td", { textContent: u.pubkey }),
+    el("td", { textContent: new Date(u.created).toLocaleString() }),
+    el("td", { textContent: u.is_admin ? "yes" : "" }),
+    el("td", {}, [select]),
+  ]);
+}
+
+async function loadUsers() {
+  const data = await req(`/users?page=${pages.users}&count=${PAGE_SIZE}`);
+  document
+    .querySelector("#users tbody")
+    .replaceChildren(...data.files.map(userRow));
+  renderPager("users", data);
+}
+
+async function loadAudit() {
+  const data = await req(`/audit?page=${pages.audit}&count=${PAGE_SIZE}`);
+  document.querySelector("#audit tbody").replaceChildren(
+    ...data.files.map((a) =>
+      el("tr", {}, [
+        el("td", { textContent: new Date(a.created).toLocaleString() }),
+        el("td", { textContent: a.actor }),
+        el("td", { textContent: a.action }),
+        el("td", { textContent: a.target }),
+        el("td", { textContent: a.reason ?? "" }),
+      ]),
+    ),
+  );
+  renderPager("audit", data);
+}
+
+const loaders = {
+  files: loadFiles,
+  users: loadUsers,
+  audit: loadAudit,
+  reports: loadReports,
+};
+
+function renderPager(list, data) {
+  const last = Math.max(0, Math.ceil(data.total / PAGE_SIZE) - 1);
+  const go = (p) => () => {
+    pages[list] = p;
+    loaders[list]().catch(showError);
+  };
+  document.querySelector(`.pager[data-list="${list}"]`).replaceChildren(
+    el("button", {
+      textContent: "Prev",
+      disabled: data.page === 0,
+      onclick: go(data.page - 1),
+    }),
+    el("span", { textContent: `${data.page + 1} / ${last + 1}` }),
+    el("button", {
+      textContent: "Next",
+      disabled: data.page >= last,
+      onclick: go(data.page + 1),
+    }),
+  );
+}
+
+async function loadPlans() {
+  const rsp = await fetch("/.well-known/nostr/nip96.json");
+  if (rsp.ok) {
+    const doc = await rsp.json();
+    plans = Object.keys(doc.plans ?? {}).filter((p) => p !== "anonymous");
+  }
+}
+
+document.getElementById("login").onclick = async () => {
+  try {
+    if (!window.nostr) {
+      throw new Error("A NIP-07 browser extension is required");
+    }
+    const self = await req("/self");
+    if (!self.is_admin) {
+      throw new Error("You are not an admin");
+    }
+    showError("");
+    document.getElementById("app").hidden = false;
+    await loadPlans();
+    await Promise.all([
+      loadStats(),
+      loadReports(),
+      loadFiles(),
+      loadUsers(),
+      loadAudit(),
+    ]);
+  } catch (e) {
+    showError(e);
+  }
+};
@@ -0,0 +1,4 @@
+alter table uploads
+    add column storage_class enum('hot','cold') not null default 'hot',
+    add column storage_class_changed timestamp null;
+create index ix_uploads_storage_class on uploads (storage_class);
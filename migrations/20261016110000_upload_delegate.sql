@@ -0,0 +1,2 @@
+alter table user_uploads
+    add column delegate binary(32) null;
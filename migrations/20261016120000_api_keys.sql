@@ -0,0 +1,14 @@
+create table api_keys
+(
+    id       integer unsigned not null auto_increment primary key,
+    user_id  integer unsigned not null,
+    key_hash binary(32)       not null,
+    name     varchar(128),
+    created  timestamp default current_timestamp,
+
+    constraint fk_api_keys_user_id
+        foreign key (user_id) references users (id)
+            on delete cascade
+            on update restrict
+);
+create unique index ix_api_keys_key_hash on api_keys (key_hash);
@@ -0,0 +1,3 @@
+alter table user_uploads
+    add column expires timestamp null;
+create index ix_user_uploads_expires on user_uploads (expires);
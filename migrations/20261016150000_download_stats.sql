@@ -0,0 +1,4 @@
+alter table uploads
+    add column downloads integer unsigned not null default 0,
+    add column last_accessed timestamp null;
+create index ix_uploads_last_accessed on uploads (last_accessed);
@@ -0,0 +1,12 @@
+create table user_bandwidth
+(
+    user_id integer unsigned not null,
+    month   char(7)          not null,
+    bytes   bigint unsigned  not null default 0,
+
+    primary key (user_id, month),
+    constraint fk_user_bandwidth_user_id
+        foreign key (user_id) references users (id)
+            on delete cascade
+            on update restrict
+);
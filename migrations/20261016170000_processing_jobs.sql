@@ -0,0 +1,17 @@
+create table processing_jobs
+(
+    id           integer unsigned not null auto_increment primary key,
+    file         binary(32)       not null,
+    kind         enum('label')    not null,
+    state        enum('queued','running','done','failed') not null default 'queued',
+    attempts     integer unsigned not null default 0,
+    last_error   varchar(1024),
+    next_attempt timestamp        not null default current_timestamp,
+    created      timestamp        not null default current_timestamp,
+
+    constraint fk_processing_jobs_file_id
+        foreign key (file) references uploads (id)
+            on delete cascade
+            on update restrict
+);
+create index ix_processing_jobs_state on processing_jobs (state, next_attempt);
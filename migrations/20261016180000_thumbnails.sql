@@ -0,0 +1,4 @@
+alter table uploads
+    add column thumb_mime varchar(128);
+alter table processing_jobs
+    modify column kind enum('label','thumbnail') not null;
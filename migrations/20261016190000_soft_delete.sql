@@ -0,0 +1,5 @@
+alter table user_uploads
+    add column deleted timestamp null;
+alter table uploads
+    add column deleted timestamp null;
+create index ix_user_uploads_deleted on user_uploads (deleted);
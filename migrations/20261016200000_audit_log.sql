@@ -0,0 +1,14 @@
+create table audit_log
+(
+    id      integer unsigned not null auto_increment primary key,
+    actor   binary(32)       not null,
+    action  enum('delete','restore','takedown','set_plan') not null,
+    target  varchar(128)     not null,
+    reason  varchar(1024),
+    created timestamp        not null default current_timestamp
+);
+create index ix_audit_log_created on audit_log (created);
+create trigger audit_log_no_update before update on audit_log
+    for each row signal sqlstate '45000' set message_text = 'audit_log is append-only';
+create trigger audit_log_no_delete before delete on audit_log
+    for each row signal sqlstate '45000' set message_text = 'audit_log is append-only';
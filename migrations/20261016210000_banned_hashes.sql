@@ -0,0 +1,6 @@
+create table banned_hashes
+(
+    hash    binary(32)   not null primary key,
+    reason  varchar(1024),
+    created timestamp    not null default current_timestamp
+);
@@ -0,0 +1,3 @@
+alter table uploads
+    add column original_hash binary(32);
+create index ix_uploads_original_hash on uploads (original_hash);
@@ -0,0 +1,2 @@
+alter table uploads
+    add column duration float;
@@ -0,0 +1,3 @@
+alter table uploads
+    add column blake3 binary(32);
+create index ix_uploads_blake3 on uploads (blake3);
@@ -0,0 +1,3 @@
+alter table user_uploads
+    add column public bit(1) not null default 0;
+create index ix_user_uploads_public on user_uploads (public, created);
@@ -0,0 +1,19 @@
+create table upload_variants
+(
+    file      binary(32)       not null,
+    id        binary(32)       not null,
+    kind      enum('thumbnail','transcode','conversion') not null,
+    size      bigint unsigned  not null,
+    mime_type varchar(255)     not null,
+    width     integer unsigned,
+    height    integer unsigned,
+    codec     varchar(64),
+    created   timestamp        not null default current_timestamp,
+
+    constraint fk_upload_variants_file_id
+        foreign key (file) references uploads (id)
+            on delete cascade
+            on update restrict
+);
+create unique index ix_upload_variants_file_id on upload_variants (file, id);
+create index ix_upload_variants_id on upload_variants (id);
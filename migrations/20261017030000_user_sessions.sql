@@ -0,0 +1,15 @@
+create table user_sessions
+(
+    id         integer unsigned not null auto_increment primary key,
+    user_id    integer unsigned not null,
+    token_hash binary(32)       not null,
+    created    timestamp        not null default current_timestamp,
+    expires    timestamp        not null,
+
+    constraint fk_user_sessions_user_id
+        foreign key (user_id) references users (id)
+            on delete cascade
+            on update restrict
+);
+create unique index ix_user_sessions_token_hash on user_sessions (token_hash);
+create index ix_user_sessions_expires on user_sessions (expires);
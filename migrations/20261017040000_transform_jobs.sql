@@ -0,0 +1,2 @@
+alter table processing_jobs
+    modify column kind enum('label','thumbnail','transform') not null;
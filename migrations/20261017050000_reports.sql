@@ -0,0 +1,22 @@
+create table reports
+(
+    id          integer unsigned not null auto_increment primary key,
+    file        binary(32)       not null,
+    reporter_id integer unsigned not null,
+    reason      varchar(1024)    not null,
+    created     timestamp        not null default current_timestamp,
+    reviewed    bit(1)           not null default 0,
+
+    constraint fk_reports_file
+        foreign key (file) references uploads (id)
+            on delete cascade
+            on update restrict,
+    constraint fk_reports_reporter_id
+        foreign key (reporter_id) references users (id)
+            on delete cascade
+            on update restrict
+);
+create unique index ix_reports_file_reporter on reports (file, reporter_id);
+create index ix_reports_reviewed on reports (reviewed, created);
+alter table audit_log
+    modify action enum ('delete','restore','takedown','set_plan','dismiss_report') not null;
@@ -0,0 +1,2 @@
+alter table audit_log
+    modify action enum ('delete','restore','takedown','set_plan','dismiss_report','ban') not null;
@@ -1,14 +1,24 @@
+use std::time::Duration;
+
 use anyhow::Error;
 use log::warn;
 use rocket::fairing::{Fairing, Info, Kind};
-use rocket::{Data, Request};
+use rocket::{Orbit, Request, Response, Rocket};
 
 pub mod plausible;
 
 pub trait Analytics {
-    fn track(&self, req: &Request) -> Result<(), Error>;
+    fn track(&self, req: &Request, res: &Response) -> Result<(), Error>;
+
+    /// Number of events which have not been sent yet
+    fn pending(&self) -> usize {
+        0
+    }
 }
 
+/// Max time to wait for queued events to be sent on shutdown
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub struct AnalyticsFairing {
     inner: Box<dyn Analytics + Sync + Send>,
 }
@@ -29,12 +39,22 @@ impl Fairing for AnalyticsFairing {
     fn info(&self) -> Info {
         Info {
             name: "Analytics",
-            kind: Kind::Request,
+            kind: Kind::Response | Kind::Shutdown,
+        }
+    }
+
+    async fn on_shutdown(&self, _rocket: &Rocket<Orbit>) {
+        let start = tokio::time::Instant::now();
+        while self.inner.pending() > 0 && start.elapsed() < FLUSH_TIMEOUT {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        if self.inner.pending() > 0 {
+            warn!("{} analytics events were not sent", self.inner.pending());
         }
     }
 
-    async fn on_request(&self, req: &mut Request<'_>, _data: &mut Data<'_>) {
-        if let Err(e) = self.inner.track(req) {
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        if let Err(e) = self.inner.track(req, res) {
             warn!("Failed to track! {}", e);
         }
     }
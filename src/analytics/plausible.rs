@@ -1,12 +1,21 @@
 use crate::analytics::Analytics;
+use crate::geoip::GeoIp;
 use crate::settings::Settings;
 use anyhow::Error;
 use log::{info, warn};
-use reqwest::ClientBuilder;
-use rocket::Request;
+use reqwest::{Client, ClientBuilder};
+use rocket::http::Method;
+use rocket::{Request, Response};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::task::JoinSet;
+
+/// Max number of events sent at the same time
+const MAX_CONCURRENT: usize = 50;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Event {
@@ -14,6 +23,8 @@ struct Event {
     pub domain: String,
     pub url: String,
     pub referrer: Option<String>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub props: HashMap<String, String>,
     #[serde(skip_serializing)]
     pub user_agent: Option<String>,
     #[serde(skip_serializing)]
@@ -22,10 +33,13 @@ struct Event {
 
 pub struct PlausibleAnalytics {
     tx: UnboundedSender<Event>,
+    geoip: Option<GeoIp>,
+    /// Events queued or being sent
+    pending: Arc<AtomicUsize>,
 }
 
 impl PlausibleAnalytics {
-    pub fn new(settings: &Settings) -> Self {
+    pub fn new(settings: &Settings, geoip: Option<GeoIp>) -> Self {
         let (tx, mut rx) = unbounded_channel::<Event>();
         let url = match &settings.plausible_url {
             Some(s) => s.clone(),
@@ -33,50 +47,140 @@ impl PlausibleAnalytics {
         };
         let pub_url = settings.public_url.clone();
         let c = ClientBuilder::new().build().unwrap();
+        let pending = Arc::new(AtomicUsize::new(0));
+        let sent = pending.clone();
         tokio::spawn(async move {
-            while let Some(mut msg) = rx.recv().await {
-                msg.url = format!("{}{}", pub_url, msg.url);
-                match c
-                    .post(format!("{}/api/event", url))
-                    .header(
-                        "user-agent",
-                        match &msg.user_agent {
-                            Some(s) => s,
-                            None => "",
-                        },
-                    )
-                    .header(
-                        "x-forwarded-for",
-                        match &msg.xff {
-                            Some(s) => s,
-                            None => "",
-                        },
-                    )
-                    .json(&msg)
-                    .timeout(Duration::from_secs(30))
-                    .send()
-                    .await
-                {
-                    Ok(_v) => info!("Sent {:?}", msg),
-                    Err(e) => warn!("Failed to track: {}", e),
+            // the plausible events api only accepts one event per request, queued events
+            // are sent concurrently over a shared connection pool
+            let mut queued = Vec::with_capacity(MAX_CONCURRENT);
+            while rx.recv_many(&mut queued, MAX_CONCURRENT).await > 0 {
+                let mut jobs = JoinSet::new();
+                for mut msg in queued.drain(..) {
+                    msg.url = format!("{}{}", pub_url, msg.url);
+                    jobs.spawn(Self::send(c.clone(), url.clone(), msg));
+                }
+                let total = jobs.len();
+                let mut failed = 0;
+                while let Some(r) = jobs.join_next().await {
+                    if let Ok(Err(e)) = r {
+                        warn!("Failed to track: {}", e);
+                        failed += 1;
+                    }
                 }
+                info!("Sent {} analytics events ({} failed)", total, failed);
+                sent.fetch_sub(total, Ordering::SeqCst);
             }
         });
 
-        Self { tx }
+        Self { tx, geoip, pending }
+    }
+
+    async fn send(c: Client, url: String, msg: Event) -> Result<(), reqwest::Error> {
+        c.post(format!("{}/api/event", url))
+            .header(
+                "user-agent",
+                match &msg.user_agent {
+                    Some(s) => s,
+                    None => "",
+                },
+            )
+            .header(
+                "x-forwarded-for",
+                match &msg.xff {
+                    Some(s) => s,
+                    None => "",
+                },
+            )
+            .json(&msg)
+            .timeout(Duration::from_secs(30))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Event name based on the route which handled the request
+    fn event_name(req: &Request, res: &Response) -> &'static str {
+        if !res.status().class().is_success() {
+            return "pageview";
+        }
+        let route = match req.route() {
+            Some(r) => r,
+            None => return "pageview",
+        };
+        let name = route.name.as_deref().unwrap_or("");
+        match req.method() {
+            Method::Get if name == "get_blob" => "download",
+            Method::Put | Method::Post if name.starts_with("upload") => "upload",
+            Method::Delete if matches!(name, "delete_blob" | "delete") => "delete",
+            Method::Post if name == "delete_batch" => "delete",
+            _ => "pageview",
+        }
+    }
+
+    /// Bucket file sizes to avoid high cardinality props
+    fn size_bucket(size: u64) -> &'static str {
+        const MB: u64 = 1024 * 1024;
+        match size {
+            s if s < MB => "<1MB",
+            s if s < 10 * MB => "1-10MB",
+            s if s < 100 * MB => "10-100MB",
+            s if s < 1024 * MB => "100MB-1GB",
+            _ => ">1GB",
+        }
     }
 }
 
 impl Analytics for PlausibleAnalytics {
-    fn track(&self, req: &Request) -> Result<(), Error> {
+    fn track(&self, req: &Request, res: &Response) -> Result<(), Error> {
+        let name = Self::event_name(req, res);
+        let mut props = HashMap::new();
+        if let Some(r) = req.route() {
+            props.insert("route".to_string(), r.uri.origin.path().to_string());
+        }
+        if name != "pageview" {
+            let (mime, size) = if name == "download" {
+                (
+                    res.content_type().map(|c| c.to_string()),
+                    res.body().preset_size().map(|s| s as u64),
+                )
+            } else {
+                (
+                    req.content_type().map(|c| c.to_string()),
+                    req.headers()
+                        .get_one("content-length")
+                        .and_then(|s| s.parse().ok()),
+                )
+            };
+            if let Some(m) = mime {
+                props.insert("mime_type".to_string(), m);
+            }
+            if let Some(s) = size {
+                props.insert("size".to_string(), Self::size_bucket(s).to_string());
+            }
+        }
+        if let Some(c) = res.headers().get_one("x-cache") {
+            props.insert("cache".to_string(), c.to_lowercase());
+        }
+        if let Some(country) = self
+            .geoip
+            .as_ref()
+            .zip(req.client_ip())
+            .and_then(|(g, ip)| g.country(ip))
+        {
+            props.insert("country".to_string(), country);
+        }
+        let domain = match req.host() {
+            Some(s) => s.to_string(),
+            None => return Ok(()), // ignore request
+        };
+        self.pending.fetch_add(1, Ordering::SeqCst);
         Ok(self.tx.send(Event {
-            name: "pageview".to_string(),
-            domain: match req.host() {
-                Some(s) => s.to_string(),
-                None => return Ok(()), // ignore request
-            },
+            name: name.to_string(),
+            domain,
             url: req.uri().to_string(),
             referrer: req.headers().get_one("Referer").map(|s| s.to_string()),
+            props,
             user_agent: req.headers().get_one("User-Agent").map(|s| s.to_string()),
             xff: req
                 .headers()
@@ -84,4 +188,8 @@ impl Analytics for PlausibleAnalytics {
                 .map(|s| s.to_string()),
         })?)
     }
+
+    fn pending(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
 }
@@ -0,0 +1,101 @@
+use std::convert::Infallible;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::request::{FromRequest, Outcome};
+use rocket::{async_trait, Request, Response};
+
+/// Routes which are also served without a version prefix
+const LEGACY_PREFIXES: [&str; 2] = ["/n96", "/admin"];
+
+/// Routes under a legacy prefix which are not part of the API
+const NON_API_ROUTES: [&str; 1] = ["admin_ui"];
+
+/// Unix time when the unversioned routes were deprecated (RFC 9745), 2026-10-16
+pub const DEPRECATED_AT: i64 = 1792108800;
+
+/// API version of the current request, handlers can use this to
+/// keep legacy response shapes when the versioned API changes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiVersion {
+    /// Unversioned routes (deprecated)
+    Legacy,
+    V1,
+}
+
+impl ApiVersion {
+    /// Path prefix for the current API version
+    pub const CURRENT_PREFIX: &'static str = "/v1";
+
+    pub fn from_path(path: &str) -> Option<Self> {
+        if path == "/v1" || path.starts_with("/v1/") {
+            return Some(ApiVersion::V1);
+        }
+        if LEGACY_PREFIXES
+            .iter()
+            .any(|p| path == *p || path.starts_with(&format!("{}/", p)))
+        {
+            return Some(ApiVersion::Legacy);
+        }
+        None
+    }
+}
+
+#[async_trait]
+impl<'r> FromRequest<'r> for ApiVersion {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        // routes which have fixed paths (blossom, well-known) always use the current shapes
+        Outcome::Success(
+            ApiVersion::from_path(request.uri().path().as_str()).unwrap_or(ApiVersion::V1),
+        )
+    }
+}
+
+/// Adds `Deprecation` / `Sunset` headers to responses from legacy routes
+pub struct ApiDeprecation {
+    sunset: Option<String>,
+}
+
+impl ApiDeprecation {
+    pub fn new(sunset: Option<String>) -> Self {
+        Self { sunset }
+    }
+}
+
+#[async_trait]
+impl Fairing for ApiDeprecation {
+    fn info(&self) -> Info {
+        Info {
+            name: "API deprecation headers",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, response: &mut Response<'r>) {
+        let path = req.uri().path();
+        if ApiVersion::from_path(path.as_str()) != Some(ApiVersion::Legacy) {
+            return;
+        }
+        if req
+            .route()
+            .and_then(|r| r.name.as_deref())
+            .is_some_and(|n| NON_API_ROUTES.contains(&n))
+        {
+            return;
+        }
+        response.set_header(Header::new("Deprecation", format!("@{}", DEPRECATED_AT)));
+        if let Some(s) = &self.sunset {
+            response.set_header(Header::new("Sunset", s.clone()));
+        }
+        response.set_header(Header::new(
+            "Link",
+            format!(
+                "<{}{}>; rel=\"successor-version\"",
+                ApiVersion::CURRENT_PREFIX,
+                path
+            ),
+        ));
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::{async_trait, Request};
+
+use crate::settings::Settings;
+
+/// Pubkey of the synthetic user which owns anonymous uploads,
+/// this is not a valid public key so nobody can sign events for it
+pub const ANONYMOUS_PUBKEY: [u8; 32] = [0; 32];
+
+/// Unauthenticated request, only available when anonymous uploads are enabled
+pub struct AnonymousAuth {
+    pub content_type: Option<String>,
+    pub content_length: Option<u64>,
+    pub ip: IpAddr,
+    limiter: Option<AnonymousRateLimiter>,
+    uploads_per_hour: u32,
+}
+
+impl AnonymousAuth {
+    /// Count an upload from this IP, returns false when the hourly limit is reached
+    pub fn check_rate_limit(&self) -> bool {
+        match &self.limiter {
+            Some(l) => l.check(self.ip, self.uploads_per_hour),
+            None => true,
+        }
+    }
+}
+
+/// Fixed window upload counter per IP
+#[derive(Clone, Default)]
+pub struct AnonymousRateLimiter {
+    window: Arc<Mutex<HashMap<IpAddr, (Instant, u32)>>>,
+}
+
+impl AnonymousRateLimiter {
+    const WINDOW: Duration = Duration::from_secs(3600);
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count an upload from this IP, returns false when the limit is reached
+    pub fn check(&self, ip: IpAddr, limit: u32) -> bool {
+        let now = Instant::now();
+        let mut window = self.window.lock().unwrap();
+        if window.len() > 10_000 {
+            window.retain(|_, (start, _)| now.duration_since(*start) < Self::WINDOW);
+        }
+        let entry = window.entry(ip).or_insert((now, 0));
+        if now.duration_since(entry.0) >= Self::WINDOW {
+            *entry = (now, 0);
+        }
+        if entry.1 >= limit {
+            return false;
+        }
+        entry.1 += 1;
+        true
+    }
+}
+
+#[async_trait]
+impl<'r> FromRequest<'r> for AnonymousAuth {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        if request.headers().contains("authorization") {
+            return Outcome::Forward(Status::Unauthorized);
+        }
+        let anon = match request
+            .rocket()
+            .state::<Settings>()
+            .and_then(|s| s.anonymous_uploads.as_ref())
+        {
+            Some(a) => a,
+            None => return Outcome::Forward(Status::Unauthorized),
+        };
+        let ip = match request.client_ip() {
+            Some(ip) => ip,
+            None => return Outcome::Error((Status::new(400), "Unknown client IP")),
+        };
+        Outcome::Success(AnonymousAuth {
+            ip,
+            limiter: request.rocket().state::<AnonymousRateLimiter>().cloned(),
+            uploads_per_hour: anon.uploads_per_hour,
+            content_type: request
+                .headers()
+                .get_one("content-type")
+                .map(|h| h.to_string()),
+            content_length: request
+                .headers()
+                .get_one("content-length")
+                .and_then(|h| h.parse().ok()),
+        })
+    }
+}
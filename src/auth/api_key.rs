@@ -0,0 +1,83 @@
+use log::warn;
+use nostr::PublicKey;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::{async_trait, Request};
+use sha2::{Digest, Sha256};
+
+use crate::db::{Database, User};
+
+/// Prefix for API keys so they can be easily identified (secret scanning etc.)
+pub const API_KEY_PREFIX: &str = "r96_";
+
+/// Authorization using an API key `Authorization: Bearer <key>`
+pub struct ApiKeyAuth {
+    pub content_type: Option<String>,
+    pub content_length: Option<u64>,
+    pub user: User,
+    pub pubkey: PublicKey,
+}
+
+impl ApiKeyAuth {
+    /// Generate a new random API key
+    pub fn generate_key() -> String {
+        format!(
+            "{}{}{}",
+            API_KEY_PREFIX,
+            uuid::Uuid::new_v4().simple(),
+            uuid::Uuid::new_v4().simple()
+        )
+    }
+
+    /// Hash of an API key as stored in the database
+    pub fn hash_key(key: &str) -> Vec<u8> {
+        Sha256::digest(key.as_bytes()).to_vec()
+    }
+}
+
+#[async_trait]
+impl<'r> FromRequest<'r> for ApiKeyAuth {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let key = match request
+            .headers()
+            .get_one("authorization")
+            .and_then(|a| a.strip_prefix("Bearer "))
+        {
+            Some(k) => k.trim(),
+            None => return Outcome::Error((Status::new(401), "Auth header not found")),
+        };
+        if !key.starts_with(API_KEY_PREFIX) {
+            return Outcome::Error((Status::new(401), "Invalid API key"));
+        }
+        let db = match request.rocket().state::<Database>() {
+            Some(db) => db,
+            None => return Outcome::Error((Status::new(500), "Database not available")),
+        };
+        let user = match db.get_api_key_user(&ApiKeyAuth::hash_key(key)).await {
+            Ok(Some(u)) => u,
+            Ok(None) => return Outcome::Error((Status::new(401), "Invalid API key")),
+            Err(e) => {
+                warn!("Failed to check API key: {}", e);
+                return Outcome::Error((Status::new(500), "Failed to check API key"));
+            }
+        };
+        let pubkey = match PublicKey::from_slice(&user.pubkey) {
+            Ok(p) => p,
+            Err(_) => return Outcome::Error((Status::new(500), "Invalid user pubkey")),
+        };
+        Outcome::Success(ApiKeyAuth {
+            user,
+            pubkey,
+            content_type: request
+                .headers()
+                .get_one("content-type")
+                .map(|h| h.to_string()),
+            content_length: request
+                .headers()
+                .get_one("content-length")
+                .and_then(|h| h.parse().ok()),
+        })
+    }
+}
@@ -1,41 +1,65 @@
 use base64::prelude::*;
 use log::info;
-use nostr::{Event, JsonUtil, Kind, TagKind, Timestamp};
+use nostr::{Event, JsonUtil, Kind, PublicKey, TagKind, Timestamp};
 use rocket::http::Status;
 use rocket::request::{FromRequest, Outcome};
 use rocket::{async_trait, Request};
 
+use crate::auth::nip26::verify_delegation;
+
 pub struct BlossomAuth {
     pub content_type: Option<String>,
     pub x_content_type: Option<String>,
     pub x_sha_256: Option<String>,
     pub x_content_length: Option<u64>,
     pub event: Event,
+    /// Delegator pubkey when the event has a valid NIP-26 delegation tag
+    pub delegator: Option<PublicKey>,
+}
+
+impl BlossomAuth {
+    /// Pubkey the request acts on behalf of
+    pub fn pubkey(&self) -> PublicKey {
+        self.delegator.unwrap_or(self.event.pubkey)
+    }
 }
 
+/// Reason the auth header of a request was rejected, reported in the `X-Reason` header
+pub struct AuthFailure(pub Option<&'static str>);
+
 #[async_trait]
 impl<'r> FromRequest<'r> for BlossomAuth {
     type Error = &'static str;
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let outcome = BlossomAuth::parse(request);
+        if let Outcome::Error((_, reason)) = &outcome {
+            request.local_cache(|| AuthFailure(Some(reason)));
+        }
+        outcome
+    }
+}
+
+impl BlossomAuth {
+    fn parse(request: &Request<'_>) -> Outcome<Self, &'static str> {
         if let Some(auth) = request.headers().get_one("authorization") {
             if auth.starts_with("Nostr ") {
                 let event = if let Ok(j) = BASE64_STANDARD.decode(&auth[6..]) {
                     if let Ok(ev) = Event::from_json(j) {
                         ev
                     } else {
-                        return Outcome::Error((Status::new(400), "Invalid nostr event"));
+                        return Outcome::Error((Status::Unauthorized, "Invalid nostr event"));
                     }
                 } else {
-                    return Outcome::Error((Status::new(400), "Invalid auth string"));
+                    return Outcome::Error((Status::Unauthorized, "Invalid auth string"));
                 };
 
                 if event.kind != Kind::Custom(24242) {
-                    return Outcome::Error((Status::new(400), "Wrong event kind"));
+                    return Outcome::Error((Status::Unauthorized, "Wrong event kind"));
                 }
                 if event.created_at > Timestamp::now() {
                     return Outcome::Error((
-                        Status::new(400),
+                        Status::Unauthorized,
                         "Created timestamp is in the future",
                     ));
                 }
@@ -48,21 +72,29 @@ impl<'r> FromRequest<'r> for BlossomAuth {
                         None
                     }
                 }) {
-                    let u_exp: Timestamp = expiration.parse().unwrap();
-                    if u_exp <= Timestamp::now() {
-                        return Outcome::Error((Status::new(400), "Expiration invalid"));
+                    match expiration.parse::<Timestamp>() {
+                        Ok(u_exp) if u_exp > Timestamp::now() => {}
+                        _ => return Outcome::Error((Status::Unauthorized, "Expiration invalid")),
                     }
                 } else {
-                    return Outcome::Error((Status::new(400), "Missing expiration tag"));
+                    return Outcome::Error((Status::Unauthorized, "Missing expiration tag"));
                 }
 
                 if event.verify().is_err() {
-                    return Outcome::Error((Status::new(400), "Event signature invalid"));
+                    return Outcome::Error((Status::Unauthorized, "Event signature invalid"));
                 }
 
+                let delegator = match verify_delegation(&event) {
+                    Ok(d) => d,
+                    Err(_) => {
+                        return Outcome::Error((Status::Unauthorized, "Delegation tag invalid"));
+                    }
+                };
+
                 info!("{}", event.as_json());
                 Outcome::Success(BlossomAuth {
                     event,
+                    delegator,
                     content_type: request.headers().iter().find_map(|h| {
                         if h.name == "content-type" {
                             Some(h.value.to_string())
@@ -79,7 +111,7 @@ impl<'r> FromRequest<'r> for BlossomAuth {
                     }),
                     x_content_length: request.headers().iter().find_map(|h| {
                         if h.name == "x-content-length" {
-                            Some(h.value.parse().unwrap())
+                            h.value.parse().ok()
                         } else {
                             None
                         }
@@ -93,7 +125,7 @@ impl<'r> FromRequest<'r> for BlossomAuth {
                     }),
                 })
             } else {
-                Outcome::Error((Status::new(400), "Auth scheme must be Nostr"))
+                Outcome::Error((Status::Unauthorized, "Auth scheme must be Nostr"))
             }
         } else {
             Outcome::Error((Status::new(401), "Auth header not found"))
@@ -1,2 +1,7 @@
+pub mod anonymous;
+pub mod api_key;
 pub mod blossom;
+pub mod nip26;
 pub mod nip98;
+pub mod request;
+pub mod session;
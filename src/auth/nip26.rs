@@ -0,0 +1,20 @@
+use anyhow::Result;
+use nostr::nips::nip26::{DelegationTag, EventProperties};
+use nostr::{Event, PublicKey};
+use rocket::serde::json::serde_json;
+
+/// Verify the NIP-26 `delegation` tag of an event and return the delegator pubkey
+pub fn verify_delegation(event: &Event) -> Result<Option<PublicKey>> {
+    let tag = match event
+        .tags
+        .iter()
+        .map(|t| t.as_slice())
+        .find(|t| t.first().is_some_and(|k| k == "delegation"))
+    {
+        Some(t) => t,
+        None => return Ok(None),
+    };
+    let tag = DelegationTag::from_json(&serde_json::to_string(tag)?)?;
+    tag.validate(&event.pubkey, &EventProperties::from_event(event))?;
+    Ok(Some(tag.delegator_pubkey()))
+}
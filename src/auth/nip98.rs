@@ -1,16 +1,65 @@
 use base64::prelude::BASE64_STANDARD;
 use base64::Engine;
 use log::info;
-use nostr::{Event, JsonUtil, Kind, Timestamp};
+use nostr::{Event, JsonUtil, Kind, PublicKey, Timestamp};
 use rocket::http::uri::{Absolute, Uri};
 use rocket::http::Status;
 use rocket::request::{FromRequest, Outcome};
 use rocket::{async_trait, Request};
+use sha2::{Digest, Sha256};
+
+use crate::auth::nip26::verify_delegation;
+use crate::settings::Settings;
+
+/// Max age in seconds of auth events for requests without a body (list, delete etc.)
+pub const MAX_AUTH_AGE: u64 = 60;
 
 pub struct Nip98Auth {
     pub content_type: Option<String>,
     pub content_length: Option<u64>,
+    /// SHA-256 hash of the request body from the `payload` tag
+    pub payload: Option<String>,
     pub event: Event,
+    /// Delegator pubkey when the event has a valid NIP-26 delegation tag
+    pub delegator: Option<PublicKey>,
+}
+
+impl Nip98Auth {
+    /// Pubkey the request acts on behalf of
+    pub fn pubkey(&self) -> PublicKey {
+        self.delegator.unwrap_or(self.event.pubkey)
+    }
+
+    /// Check the event was created within the last `max_age` seconds
+    pub fn check_age(&self, max_age: u64) -> Result<(), &'static str> {
+        if self.event.created_at.as_u64() + max_age < Timestamp::now().as_u64() {
+            return Err("Auth event timestamp out of range");
+        }
+        Ok(())
+    }
+
+    /// Check the `payload` tag against the hash of the uploaded file,
+    /// only enforced in strict mode as clients disagree on what gets hashed
+    pub fn check_payload(&self, settings: &Settings, body_hash: &[u8]) -> Result<(), &'static str> {
+        if !settings.strict_auth.unwrap_or(false) {
+            return Ok(());
+        }
+        match &self.payload {
+            Some(p) if p.eq_ignore_ascii_case(&hex::encode(body_hash)) => Ok(()),
+            Some(_) => Err("Payload tag does not match"),
+            None => Err("Missing payload tag"),
+        }
+    }
+
+    /// Check the `payload` tag against the SHA-256 of a request body, always enforced
+    /// for bodies which are parsed by the server (unlike uploads, see [Nip98Auth::check_payload])
+    pub fn check_body(&self, body: &[u8]) -> Result<(), &'static str> {
+        match &self.payload {
+            Some(p) if p.eq_ignore_ascii_case(&hex::encode(Sha256::digest(body))) => Ok(()),
+            Some(_) => Err("Payload tag does not match"),
+            None => Err("Missing payload tag"),
+        }
+    }
 }
 
 #[async_trait]
@@ -40,6 +89,11 @@ impl<'r> FromRequest<'r> for Nip98Auth {
                     ));
                 }
 
+                let strict_settings = request
+                    .rocket()
+                    .state::<Settings>()
+                    .filter(|s| s.strict_auth.unwrap_or(false));
+
                 // check url tag
                 if let Some(url) = event.tags.iter().find_map(|t| {
                     let vec = t.as_slice();
@@ -56,6 +110,17 @@ impl<'r> FromRequest<'r> for Nip98Auth {
                     } else {
                         return Outcome::Error((Status::new(401), "Invalid U tag"));
                     }
+                    // strict mode requires the full url to match (host, path & query)
+                    if let Some(settings) = strict_settings {
+                        let req_url = format!(
+                            "{}{}",
+                            settings.public_url.trim_end_matches('/'),
+                            request.uri()
+                        );
+                        if url.trim_end_matches('/') != req_url.trim_end_matches('/') {
+                            return Outcome::Error((Status::new(401), "U tag does not match"));
+                        }
+                    }
                 } else {
                     return Outcome::Error((Status::new(401), "Missing url tag"));
                 }
@@ -80,9 +145,27 @@ impl<'r> FromRequest<'r> for Nip98Auth {
                     return Outcome::Error((Status::new(401), "Event signature invalid"));
                 }
 
+                let delegator = match verify_delegation(&event) {
+                    Ok(d) => d,
+                    Err(_) => {
+                        return Outcome::Error((Status::new(401), "Delegation tag invalid"));
+                    }
+                };
+
+                let payload = event.tags.iter().find_map(|t| {
+                    let vec = t.as_slice();
+                    if vec[0] == "payload" {
+                        vec.get(1).cloned()
+                    } else {
+                        None
+                    }
+                });
+
                 info!("{}", event.as_json());
                 Outcome::Success(Nip98Auth {
                     event,
+                    delegator,
+                    payload,
                     content_type: request.headers().iter().find_map(|h| {
                         if h.name == "content-type" {
                             Some(h.value.to_string())
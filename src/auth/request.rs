@@ -0,0 +1,144 @@
+use nostr::{Event, PublicKey, TagKind};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::{async_trait, Request};
+
+use crate::auth::anonymous::{AnonymousAuth, ANONYMOUS_PUBKEY};
+use crate::auth::api_key::ApiKeyAuth;
+use crate::auth::blossom::BlossomAuth;
+use crate::auth::nip98::Nip98Auth;
+
+/// Auth event of a request signed with a nostr key
+pub trait NostrAuth {
+    /// Pubkey the request acts on behalf of
+    fn pubkey(&self) -> PublicKey;
+    fn event(&self) -> &Event;
+    fn content_type(&self) -> Option<String>;
+    /// Size of the request body as declared by the client
+    fn content_length(&self) -> Option<u64>;
+}
+
+impl NostrAuth for Nip98Auth {
+    fn pubkey(&self) -> PublicKey {
+        Nip98Auth::pubkey(self)
+    }
+
+    fn event(&self) -> &Event {
+        &self.event
+    }
+
+    fn content_type(&self) -> Option<String> {
+        self.content_type.clone()
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        self.content_length
+    }
+}
+
+impl NostrAuth for BlossomAuth {
+    fn pubkey(&self) -> PublicKey {
+        BlossomAuth::pubkey(self)
+    }
+
+    fn event(&self) -> &Event {
+        &self.event
+    }
+
+    fn content_type(&self) -> Option<String> {
+        self.content_type.clone()
+    }
+
+    /// Blob size from the `size` tag of the auth event
+    fn content_length(&self) -> Option<u64> {
+        self.event.tags.iter().find_map(|t| {
+            if t.kind() == TagKind::Size {
+                t.content().and_then(|v| v.parse().ok())
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// Caller of a route which accepts a nostr auth event `A`, an API key (`Bearer`) or
+/// no authorization at all (anonymous uploads), selected by the `Authorization` header
+pub enum RequestAuth<A> {
+    Nostr(A),
+    ApiKey(ApiKeyAuth),
+    Anonymous(AnonymousAuth),
+}
+
+impl<A: NostrAuth> RequestAuth<A> {
+    /// Pubkey of the user the request acts on behalf of, [ANONYMOUS_PUBKEY] for anonymous requests
+    pub fn pubkey(&self) -> Vec<u8> {
+        match self.user() {
+            Some(p) => p.to_bytes().to_vec(),
+            None => ANONYMOUS_PUBKEY.to_vec(),
+        }
+    }
+
+    /// Pubkey of the authenticated user, [None] for anonymous requests
+    pub fn user(&self) -> Option<PublicKey> {
+        match self {
+            RequestAuth::Nostr(a) => Some(a.pubkey()),
+            RequestAuth::ApiKey(a) => Some(a.pubkey),
+            RequestAuth::Anonymous(_) => None,
+        }
+    }
+
+    pub fn event(&self) -> Option<&Event> {
+        match self {
+            RequestAuth::Nostr(a) => Some(a.event()),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(&self) -> Option<String> {
+        match self {
+            RequestAuth::Nostr(a) => a.content_type(),
+            RequestAuth::ApiKey(a) => a.content_type.clone(),
+            RequestAuth::Anonymous(a) => a.content_type.clone(),
+        }
+    }
+
+    pub fn content_length(&self) -> Option<u64> {
+        match self {
+            RequestAuth::Nostr(a) => a.content_length(),
+            RequestAuth::ApiKey(a) => a.content_length,
+            RequestAuth::Anonymous(a) => a.content_length,
+        }
+    }
+
+    /// Idempotency keys are scoped to the user, anonymous requests by IP
+    pub fn idempotency_scope(&self) -> String {
+        match self {
+            RequestAuth::Anonymous(a) => format!("anonymous:{}", a.ip),
+            _ => hex::encode(self.pubkey()),
+        }
+    }
+}
+
+#[async_trait]
+impl<'r, A> FromRequest<'r> for RequestAuth<A>
+where
+    A: FromRequest<'r, Error = &'static str>,
+{
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match request.headers().get_one("authorization") {
+            Some(a) if a.starts_with("Bearer ") => ApiKeyAuth::from_request(request)
+                .await
+                .map(RequestAuth::ApiKey),
+            Some(_) => A::from_request(request).await.map(RequestAuth::Nostr),
+            None => match AnonymousAuth::from_request(request).await {
+                Outcome::Success(a) => Outcome::Success(RequestAuth::Anonymous(a)),
+                Outcome::Error(e) => Outcome::Error(e),
+                Outcome::Forward(_) => {
+                    Outcome::Error((Status::Unauthorized, "Auth header not found"))
+                }
+            },
+        }
+    }
+}
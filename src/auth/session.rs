@@ -0,0 +1,69 @@
+use log::warn;
+use nostr::PublicKey;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::{async_trait, Request};
+use sha2::{Digest, Sha256};
+
+use crate::db::{Database, User};
+
+/// Cookie holding the portal session token
+pub const SESSION_COOKIE: &str = "r96_session";
+
+/// Authorization using a portal session cookie, created by logging in with NIP-98
+pub struct SessionAuth {
+    /// Session token from the cookie
+    pub token: String,
+    pub user: User,
+    pub pubkey: PublicKey,
+}
+
+impl SessionAuth {
+    /// Generate a new random session token
+    pub fn generate_token() -> String {
+        format!(
+            "{}{}",
+            uuid::Uuid::new_v4().simple(),
+            uuid::Uuid::new_v4().simple()
+        )
+    }
+
+    /// Hash of a session token as stored in the database
+    pub fn hash_token(token: &str) -> Vec<u8> {
+        Sha256::digest(token.as_bytes()).to_vec()
+    }
+}
+
+#[async_trait]
+impl<'r> FromRequest<'r> for SessionAuth {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        // missing or expired sessions are forwarded so the login page can be shown
+        let token = match request.cookies().get(SESSION_COOKIE) {
+            Some(c) => c.value().to_string(),
+            None => return Outcome::Forward(Status::Unauthorized),
+        };
+        let db = match request.rocket().state::<Database>() {
+            Some(db) => db,
+            None => return Outcome::Error((Status::new(500), "Database not available")),
+        };
+        let user = match db.get_session_user(&SessionAuth::hash_token(&token)).await {
+            Ok(Some(u)) => u,
+            Ok(None) => return Outcome::Forward(Status::Unauthorized),
+            Err(e) => {
+                warn!("Failed to check session: {}", e);
+                return Outcome::Error((Status::new(500), "Failed to check session"));
+            }
+        };
+        let pubkey = match PublicKey::from_slice(&user.pubkey) {
+            Ok(p) => p,
+            Err(_) => return Outcome::Error((Status::new(500), "Invalid user pubkey")),
+        };
+        Outcome::Success(SessionAuth {
+            token,
+            user,
+            pubkey,
+        })
+    }
+}
@@ -0,0 +1,319 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Error};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use rocket::serde::json::serde_json;
+use serde::{Deserialize, Serialize};
+use tokio::fs::{self, File};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter};
+
+use crate::db::{Database, FileUpload, FileVariant, User, UserUpload, VariantKind};
+use crate::filesystem::FileStore;
+
+/// Metadata dump, one [Record] per line
+const METADATA_FILE: &str = "metadata.jsonl";
+
+/// Directory containing the file contents, named by hex id
+const BLOBS_DIR: &str = "blobs";
+
+const PAGE_SIZE: u32 = 1000;
+
+/// Line in the metadata dump, users are always written before files
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Record {
+    User(BackupUser),
+    File(Box<BackupFile>),
+}
+
+#[derive(Serialize, Deserialize)]
+struct BackupUser {
+    #[serde(with = "hex")]
+    pubkey: Vec<u8>,
+    created: DateTime<Utc>,
+    is_admin: bool,
+    plan: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BackupFile {
+    #[serde(with = "hex")]
+    id: Vec<u8>,
+    name: String,
+    size: u64,
+    mime_type: String,
+    created: DateTime<Utc>,
+    width: Option<u32>,
+    height: Option<u32>,
+    blur_hash: Option<String>,
+    duration: Option<f32>,
+    alt: Option<String>,
+    thumb_mime: Option<String>,
+    original_hash: Option<String>,
+    blake3: Option<String>,
+    owners: Vec<BackupOwner>,
+    /// Derived blobs, stored in [BLOBS_DIR] like the files
+    #[serde(default)]
+    variants: Vec<FileVariant>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BackupOwner {
+    #[serde(with = "hex")]
+    pubkey: Vec<u8>,
+    created: Option<DateTime<Utc>>,
+    expires: Option<DateTime<Utc>>,
+    delegate: Option<String>,
+    public: bool,
+}
+
+impl BackupFile {
+    fn new(f: &FileUpload, owners: Vec<UserUpload>, variants: Vec<FileVariant>) -> Self {
+        Self {
+            id: f.id.clone(),
+            name: f.name.clone(),
+            size: f.size,
+            mime_type: f.mime_type.clone(),
+            created: f.created,
+            width: f.width,
+            height: f.height,
+            blur_hash: f.blur_hash.clone(),
+            duration: f.duration,
+            alt: f.alt.clone(),
+            thumb_mime: f.thumb_mime.clone(),
+            original_hash: f.original_hash.as_ref().map(hex::encode),
+            blake3: f.blake3.as_ref().map(hex::encode),
+            owners: owners
+                .into_iter()
+                .map(|o| BackupOwner {
+                    pubkey: o.pubkey,
+                    created: o.created,
+                    expires: o.expires,
+                    delegate: o.delegate.as_ref().map(hex::encode),
+                    public: o.public,
+                })
+                .collect(),
+            variants,
+        }
+    }
+
+    fn upload(&self) -> Result<(FileUpload, Vec<UserUpload>), Error> {
+        let decode = |v: &Option<String>| v.as_ref().map(hex::decode).transpose();
+        let upload = FileUpload {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            size: self.size,
+            mime_type: self.mime_type.clone(),
+            created: self.created,
+            width: self.width,
+            height: self.height,
+            blur_hash: self.blur_hash.clone(),
+            duration: self.duration,
+            alt: self.alt.clone(),
+            thumb_mime: self.thumb_mime.clone(),
+            original_hash: decode(&self.original_hash)?,
+            blake3: decode(&self.blake3)?,
+            ..Default::default()
+        };
+        let owners = self
+            .owners
+            .iter()
+            .map(|o| {
+                Ok(UserUpload {
+                    pubkey: o.pubkey.clone(),
+                    created: o.created,
+                    expires: o.expires,
+                    delegate: decode(&o.delegate)?,
+                    public: o.public,
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        Ok((upload, owners))
+    }
+}
+
+fn blob_path(dir: &Path, id: &[u8]) -> PathBuf {
+    dir.join(BLOBS_DIR).join(hex::encode(id))
+}
+
+/// Write all users and files (excluding the trash) to a backup directory
+pub async fn export(db: &Database, fs: &FileStore, out: &Path) -> Result<(), Error> {
+    fs::create_dir_all(out.join(BLOBS_DIR)).await?;
+    let mut meta = BufWriter::new(File::create(out.join(METADATA_FILE)).await?);
+
+    let mut users = 0;
+    let mut offset = 0;
+    loop {
+        let (page, _) = db.list_users(offset, PAGE_SIZE).await?;
+        if page.is_empty() {
+            break;
+        }
+        offset += PAGE_SIZE;
+        for u in page {
+            let rec = Record::User(BackupUser {
+                pubkey: u.pubkey,
+                created: u.created,
+                is_admin: u.is_admin,
+                plan: u.plan,
+            });
+            write_record(&mut meta, &rec).await?;
+            users += 1;
+        }
+    }
+
+    let (mut files, mut missing) = (0, 0);
+    let mut offset = 0;
+    loop {
+        let page = db.list_stored_files(offset, PAGE_SIZE).await?;
+        if page.is_empty() {
+            break;
+        }
+        offset += PAGE_SIZE;
+        for f in page {
+            let src = fs.get(&f.id);
+            if !src.exists() {
+                warn!("Skipping {}, file not found in storage", hex::encode(&f.id));
+                missing += 1;
+                continue;
+            }
+            let dst = blob_path(out, &f.id);
+            fs::copy(&src, &dst).await?;
+            let thumb = fs.map_thumb_path(&f.id);
+            if thumb.exists() {
+                fs::copy(&thumb, dst.with_extension("thumb")).await?;
+            }
+            let mut variants = db.get_file_variants(&f.id).await?;
+            variants.retain(|v| fs.map_variant_path(&v.id).exists());
+            for v in &variants {
+                fs::copy(fs.map_variant_path(&v.id), blob_path(out, &v.id)).await?;
+            }
+            let owners = db.get_file_uploads(&f.id).await?;
+            let rec = Record::File(Box::new(BackupFile::new(&f, owners, variants)));
+            write_record(&mut meta, &rec).await?;
+            files += 1;
+        }
+    }
+    meta.flush().await?;
+    info!(
+        "Exported {} users and {} files to {}, {} files were missing",
+        users,
+        files,
+        out.display(),
+        missing
+    );
+    Ok(())
+}
+
+async fn write_record(out: &mut BufWriter<File>, rec: &Record) -> Result<(), Error> {
+    let mut line = serde_json::to_vec(rec)?;
+    line.push(b'\n');
+    out.write_all(&line).await?;
+    Ok(())
+}
+
+/// Load a backup directory created by [export], files with a hash mismatch are skipped
+pub async fn import(db: &Database, fs: &FileStore, input: &Path) -> Result<(), Error> {
+    let meta = File::open(input.join(METADATA_FILE)).await?;
+    let mut lines = BufReader::new(meta).lines();
+
+    let (mut users, mut files, mut failed) = (0, 0, 0);
+    let mut line_no = 0;
+    while let Some(line) = lines.next_line().await? {
+        line_no += 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let rec: Record = match serde_json::from_str(&line) {
+            Ok(r) => r,
+            Err(e) => bail!("Invalid record on line {}: {}", line_no, e),
+        };
+        match rec {
+            Record::User(u) => {
+                db.import_user(&User {
+                    id: 0,
+                    pubkey: u.pubkey,
+                    created: u.created,
+                    is_admin: u.is_admin,
+                    plan: u.plan,
+                })
+                .await?;
+                users += 1;
+            }
+            Record::File(f) => match import_file(db, fs, input, &f).await {
+                Ok(()) => files += 1,
+                Err(e) => {
+                    warn!("Failed to import {}: {}", hex::encode(&f.id), e);
+                    failed += 1;
+                }
+            },
+        }
+    }
+    info!(
+        "Imported {} users and {} files, {} files failed",
+        users, files, failed
+    );
+    Ok(())
+}
+
+async fn import_file(
+    db: &Database,
+    fs: &FileStore,
+    input: &Path,
+    file: &BackupFile,
+) -> Result<(), Error> {
+    let (mut upload, owners) = file.upload()?;
+    let src = blob_path(input, &upload.id);
+    let (hash, blake3) = fs.hash_file(&mut File::open(&src).await?).await?;
+    if hash != upload.id {
+        bail!("hash mismatch, got {}", hex::encode(hash));
+    }
+    if upload.blake3.is_none() {
+        upload.blake3 = blake3;
+    }
+
+    let dst = fs.map_path(&upload.id);
+    if !dst.exists() {
+        if let Some(parent) = dst.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::copy(&src, &dst).await?;
+    }
+    let thumb = src.with_extension("thumb");
+    if thumb.exists() {
+        fs::copy(&thumb, fs.map_thumb_path(&upload.id)).await?;
+    } else if !file
+        .variants
+        .iter()
+        .any(|v| v.kind == VariantKind::Thumbnail)
+    {
+        upload.thumb_mime = None;
+    }
+    db.import_file(&upload, &owners).await?;
+
+    for v in &file.variants {
+        let src = blob_path(input, &v.id);
+        let (hash, _) = fs.hash_file(&mut File::open(&src).await?).await?;
+        if hash != v.id {
+            warn!(
+                "Skipping variant {} of {}, hash mismatch",
+                hex::encode(&v.id),
+                hex::encode(&upload.id)
+            );
+            continue;
+        }
+        let dst = fs.map_variant_path(&v.id);
+        if !dst.exists() {
+            if let Some(parent) = dst.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            fs::copy(&src, &dst).await?;
+        }
+        db.add_file_variant(&FileVariant {
+            file: upload.id.clone(),
+            ..v.clone()
+        })
+        .await?;
+    }
+    Ok(())
+}
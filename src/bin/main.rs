@@ -1,32 +1,92 @@
-use std::net::{IpAddr, SocketAddr};
+use std::net::IpAddr;
+use std::path::PathBuf;
+#[cfg(feature = "tls")]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::Error;
-use clap::Parser;
+use anyhow::{bail, Error};
+use clap::{Parser, Subcommand};
 use config::Config;
 use log::{error, info};
 use rocket::config::Ident;
 use rocket::data::{ByteUnit, Limits};
 use rocket::routes;
 use rocket::shield::Shield;
+#[cfg(feature = "tls")]
+use rocket::Ignite;
+use rocket::{Build, Rocket};
 #[cfg(feature = "analytics")]
 use route96::analytics::plausible::PlausibleAnalytics;
 #[cfg(feature = "analytics")]
 use route96::analytics::AnalyticsFairing;
+use route96::api_version::{ApiDeprecation, ApiVersion};
+use route96::auth::anonymous::AnonymousRateLimiter;
+use route96::backup;
+use route96::blocklist::Blocklist;
+use route96::cleanup::FileCleanup;
 use route96::cors::CORS;
 use route96::db::Database;
+use route96::encoding::ResponseCompression;
 use route96::filesystem::FileStore;
+use route96::geoip::GeoIp;
+use route96::idempotency::IdempotencyCache;
+use route96::limits::HeaderLimit;
+use route96::listener::UnixSocketProxy;
+use route96::notify::Notifier;
+#[cfg(feature = "media-compression")]
+use route96::processing::queue::ProcessingQueue;
+use route96::progress::UploadProgressTracker;
 use route96::routes;
-use route96::routes::{get_blob, head_blob, root};
-use route96::settings::Settings;
+use route96::routes::MultipartUploads;
+use route96::routes::{
+    get_blob, get_blob_blake3, get_metadata, get_thumbnail, get_variant, head_blob,
+    reserve_upload_id, root, upload_progress,
+};
+#[cfg(feature = "tls")]
+use route96::settings::TlsSettings;
+use route96::settings::{ListenAddr, ListenerSettings, Settings};
+use route96::shutdown::GracefulShutdown;
+use route96::stats::DownloadStats;
+use route96::tiering::StorageTiering;
+#[cfg(feature = "tls")]
+use route96::tls::{acme_routes, tls_config, CertManager};
 #[cfg(feature = "void-cat-redirects")]
 use route96::void_db::VoidCatDb;
 use route96::webhook::Webhook;
+use tokio::task::JoinSet;
 
 #[derive(Parser, Debug)]
 #[command(version, about)]
 struct Args {
     #[arg(long)]
     pub config: Option<String>,
+
+    /// Apply database migrations and exit
+    #[arg(long)]
+    pub migrate_only: bool,
+
+    /// Validate the configuration (storage, database, urls) and exit
+    #[arg(long)]
+    pub check_config: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Write all users, files and metadata to a backup directory
+    Export {
+        /// Output directory
+        path: PathBuf,
+    },
+    /// Load a backup directory created with `export`, file hashes are verified
+    Import {
+        /// Backup directory
+        path: PathBuf,
+    },
 }
 
 #[rocket::main]
@@ -46,68 +106,404 @@ async fn main() -> Result<(), Error> {
 
     let settings: Settings = builder.try_deserialize()?;
 
-    let db = Database::new(&settings.database).await?;
+    let mut errors = settings.validate();
+    let db = match Database::new(&settings.database).await {
+        Ok(db) => Some(db),
+        Err(e) => {
+            errors.push(format!("database is not reachable: {}", e));
+            None
+        }
+    };
+    let current = match &db {
+        Some(db) => match db.schema_version().await {
+            Ok(v) => v,
+            Err(e) => {
+                errors.push(format!("failed to read database schema version: {}", e));
+                None
+            }
+        },
+        None => None,
+    };
+    let db = match db {
+        Some(db) if errors.is_empty() => db,
+        _ => bail!("Invalid configuration:\n  - {}", errors.join("\n  - ")),
+    };
+    if args.check_config {
+        println!("Configuration OK");
+        return Ok(());
+    }
 
-    info!("Running DB migration");
+    let latest = Database::latest_schema_version();
+    if let Some(v) = current {
+        if v > latest {
+            bail!(
+                "Database schema version {} is newer than this build ({}), refusing to start",
+                v,
+                latest
+            );
+        }
+    }
+    info!(
+        "Running DB migration {} -> {}",
+        current.map_or("none".to_string(), |v| v.to_string()),
+        latest
+    );
     db.migrate().await?;
+    if args.migrate_only {
+        return Ok(());
+    }
 
-    let mut config = rocket::Config::default();
-    let ip: SocketAddr = match &settings.listen {
-        Some(i) => i.parse()?,
-        None => SocketAddr::new(IpAddr::from([0, 0, 0, 0]), 8000),
+    match &args.command {
+        Some(Command::Export { path }) => {
+            let fs = FileStore::new(settings.clone());
+            return backup::export(&db, &fs, path).await;
+        }
+        Some(Command::Import { path }) => {
+            let fs = FileStore::new(settings.clone());
+            return backup::import(&db, &fs, path).await;
+        }
+        None => {}
+    }
+
+    let blocklist = Blocklist::new(&settings, db.clone());
+    blocklist.start();
+
+    let fs = FileStore::new(settings.clone()).with_blocklist(blocklist);
+    // temp files from a previous run which was not shut down cleanly
+    fs.clean_temp();
+    if let Some(tiering) = StorageTiering::new(&settings, db.clone(), fs.clone()) {
+        info!("Starting storage tiering");
+        tiering.start();
+    }
+
+    let progress = UploadProgressTracker::new();
+    progress.start_sweep();
+    #[cfg(feature = "media-compression")]
+    ProcessingQueue::new(&settings, db.clone(), fs.clone(), progress.clone()).start();
+
+    FileCleanup::new(&settings, db.clone(), fs.clone()).start();
+
+    let download_stats = DownloadStats::new(db.clone());
+    download_stats.start();
+
+    let geoip = match &settings.geoip_database {
+        Some(path) => Some(GeoIp::open(path)?),
+        None => None,
     };
-    config.address = ip.ip();
-    config.port = ip.port();
 
+    let notifier = match &settings.notifications {
+        Some(n) => Some(Notifier::new(n).await?),
+        None => None,
+    };
+
+    let mut config = rocket::Config::default();
     let upload_limit = ByteUnit::from(settings.max_upload_bytes);
-    config.limits = Limits::new()
+    let mut limits = Limits::new()
         .limit("file", upload_limit)
         .limit("data-form", upload_limit)
         .limit("form", upload_limit);
+    if let Some(rl) = &settings.request_limits {
+        if let Some(ka) = rl.keep_alive {
+            config.keep_alive = ka;
+        }
+        if let Some(bl) = &rl.body_limits {
+            for (k, v) in bl {
+                limits = limits.limit(k.clone(), ByteUnit::from(*v));
+            }
+        }
+    }
+    config.limits = limits;
     config.ident = Ident::try_new("route96").unwrap();
+    let shutdown_timeout = Duration::from_secs(settings.shutdown_timeout.unwrap_or(30));
+    config.shutdown.grace = shutdown_timeout.as_secs() as u32;
+
+    #[cfg(feature = "void-cat-redirects")]
+    let void_db = match &settings.void_cat_database {
+        Some(conn) => Some(VoidCatDb::connect(conn).await?),
+        None => None,
+    };
+
+    let listeners = match &settings.listen {
+        Some(l) => l.listeners(),
+        None => vec![ListenerSettings::Address("0.0.0.0:8000".to_string())],
+    };
+    // the admin API is moved to the admin listeners when there are any
+    let admin_listener = listeners.iter().any(|l| l.admin_only());
+
+    #[cfg(feature = "tls")]
+    let acme = match &settings.tls {
+        Some(TlsSettings {
+            acme: Some(acme), ..
+        }) => {
+            // HTTP-01 challenges are answered on the plain HTTP listeners
+            if !listeners.iter().any(|l| !l.tls() && !l.admin_only()) {
+                bail!("ACME requires a listener without TLS on port 80");
+            }
+            let manager = CertManager::new(settings.tls.as_ref().unwrap(), acme);
+            manager.clone().start();
+            Some(manager)
+        }
+        _ => None,
+    };
+    if listeners.iter().any(|l| l.tls()) && settings.tls.is_none() {
+        bail!("TLS listeners require the `tls` settings");
+    }
+
+    let idempotency = IdempotencyCache::new(Duration::from_secs(
+        settings.idempotency_ttl.unwrap_or(86400),
+    ));
+    let app = App {
+        multipart: MultipartUploads::new(&settings, idempotency.clone()),
+        webhook: settings
+            .webhook_url
+            .as_ref()
+            .map(|w| Webhook::new(w.clone())),
+        settings,
+        db,
+        fs,
+        download_stats,
+        progress,
+        anonymous: AnonymousRateLimiter::new(),
+        idempotency,
+        geoip,
+        notifier,
+        #[cfg(feature = "void-cat-redirects")]
+        void_db,
+        #[cfg(feature = "tls")]
+        acme,
+        shutdown_timeout,
+    };
 
-    let mut rocket = rocket::Rocket::custom(config)
-        .manage(FileStore::new(settings.clone()))
-        .manage(settings.clone())
-        .manage(db.clone())
-        .manage(
-            settings
-                .webhook_url
-                .as_ref()
-                .map(|w| Webhook::new(w.clone())),
-        )
-        .attach(CORS)
-        .attach(Shield::new()) // disable
-        .mount("/", routes![root, get_blob, head_blob])
-        .mount("/admin", routes::admin_routes());
-
-    #[cfg(feature = "analytics")]
-    {
-        if settings.plausible_url.is_some() {
-            rocket = rocket.attach(AnalyticsFairing::new(PlausibleAnalytics::new(&settings)))
-        }
-    }
-    #[cfg(feature = "blossom")]
-    {
-        rocket = rocket.mount("/", routes::blossom_routes());
-    }
-    #[cfg(feature = "nip96")]
-    {
-        rocket = rocket.mount("/", routes::nip96_routes());
+    let mut servers = JoinSet::new();
+    for l in &listeners {
+        let mut config = config.clone();
+        let unix = match l.parse()? {
+            ListenAddr::Tcp(addr) => {
+                config.address = addr.ip();
+                config.port = addr.port();
+                None
+            }
+            ListenAddr::Unix(_) if l.tls() => bail!("TLS is not supported on unix sockets"),
+            ListenAddr::Unix(path) => {
+                // connections on the socket are forwarded to a random loopback port
+                config.address = IpAddr::from([127, 0, 0, 1]);
+                config.port = 0;
+                Some(UnixSocketProxy::new(path))
+            }
+        };
+        if l.tls() {
+            #[cfg(feature = "tls")]
+            {
+                servers.spawn(serve_tls(app.clone(), config, l.clone(), !admin_listener));
+                continue;
+            }
+            #[cfg(not(feature = "tls"))]
+            bail!("TLS listeners require the `tls` feature");
+        }
+        let mut rocket = app.build(config, l, !admin_listener, true);
+        if let Some(u) = unix {
+            rocket = rocket.attach(u);
+        }
+        servers.spawn(rocket.launch());
     }
+    while let Some(res) = servers.join_next().await {
+        if let Err(e) = res? {
+            error!("Rocker error {}", e);
+            return Err(Error::from(e));
+        }
+    }
+    Ok(())
+}
+
+/// State shared by all listeners
+#[derive(Clone)]
+struct App {
+    settings: Settings,
+    db: Database,
+    fs: FileStore,
+    download_stats: DownloadStats,
+    progress: UploadProgressTracker,
+    multipart: MultipartUploads,
+    anonymous: AnonymousRateLimiter,
+    idempotency: IdempotencyCache,
+    geoip: Option<GeoIp>,
+    notifier: Option<Notifier>,
+    webhook: Option<Webhook>,
     #[cfg(feature = "void-cat-redirects")]
-    {
-        if let Some(conn) = settings.void_cat_database {
-            let vdb = VoidCatDb::connect(&conn).await?;
-            rocket = rocket
-                .mount("/", routes![routes::void_cat_redirect])
-                .manage(vdb);
+    void_db: Option<VoidCatDb>,
+    #[cfg(feature = "tls")]
+    acme: Option<CertManager>,
+    shutdown_timeout: Duration,
+}
+
+/// Serve a TLS listener, the listener is restarted when ACME renews the certificate
+#[cfg(feature = "tls")]
+async fn serve_tls(
+    app: App,
+    mut config: rocket::Config,
+    listener: ListenerSettings,
+    mount_admin: bool,
+) -> Result<Rocket<Ignite>, rocket::Error> {
+    if let Some(tls) = &app.settings.tls {
+        config.tls = Some(tls_config(tls));
+    }
+    let mut issued = app.acme.as_ref().map(|a| a.subscribe());
+    if let Some(rx) = issued.as_mut() {
+        info!("Waiting for certificate to start {}", listener.address());
+        let _ = rx.wait_for(|v| *v > 0).await;
+    }
+    loop {
+        // a restart for a new certificate must not stop uploads on the other listeners
+        let rocket = app
+            .build(config.clone(), &listener, mount_admin, issued.is_none())
+            .ignite()
+            .await?;
+        let renewed = Arc::new(AtomicBool::new(false));
+        let watcher = issued.clone().map(|mut rx| {
+            let (shutdown, renewed) = (rocket.shutdown(), renewed.clone());
+            rx.borrow_and_update();
+            tokio::spawn(async move {
+                if rx.changed().await.is_ok() {
+                    renewed.store(true, Ordering::SeqCst);
+                    shutdown.notify();
+                }
+            })
+        });
+        let res = rocket.launch().await;
+        if let Some(w) = watcher {
+            w.abort();
+        }
+        if !renewed.load(Ordering::SeqCst) {
+            return res;
+        }
+        info!("Restarting {} with the new certificate", listener.address());
+    }
+}
+
+impl App {
+    /// Rocket instance for a listener
+    fn build(
+        &self,
+        config: rocket::Config,
+        listener: &ListenerSettings,
+        mount_admin: bool,
+        graceful_shutdown: bool,
+    ) -> Rocket<Build> {
+        let mut rocket = self.rocket(config);
+        if graceful_shutdown {
+            rocket = rocket.attach(GracefulShutdown::new(
+                self.fs.clone(),
+                self.download_stats.clone(),
+                self.shutdown_timeout,
+            ));
+        }
+        if listener.admin_only() {
+            return self.mount_admin(rocket);
+        }
+        rocket = self.mount_public(rocket);
+        #[cfg(feature = "tls")]
+        if let (Some(acme), false) = (&self.acme, listener.tls()) {
+            rocket = rocket.mount("/", acme_routes()).manage(acme.challenges());
+        }
+        if mount_admin {
+            rocket = self.mount_admin(rocket);
         }
+        rocket
     }
-    if let Err(e) = rocket.launch().await {
-        error!("Rocker error {}", e);
-        Err(Error::from(e))
-    } else {
-        Ok(())
+
+    /// Rocket instance with the shared state and common fairings, without any routes
+    fn rocket(&self, config: rocket::Config) -> Rocket<Build> {
+        let mut rocket = rocket::Rocket::custom(config)
+            .manage(self.fs.clone())
+            .manage(self.settings.clone())
+            .manage(self.db.clone())
+            .manage(self.progress.clone())
+            .manage(self.multipart.clone())
+            .manage(self.anonymous.clone())
+            .manage(self.idempotency.clone())
+            .manage(self.download_stats.clone())
+            .manage(self.geoip.clone())
+            .manage(self.notifier.clone())
+            .manage(self.webhook.clone())
+            .attach(CORS)
+            .attach(ApiDeprecation::new(self.settings.api_sunset.clone()))
+            .attach(Shield::new()); // disable
+        if self.settings.response_compression.unwrap_or(true) {
+            rocket = rocket.attach(ResponseCompression);
+        }
+        if let Some(max) = self
+            .settings
+            .request_limits
+            .as_ref()
+            .and_then(|l| l.max_header_bytes)
+        {
+            rocket = rocket.attach(HeaderLimit::new(max));
+        }
+        rocket
+    }
+
+    fn mount_admin(&self, rocket: Rocket<Build>) -> Rocket<Build> {
+        let rocket = rocket.mount("/admin", routes::admin_routes()).mount(
+            format!("{}/admin", ApiVersion::CURRENT_PREFIX),
+            routes::admin_routes(),
+        );
+        #[cfg(feature = "admin-ui")]
+        let rocket = rocket.mount("/admin", routes::admin_ui_routes());
+        rocket
+    }
+
+    fn mount_public(&self, rocket: Rocket<Build>) -> Rocket<Build> {
+        let mut rocket = rocket
+            .mount(
+                "/",
+                routes![
+                    root,
+                    get_blob,
+                    head_blob,
+                    get_metadata,
+                    get_blob_blake3,
+                    get_thumbnail,
+                    get_variant,
+                    upload_progress,
+                    reserve_upload_id
+                ],
+            )
+            .mount(ApiVersion::CURRENT_PREFIX, routes::api_key_routes())
+            .mount(ApiVersion::CURRENT_PREFIX, routes::account_routes())
+            .mount(ApiVersion::CURRENT_PREFIX, routes::multipart_routes())
+            .mount(ApiVersion::CURRENT_PREFIX, routes::report_routes())
+            .mount("/", routes::feed_routes())
+            .mount("/", routes::portal_routes());
+
+        #[cfg(feature = "analytics")]
+        {
+            if self.settings.plausible_url.is_some() {
+                rocket = rocket.attach(AnalyticsFairing::new(PlausibleAnalytics::new(
+                    &self.settings,
+                    self.geoip.clone(),
+                )))
+            }
+        }
+        #[cfg(feature = "blossom")]
+        {
+            rocket = rocket
+                .mount("/", routes::blossom_routes())
+                .attach(routes::BlossomReason);
+        }
+        #[cfg(feature = "nip96")]
+        {
+            rocket = rocket
+                .mount("/", routes::nip96_routes())
+                .mount(ApiVersion::CURRENT_PREFIX, routes::nip96_api_routes());
+        }
+        #[cfg(feature = "void-cat-redirects")]
+        {
+            if let Some(vdb) = &self.void_db {
+                rocket = rocket
+                    .mount("/", routes![routes::void_cat_redirect])
+                    .manage(vdb.clone());
+            }
+        }
+        rocket
     }
 }
@@ -141,6 +141,6 @@ async fn migrate_file(
         alt: f.description.clone(),
         ..Default::default()
     };
-    db.add_file(&fu, uid).await?;
+    db.add_file(&fu, uid, None).await?;
     Ok(())
 }
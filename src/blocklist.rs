@@ -0,0 +1,105 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::Error;
+use log::{error, info, warn};
+use reqwest::Client;
+use tokio::task::JoinHandle;
+
+use crate::db::Database;
+use crate::settings::Settings;
+
+/// Source name used for hashes banned on this server
+const LOCAL: &str = "local";
+
+/// Set of banned file hashes, merged from locally banned hashes (takedowns)
+/// and external blocklists which are refreshed on an interval
+#[derive(Clone)]
+pub struct Blocklist {
+    db: Database,
+    client: Client,
+    urls: Vec<String>,
+    interval: Duration,
+    /// Hashes by source (url / [LOCAL])
+    lists: Arc<RwLock<HashMap<String, HashSet<Vec<u8>>>>>,
+}
+
+impl Blocklist {
+    pub fn new(settings: &Settings, db: Database) -> Self {
+        let bl = settings.blocklist.as_ref();
+        Self {
+            db,
+            client: Client::new(),
+            urls: bl.map(|b| b.urls.clone()).unwrap_or_default(),
+            interval: Duration::from_secs(bl.and_then(|b| b.interval).unwrap_or(3600)),
+            lists: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Check if a file hash is banned
+    pub fn contains(&self, id: &[u8]) -> bool {
+        self.lists.read().unwrap().values().any(|l| l.contains(id))
+    }
+
+    /// Add a hash to the local set (the caller stores the ban in the database)
+    pub fn add(&self, id: &[u8]) {
+        self.lists
+            .write()
+            .unwrap()
+            .entry(LOCAL.to_string())
+            .or_default()
+            .insert(id.to_vec());
+    }
+
+    pub fn start(&self) -> JoinHandle<()> {
+        let bl = self.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = bl.refresh().await {
+                    error!("Failed to refresh blocklist: {}", e);
+                }
+                tokio::time::sleep(bl.interval).await;
+            }
+        })
+    }
+
+    /// Reload local bans and fetch all external lists, lists which fail to load
+    /// keep their previous contents
+    pub async fn refresh(&self) -> Result<(), Error> {
+        let local: HashSet<Vec<u8>> = self.db.list_banned_hashes().await?.into_iter().collect();
+        self.lists.write().unwrap().insert(LOCAL.to_string(), local);
+
+        for url in &self.urls {
+            match self.fetch(url).await {
+                Ok(list) => {
+                    info!("Loaded {} hashes from blocklist {}", list.len(), url);
+                    self.lists.write().unwrap().insert(url.clone(), list);
+                }
+                Err(e) => warn!("Failed to load blocklist {}: {}", url, e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Load a newline delimited list of sha256 hashes, anything after the hash
+    /// and lines starting with `#` are ignored
+    async fn fetch(&self, url: &str) -> Result<HashSet<Vec<u8>>, Error> {
+        let body = self
+            .client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        Ok(body
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .filter_map(|l| l.split_whitespace().next())
+            .filter_map(|h| hex::decode(h).ok())
+            .filter(|h| h.len() == 32)
+            .collect())
+    }
+}
@@ -0,0 +1,105 @@
+use std::time::Duration;
+
+use anyhow::Error;
+use log::{error, info, warn};
+use tokio::task::JoinHandle;
+
+use crate::db::Database;
+use crate::filesystem::FileStore;
+use crate::settings::Settings;
+
+/// Removes files whose ownership has expired (anonymous uploads etc.) and
+/// files which have been in the trash for longer than the retention period,
+/// files which nobody has downloaded for a long time are moved to the trash.
+/// Expired portal sessions are removed as well
+pub struct FileCleanup {
+    db: Database,
+    fs: FileStore,
+    interval: Duration,
+    unaccessed_days: Option<u32>,
+    trash_days: u32,
+}
+
+impl FileCleanup {
+    pub fn new(settings: &Settings, db: Database, fs: FileStore) -> Self {
+        Self {
+            db,
+            fs,
+            interval: Duration::from_secs(600),
+            unaccessed_days: settings.expire_unaccessed_days,
+            trash_days: settings.trash_retention_days.unwrap_or(7),
+        }
+    }
+
+    pub fn start(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = self.run_once().await {
+                    error!("File cleanup failed: {}", e);
+                }
+                tokio::time::sleep(self.interval).await;
+            }
+        })
+    }
+
+    async fn run_once(&self) -> Result<(), Error> {
+        let sessions = self.db.delete_expired_sessions().await?;
+        if sessions > 0 {
+            info!("Removed {} expired portal sessions", sessions);
+        }
+        loop {
+            let expired = self.db.list_expired_uploads(100).await?;
+            if expired.is_empty() {
+                break;
+            }
+            for (file, user_id) in expired {
+                self.remove_owner(&file, user_id, "expired").await?;
+            }
+        }
+        loop {
+            let trashed = self.db.list_trashed_uploads(self.trash_days, 100).await?;
+            if trashed.is_empty() {
+                break;
+            }
+            for (file, user_id) in trashed {
+                self.remove_owner(&file, user_id, "trashed").await?;
+            }
+        }
+        // unaccessed files are moved to the trash so their owners can restore them
+        if let Some(days) = self.unaccessed_days {
+            loop {
+                let files = self.db.list_unaccessed_files(days, 100).await?;
+                if files.is_empty() {
+                    break;
+                }
+                for f in files {
+                    self.db.trash_file_all_owners(&f.id).await?;
+                    info!(
+                        "Moved file {} to the trash, it was not accessed for {} days",
+                        hex::encode(&f.id),
+                        days
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove a users ownership of a file, deleting the file completely when nobody else owns it
+    async fn remove_owner(&self, file: &Vec<u8>, user_id: u64, reason: &str) -> Result<(), Error> {
+        self.db.delete_file_owner(file, user_id, None).await?;
+        if self.db.get_file_owners(file).await?.is_empty() {
+            let variants = self.db.delete_file(file).await?;
+            if let Err(e) = self.fs.delete(file, &variants) {
+                warn!(
+                    "Failed to delete {} file {}: {}",
+                    reason,
+                    hex::encode(file),
+                    e
+                );
+            }
+            info!("Deleted {} file {}", reason, hex::encode(file));
+        }
+        Ok(())
+    }
+}
@@ -21,6 +21,11 @@ impl Fairing for CORS {
             "PUT, GET, HEAD, DELETE, OPTIONS, POST",
         ));
         response.set_header(Header::new("Access-Control-Allow-Headers", "*"));
+        response.set_header(Header::new(
+            "Access-Control-Expose-Headers",
+            "X-Reason, X-Sha-256, X-Blake3, X-Content-Length, X-Content-Type, X-Created, X-Dimensions, \
+            X-Blurhash, X-Duration, X-Labels, X-Uploader, X-Downloads, X-Last-Accessed",
+        ));
         response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
 
         // force status 200 for options requests
@@ -1,8 +1,12 @@
 use chrono::{DateTime, Utc};
-use serde::Serialize;
-use sqlx::migrate::MigrateError;
+use serde::{Deserialize, Serialize};
+use sqlx::migrate::{MigrateError, Migrator};
+use sqlx::mysql::MySqlConnection;
 use sqlx::{Error, Executor, FromRow, Row};
 
+/// Schema migrations embedded in the binary
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations/");
+
 #[derive(Clone, FromRow, Default, Serialize)]
 pub struct FileUpload {
     #[serde(with = "hex")]
@@ -14,13 +18,181 @@ pub struct FileUpload {
     pub width: Option<u32>,
     pub height: Option<u32>,
     pub blur_hash: Option<String>,
+    /// Duration of audio / video files in seconds
+    pub duration: Option<f32>,
     pub alt: Option<String>,
+    pub storage_class: StorageClass,
+    /// Number of times this file was downloaded
+    pub downloads: u64,
+    /// Last time this file was downloaded
+    pub last_accessed: Option<DateTime<Utc>>,
+    /// Mime type of the preview image, set once a preview has been generated
+    pub thumb_mime: Option<String>,
+    /// When the last owner moved this file to the trash, the file is not served while set
+    pub deleted: Option<DateTime<Utc>>,
+    /// SHA-256 of the file as it was uploaded, before any transformations
+    #[serde(skip)]
+    pub original_hash: Option<Vec<u8>>,
+    /// BLAKE3 digest of the stored file, when enabled with [crate::settings::Settings::blake3]
+    #[serde(skip)]
+    pub blake3: Option<Vec<u8>>,
+
+    /// When the uploader's ownership of this file expires
+    #[sqlx(skip)]
+    #[serde(skip)]
+    pub expires: Option<DateTime<Utc>>,
 
     #[sqlx(skip)]
     #[cfg(feature = "labels")]
     pub labels: Vec<FileLabel>,
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, sqlx::Type, Serialize, Deserialize)]
+#[sqlx(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum StorageClass {
+    /// File is stored in the primary storage directory
+    #[default]
+    Hot,
+    /// File has been moved to the secondary (cold) storage directory
+    Cold,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, sqlx::Type, Serialize)]
+#[sqlx(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum JobKind {
+    /// Classify the contents of an image (`labels` feature)
+    Label,
+    /// Render a preview image for images, videos and documents (PDF)
+    Thumbnail,
+    /// Compress an image / transcode a video into a variant, see [crate::filesystem::FileStore::transform]
+    Transform,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, sqlx::Type, Serialize, Deserialize)]
+#[sqlx(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum VariantKind {
+    /// Preview image
+    Thumbnail,
+    /// Same media re-encoded with a different codec / resolution
+    Transcode,
+    /// Same media in a different container / image format
+    Conversion,
+}
+
+/// Blob derived from an upload, stored separately by its own hash
+/// and removed together with the upload
+#[derive(Clone, FromRow, Serialize, Deserialize)]
+pub struct FileVariant {
+    /// Hash of the upload this blob was derived from
+    #[serde(skip)]
+    pub file: Vec<u8>,
+    /// SHA-256 of the derived blob
+    #[serde(with = "hex")]
+    pub id: Vec<u8>,
+    pub kind: VariantKind,
+    pub size: u64,
+    pub mime_type: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub codec: Option<String>,
+    pub created: DateTime<Utc>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, sqlx::Type, Serialize)]
+#[sqlx(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Queued,
+    Running,
+    Done,
+    /// Job failed too many times and will not be retried
+    Failed,
+}
+
+/// Background processing job for an uploaded file
+#[derive(Clone, FromRow, Serialize)]
+pub struct ProcessingJob {
+    pub id: u64,
+    #[serde(with = "hex")]
+    pub file: Vec<u8>,
+    pub kind: JobKind,
+    pub state: JobState,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub next_attempt: DateTime<Utc>,
+    pub created: DateTime<Utc>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, sqlx::Type, Serialize)]
+#[sqlx(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    /// User deleted one of their files
+    Delete,
+    /// User restored a file from the trash
+    Restore,
+    /// Admin removed a file for all users
+    Takedown,
+    /// Admin changed the plan (quota) of a user
+    SetPlan,
+    /// Admin dismissed a report without removing the file
+    DismissReport,
+    /// Admin banned a file hash so it can't be uploaded again
+    Ban,
+}
+
+/// Audit log entry, written in the same transaction as the change it records
+pub struct NewAuditEntry<'a> {
+    pub actor: &'a Vec<u8>,
+    pub action: AuditAction,
+    /// File id or pubkey the action was performed on (hex)
+    pub target: String,
+    pub reason: Option<&'a str>,
+}
+
+impl NewAuditEntry<'_> {
+    pub(crate) async fn insert(&self, conn: &mut MySqlConnection) -> Result<(), Error> {
+        sqlx::query("insert into audit_log(actor,action,target,reason) values(?,?,?,?)")
+            .bind(self.actor)
+            .bind(self.action)
+            .bind(&self.target)
+            .bind(self.reason)
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Append-only record of a destructive or admin action
+#[derive(Clone, FromRow, Serialize)]
+pub struct AuditEntry {
+    pub id: u64,
+    /// Pubkey of the user who performed the action
+    #[serde(with = "hex")]
+    pub actor: Vec<u8>,
+    pub action: AuditAction,
+    /// File id or pubkey the action was performed on (hex)
+    pub target: String,
+    pub reason: Option<String>,
+    pub created: DateTime<Utc>,
+}
+
+/// Report of a file by a user, waiting for review by an admin
+#[derive(Clone, FromRow, Serialize)]
+pub struct Report {
+    pub id: u64,
+    #[serde(with = "hex")]
+    pub file: Vec<u8>,
+    /// Pubkey of the user who reported the file
+    #[serde(with = "hex")]
+    pub reporter: Vec<u8>,
+    pub reason: String,
+    pub created: DateTime<Utc>,
+}
+
 #[derive(Clone, FromRow, Serialize)]
 pub struct User {
     pub id: u64,
@@ -28,6 +200,29 @@ pub struct User {
     pub pubkey: Vec<u8>,
     pub created: DateTime<Utc>,
     pub is_admin: bool,
+    /// Upload plan id, see [crate::settings::Settings::plans]
+    pub plan: Option<String>,
+}
+
+#[derive(Clone, FromRow, Serialize)]
+pub struct ApiKey {
+    pub id: u64,
+    pub user_id: u64,
+    #[serde(skip)]
+    pub key_hash: Vec<u8>,
+    pub name: Option<String>,
+    pub created: DateTime<Utc>,
+}
+
+/// Ownership of a file by a user
+#[derive(Clone, FromRow)]
+pub struct UserUpload {
+    pub pubkey: Vec<u8>,
+    pub created: Option<DateTime<Utc>>,
+    pub expires: Option<DateTime<Utc>>,
+    pub delegate: Option<Vec<u8>>,
+    /// File is shown in the public feed
+    pub public: bool,
 }
 
 #[cfg(feature = "labels")]
@@ -63,7 +258,30 @@ impl Database {
     }
 
     pub async fn migrate(&self) -> Result<(), MigrateError> {
-        sqlx::migrate!("./migrations/").run(&self.pool).await
+        MIGRATOR.run(&self.pool).await
+    }
+
+    /// Latest schema version known by this build
+    pub fn latest_schema_version() -> i64 {
+        MIGRATOR.iter().map(|m| m.version).max().unwrap_or(0)
+    }
+
+    /// Schema version of the database, [None] when no migrations have been applied yet
+    pub async fn schema_version(&self) -> Result<Option<i64>, Error> {
+        let has_table: i64 = sqlx::query(
+            "select count(*) from information_schema.tables \
+            where table_schema = database() and table_name = '_sqlx_migrations'",
+        )
+        .fetch_one(&self.pool)
+        .await?
+        .try_get(0)?;
+        if has_table == 0 {
+            return Ok(None);
+        }
+        sqlx::query("select max(version) from _sqlx_migrations where success = 1")
+            .fetch_one(&self.pool)
+            .await?
+            .try_get(0)
     }
 
     pub async fn upsert_user(&self, pubkey: &Vec<u8>) -> Result<u64, Error> {
@@ -88,6 +306,63 @@ impl Database {
             .await
     }
 
+    pub async fn set_user_plan(
+        &self,
+        pubkey: &Vec<u8>,
+        plan: Option<&str>,
+        audit: &NewAuditEntry<'_>,
+    ) -> Result<u64, Error> {
+        let mut tx = self.pool.begin().await?;
+        let updated = sqlx::query("update users set plan = ? where pubkey = ?")
+            .bind(plan)
+            .bind(pubkey)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        audit.insert(&mut tx).await?;
+        tx.commit().await?;
+        Ok(updated)
+    }
+
+    /// Report a file for review, a user can only report a file once
+    pub async fn add_report(
+        &self,
+        file: &Vec<u8>,
+        reporter_id: u64,
+        reason: &str,
+    ) -> Result<(), Error> {
+        sqlx::query("insert ignore into reports(file,reporter_id,reason) values(?,?,?)")
+            .bind(file)
+            .bind(reporter_id)
+            .bind(reason)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Ban a file hash, banned files can not be uploaded or downloaded
+    pub(crate) async fn ban_hash(
+        conn: &mut MySqlConnection,
+        hash: &Vec<u8>,
+        reason: Option<&str>,
+    ) -> Result<(), Error> {
+        sqlx::query("insert ignore into banned_hashes(hash,reason) values(?,?)")
+            .bind(hash)
+            .bind(reason)
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn list_banned_hashes(&self) -> Result<Vec<Vec<u8>>, Error> {
+        sqlx::query("select hash from banned_hashes")
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(|r| r.try_get(0))
+            .collect()
+    }
+
     pub async fn get_user_id(&self, pubkey: &Vec<u8>) -> Result<u64, Error> {
         sqlx::query("select id from users where pubkey = ?")
             .bind(pubkey)
@@ -96,10 +371,30 @@ impl Database {
             .try_get(0)
     }
 
-    pub async fn add_file(&self, file: &FileUpload, user_id: u64) -> Result<(), Error> {
+    /// Insert a user from a backup, existing users are not modified
+    pub async fn import_user(&self, user: &User) -> Result<(), Error> {
+        sqlx::query("insert ignore into users(pubkey,created,is_admin,plan) values(?,?,?,?)")
+            .bind(&user.pubkey)
+            .bind(user.created)
+            .bind(user.is_admin)
+            .bind(&user.plan)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Save a file and its ownership by a user, `delegate` is the pubkey which signed
+    /// the upload when it was delegated (NIP-26). Returns false when the file was
+    /// already stored (deduplicated), only the ownership is saved then
+    pub async fn add_file(
+        &self,
+        file: &FileUpload,
+        user_id: u64,
+        delegate: Option<&Vec<u8>>,
+    ) -> Result<bool, Error> {
         let mut tx = self.pool.begin().await?;
         let q = sqlx::query("insert ignore into \
-        uploads(id,name,size,mime_type,blur_hash,width,height,alt,created) values(?,?,?,?,?,?,?,?,?)")
+        uploads(id,name,size,mime_type,blur_hash,width,height,duration,alt,created,original_hash,blake3) values(?,?,?,?,?,?,?,?,?,?,?,?)")
             .bind(&file.id)
             .bind(&file.name)
             .bind(file.size)
@@ -107,14 +402,25 @@ impl Database {
             .bind(&file.blur_hash)
             .bind(file.width)
             .bind(file.height)
+            .bind(file.duration)
             .bind(&file.alt)
-            .bind(file.created);
-        tx.execute(q).await?;
+            .bind(file.created)
+            .bind(&file.original_hash)
+            .bind(&file.blake3);
+        let inserted = tx.execute(q).await?.rows_affected() == 1;
 
-        let q2 = sqlx::query("insert ignore into user_uploads(file,user_id) values(?,?)")
-            .bind(&file.id)
-            .bind(user_id);
+        // uploading a file again takes it out of the trash
+        let q2 = sqlx::query(
+            "insert into user_uploads(file,user_id,delegate,expires) values(?,?,?,?) \
+            on duplicate key update deleted = null",
+        )
+        .bind(&file.id)
+        .bind(user_id)
+        .bind(delegate)
+        .bind(file.expires);
         tx.execute(q2).await?;
+        tx.execute(sqlx::query("update uploads set deleted = null where id = ?").bind(&file.id))
+            .await?;
 
         #[cfg(feature = "labels")]
         for lbl in &file.labels {
@@ -126,7 +432,7 @@ impl Database {
             tx.execute(q3).await?;
         }
         tx.commit().await?;
-        Ok(())
+        Ok(inserted)
     }
 
     pub async fn get_file(&self, file: &Vec<u8>) -> Result<Option<FileUpload>, Error> {
@@ -147,6 +453,98 @@ impl Database {
         .await
     }
 
+    /// List all files which are not in the trash, in id order
+    pub async fn list_stored_files(
+        &self,
+        offset: u32,
+        limit: u32,
+    ) -> Result<Vec<FileUpload>, Error> {
+        sqlx::query_as("select * from uploads where deleted is null order by id limit ? offset ?")
+            .bind(limit)
+            .bind(offset)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    /// Owners of a file, excluding owners who moved it to the trash
+    pub async fn get_file_uploads(&self, file: &Vec<u8>) -> Result<Vec<UserUpload>, Error> {
+        sqlx::query_as(
+            "select users.pubkey, user_uploads.created, user_uploads.expires, \
+            user_uploads.delegate, user_uploads.public \
+            from users, user_uploads \
+            where users.id = user_uploads.user_id \
+            and user_uploads.file = ? \
+            and user_uploads.deleted is null",
+        )
+        .bind(file)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Insert a file and its owners from a backup, keeping the original timestamps
+    pub async fn import_file(&self, file: &FileUpload, owners: &[UserUpload]) -> Result<(), Error> {
+        let mut tx = self.pool.begin().await?;
+        let q = sqlx::query("insert ignore into \
+        uploads(id,name,size,mime_type,blur_hash,width,height,duration,alt,created,original_hash,blake3,thumb_mime) values(?,?,?,?,?,?,?,?,?,?,?,?,?)")
+            .bind(&file.id)
+            .bind(&file.name)
+            .bind(file.size)
+            .bind(&file.mime_type)
+            .bind(&file.blur_hash)
+            .bind(file.width)
+            .bind(file.height)
+            .bind(file.duration)
+            .bind(&file.alt)
+            .bind(file.created)
+            .bind(&file.original_hash)
+            .bind(&file.blake3)
+            .bind(&file.thumb_mime);
+        tx.execute(q).await?;
+
+        for owner in owners {
+            tx.execute(
+                sqlx::query("insert ignore into users(pubkey) values(?)").bind(&owner.pubkey),
+            )
+            .await?;
+            let q2 = sqlx::query(
+                "insert ignore into user_uploads(file,user_id,created,expires,delegate,public) \
+                select ?, id, ?, ?, ?, ? from users where pubkey = ?",
+            )
+            .bind(&file.id)
+            .bind(owner.created)
+            .bind(owner.expires)
+            .bind(&owner.delegate)
+            .bind(owner.public)
+            .bind(&owner.pubkey);
+            tx.execute(q2).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Find a file by its BLAKE3 digest
+    pub async fn get_file_by_blake3(&self, blake3: &Vec<u8>) -> Result<Option<FileUpload>, Error> {
+        sqlx::query_as("select * from uploads where blake3 = ? limit 1")
+            .bind(blake3)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    /// First user who uploaded a file and still owns it
+    pub async fn get_file_uploader(&self, file: &Vec<u8>) -> Result<Option<User>, Error> {
+        sqlx::query_as(
+            "select users.* from users, user_uploads \
+        where users.id = user_uploads.user_id \
+        and user_uploads.file = ? \
+        and user_uploads.deleted is null \
+        order by user_uploads.created \
+        limit 1",
+        )
+        .bind(file)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
     #[cfg(feature = "labels")]
     pub async fn get_file_labels(&self, file: &Vec<u8>) -> Result<Vec<FileLabel>, Error> {
         sqlx::query_as(
@@ -158,23 +556,341 @@ impl Database {
         .await
     }
 
-    pub async fn delete_file_owner(&self, file: &Vec<u8>, owner: u64) -> Result<(), Error> {
+    /// Remove a file from an owner, the audit entry is written when set
+    pub async fn delete_file_owner(
+        &self,
+        file: &Vec<u8>,
+        owner: u64,
+        audit: Option<&NewAuditEntry<'_>>,
+    ) -> Result<(), Error> {
+        let mut tx = self.pool.begin().await?;
         sqlx::query("delete from user_uploads where file = ? and user_id = ?")
             .bind(file)
             .bind(owner)
-            .execute(&self.pool)
+            .execute(&mut *tx)
             .await?;
+        if let Some(a) = audit {
+            a.insert(&mut tx).await?;
+        }
+        tx.commit().await?;
         Ok(())
     }
 
-    pub async fn delete_file(&self, file: &Vec<u8>) -> Result<(), Error> {
+    /// Delete a file and its variants, returns the variant blobs which are
+    /// not used by any other file and can be removed from storage
+    pub async fn delete_file(&self, file: &Vec<u8>) -> Result<Vec<Vec<u8>>, Error> {
+        let mut tx = self.pool.begin().await?;
+        let unused = Self::delete_file_variants(&mut tx, file).await?;
+        tx.commit().await?;
+        Ok(unused)
+    }
+
+    /// See [Database::delete_file]
+    pub(crate) async fn delete_file_variants(
+        conn: &mut MySqlConnection,
+        file: &Vec<u8>,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        let variants: Vec<Vec<u8>> =
+            sqlx::query_scalar("select id from upload_variants where file = ?")
+                .bind(file)
+                .fetch_all(&mut *conn)
+                .await?;
         sqlx::query("delete from uploads where id = ?")
+            .bind(file)
+            .execute(&mut *conn)
+            .await?;
+        let mut unused = Vec::new();
+        for v in variants {
+            let used: i64 = sqlx::query_scalar("select count(*) from upload_variants where id = ?")
+                .bind(&v)
+                .fetch_one(&mut *conn)
+                .await?;
+            if used == 0 {
+                unused.push(v);
+            }
+        }
+        Ok(unused)
+    }
+
+    pub async fn add_file_variant(&self, variant: &FileVariant) -> Result<(), Error> {
+        sqlx::query(
+            "insert into upload_variants(file,id,kind,size,mime_type,width,height,codec) \
+            values(?,?,?,?,?,?,?,?) \
+            on duplicate key update kind = values(kind), mime_type = values(mime_type), \
+            width = values(width), height = values(height), codec = values(codec)",
+        )
+        .bind(&variant.file)
+        .bind(&variant.id)
+        .bind(variant.kind)
+        .bind(variant.size)
+        .bind(&variant.mime_type)
+        .bind(variant.width)
+        .bind(variant.height)
+        .bind(&variant.codec)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_file_variants(&self, file: &Vec<u8>) -> Result<Vec<FileVariant>, Error> {
+        sqlx::query_as("select * from upload_variants where file = ? order by kind, created")
+            .bind(file)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    /// Variant blob by its own hash, only when the original file is not in the trash
+    pub async fn get_variant(&self, id: &Vec<u8>) -> Result<Option<FileVariant>, Error> {
+        sqlx::query_as(
+            "select v.* from upload_variants v, uploads u \
+            where v.id = ? and v.file = u.id and u.deleted is null \
+            limit 1",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    /// Newest variant of a kind for a file
+    pub async fn get_file_variant(
+        &self,
+        file: &Vec<u8>,
+        kind: VariantKind,
+    ) -> Result<Option<FileVariant>, Error> {
+        sqlx::query_as(
+            "select * from upload_variants where file = ? and kind = ? \
+            order by created desc limit 1",
+        )
+        .bind(file)
+        .bind(kind)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    /// Move a file to the trash of an owner, the file is marked as deleted
+    /// when all owners have moved it to the trash
+    pub async fn trash_file(
+        &self,
+        file: &Vec<u8>,
+        owner: u64,
+        audit: &NewAuditEntry<'_>,
+    ) -> Result<(), Error> {
+        let mut tx = self.pool.begin().await?;
+        tx.execute(
+            sqlx::query(
+                "update user_uploads set deleted = current_timestamp \
+                where file = ? and user_id = ? and deleted is null",
+            )
+            .bind(file)
+            .bind(owner),
+        )
+        .await?;
+        tx.execute(
+            sqlx::query(
+                "update uploads set deleted = current_timestamp \
+                where id = ? \
+                and not exists(select 1 from user_uploads where file = ? and deleted is null)",
+            )
+            .bind(file)
+            .bind(file),
+        )
+        .await?;
+        audit.insert(&mut tx).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Move a file to the trash of all its owners
+    pub async fn trash_file_all_owners(&self, file: &Vec<u8>) -> Result<(), Error> {
+        let mut tx = self.pool.begin().await?;
+        tx.execute(
+            sqlx::query(
+                "update user_uploads set deleted = current_timestamp \
+                where file = ? and deleted is null",
+            )
+            .bind(file),
+        )
+        .await?;
+        tx.execute(
+            sqlx::query("update uploads set deleted = current_timestamp where id = ?").bind(file),
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Take a file out of the trash of an owner, returns false when the file was not in the trash.
+    /// Restoring counts as an access so that unaccessed files are not moved to the trash again
+    pub async fn restore_file(
+        &self,
+        file: &Vec<u8>,
+        owner: u64,
+        audit: &NewAuditEntry<'_>,
+    ) -> Result<bool, Error> {
+        let mut tx = self.pool.begin().await?;
+        let restored = tx
+            .execute(
+                sqlx::query(
+                    "update user_uploads set deleted = null \
+                    where file = ? and user_id = ? and deleted is not null",
+                )
+                .bind(file)
+                .bind(owner),
+            )
+            .await?
+            .rows_affected();
+        if restored > 0 {
+            tx.execute(
+                sqlx::query(
+                    "update uploads set deleted = null, last_accessed = current_timestamp \
+                    where id = ?",
+                )
+                .bind(file),
+            )
+            .await?;
+            audit.insert(&mut tx).await?;
+        }
+        tx.commit().await?;
+        Ok(restored > 0)
+    }
+
+    /// List the files in a users trash
+    pub async fn list_trash(
+        &self,
+        pubkey: &Vec<u8>,
+        offset: u32,
+        limit: u32,
+    ) -> Result<(Vec<FileUpload>, i64), Error> {
+        let results: Vec<FileUpload> = sqlx::query_as(
+            "select uploads.* from uploads, users, user_uploads \
+            where users.pubkey = ? \
+            and users.id = user_uploads.user_id \
+            and user_uploads.file = uploads.id \
+            and user_uploads.deleted is not null \
+            order by user_uploads.deleted desc \
+            limit ? offset ?",
+        )
+        .bind(pubkey)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
+        let count: i64 = sqlx::query(
+            "select count(uploads.id) from uploads, users, user_uploads \
+            where users.pubkey = ? \
+            and users.id = user_uploads.user_id \
+            and user_uploads.file = uploads.id \
+            and user_uploads.deleted is not null",
+        )
+        .bind(pubkey)
+        .fetch_one(&self.pool)
+        .await?
+        .try_get(0)?;
+        Ok((results, count))
+    }
+
+    /// List (file, user_id) pairs which have been in the trash for more than `days`
+    pub async fn list_trashed_uploads(
+        &self,
+        days: u32,
+        limit: u32,
+    ) -> Result<Vec<(Vec<u8>, u64)>, Error> {
+        sqlx::query_as(
+            "select file, user_id from user_uploads \
+            where deleted < date_sub(current_timestamp, interval ? day) \
+            limit ?",
+        )
+        .bind(days)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Files with a preview image stored next to the file (`.thumb`) by older versions,
+    /// which have no thumbnail variant yet
+    pub async fn list_legacy_thumbnails(&self, limit: u32) -> Result<Vec<FileUpload>, Error> {
+        sqlx::query_as(
+            "select u.* from uploads u \
+            where u.thumb_mime is not null \
+            and not exists(select 1 from upload_variants v where v.file = u.id and v.kind = 'thumbnail') \
+            limit ?",
+        )
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Mark a file as having a preview image, [None] when it has no preview
+    pub async fn set_file_thumbnail(
+        &self,
+        file: &Vec<u8>,
+        mime_type: Option<&str>,
+    ) -> Result<(), Error> {
+        sqlx::query("update uploads set thumb_mime = ? where id = ?")
+            .bind(mime_type)
             .bind(file)
             .execute(&self.pool)
             .await?;
         Ok(())
     }
 
+    /// Find a file owned by a user by its stored hash or the hash of the original upload
+    pub async fn get_user_file_id(
+        &self,
+        pubkey: &Vec<u8>,
+        hash: &Vec<u8>,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        sqlx::query(
+            "select uploads.id from uploads, users, user_uploads \
+            where users.pubkey = ? \
+            and users.id = user_uploads.user_id \
+            and user_uploads.file = uploads.id \
+            and (uploads.id = ? or uploads.original_hash = ?) \
+            order by uploads.id = ? desc \
+            limit 1",
+        )
+        .bind(pubkey)
+        .bind(hash)
+        .bind(hash)
+        .bind(hash)
+        .fetch_optional(&self.pool)
+        .await?
+        .map(|r| r.try_get(0))
+        .transpose()
+    }
+
+    /// Size of the files of a user (including the trash) and their variants
+    pub async fn get_user_storage(&self, pubkey: &Vec<u8>) -> Result<u64, Error> {
+        let size: u64 = sqlx::query(
+            "select cast(coalesce(( \
+                select sum(u.size) from users us, user_uploads uu, uploads u \
+                where us.pubkey = ? and uu.user_id = us.id and u.id = uu.file), 0) \
+            + coalesce(( \
+                select sum(v.size) from users us, user_uploads uu, upload_variants v \
+                where us.pubkey = ? and uu.user_id = us.id and v.file = uu.file), 0) as unsigned)",
+        )
+        .bind(pubkey)
+        .bind(pubkey)
+        .fetch_one(&self.pool)
+        .await?
+        .try_get(0)?;
+        Ok(size)
+    }
+
+    /// Number of files owned by a user, including files in the trash
+    pub async fn count_user_files(&self, pubkey: &Vec<u8>) -> Result<u64, Error> {
+        let count: i64 = sqlx::query(
+            "select count(user_uploads.file) from users, user_uploads \
+            where users.pubkey = ? \
+            and users.id = user_uploads.user_id",
+        )
+        .bind(pubkey)
+        .fetch_one(&self.pool)
+        .await?
+        .try_get(0)?;
+        Ok(count as u64)
+    }
+
     pub async fn list_files(
         &self,
         pubkey: &Vec<u8>,
@@ -186,10 +902,58 @@ impl Database {
             where users.pubkey = ? \
             and users.id = user_uploads.user_id \
             and user_uploads.file = uploads.id \
+            and user_uploads.deleted is null \
+            order by uploads.created desc \
+            limit ? offset ?",
+        )
+        .bind(pubkey)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
+        let count: i64 = sqlx::query(
+            "select count(uploads.id) from uploads, users, user_uploads \
+            where users.pubkey = ? \
+            and users.id = user_uploads.user_id \
+            and user_uploads.file = uploads.id \
+            and user_uploads.deleted is null",
+        )
+        .bind(pubkey)
+        .fetch_one(&self.pool)
+        .await?
+        .try_get(0)?;
+
+        Ok((results, count))
+    }
+
+    /// Files of a user whose name or mime type contains `query`
+    pub async fn search_files(
+        &self,
+        pubkey: &Vec<u8>,
+        query: &str,
+        offset: u32,
+        limit: u32,
+    ) -> Result<(Vec<FileUpload>, i64), Error> {
+        let pattern = format!(
+            "%{}%",
+            query
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_")
+        );
+        let results: Vec<FileUpload> = sqlx::query_as(
+            "select uploads.* from uploads, users, user_uploads \
+            where users.pubkey = ? \
+            and users.id = user_uploads.user_id \
+            and user_uploads.file = uploads.id \
+            and user_uploads.deleted is null \
+            and (uploads.name like ? or uploads.mime_type like ?) \
             order by uploads.created desc \
             limit ? offset ?",
         )
         .bind(pubkey)
+        .bind(&pattern)
+        .bind(&pattern)
         .bind(limit)
         .bind(offset)
         .fetch_all(&self.pool)
@@ -198,13 +962,394 @@ impl Database {
             "select count(uploads.id) from uploads, users, user_uploads \
             where users.pubkey = ? \
             and users.id = user_uploads.user_id \
-            and user_uploads.file = uploads.id",
+            and user_uploads.file = uploads.id \
+            and user_uploads.deleted is null \
+            and (uploads.name like ? or uploads.mime_type like ?)",
+        )
+        .bind(pubkey)
+        .bind(&pattern)
+        .bind(&pattern)
+        .fetch_one(&self.pool)
+        .await?
+        .try_get(0)?;
+
+        Ok((results, count))
+    }
+
+    /// Show or hide a file owned by `pubkey` in the public feed, returns false when the user
+    /// does not own the file
+    pub async fn set_file_public(
+        &self,
+        file: &Vec<u8>,
+        pubkey: &Vec<u8>,
+        public: bool,
+    ) -> Result<bool, Error> {
+        let res = sqlx::query(
+            "update user_uploads, users set user_uploads.public = ? \
+            where users.pubkey = ? \
+            and users.id = user_uploads.user_id \
+            and user_uploads.file = ? \
+            and user_uploads.deleted is null",
         )
+        .bind(public)
         .bind(pubkey)
+        .bind(file)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// List media files which were shared in the public feed, newest first
+    pub async fn list_public_files(
+        &self,
+        offset: u32,
+        limit: u32,
+    ) -> Result<(Vec<FileUpload>, i64), Error> {
+        let results: Vec<FileUpload> = sqlx::query_as(
+            "select uploads.* from uploads \
+            where uploads.deleted is null \
+            and (uploads.mime_type like 'image/%' \
+            or uploads.mime_type like 'video/%' \
+            or uploads.mime_type like 'audio/%') \
+            and exists (select 1 from user_uploads \
+            where user_uploads.file = uploads.id \
+            and user_uploads.public = 1 \
+            and user_uploads.deleted is null) \
+            order by uploads.created desc \
+            limit ? offset ?",
+        )
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
+        let count: i64 = sqlx::query(
+            "select count(uploads.id) from uploads \
+            where uploads.deleted is null \
+            and (uploads.mime_type like 'image/%' \
+            or uploads.mime_type like 'video/%' \
+            or uploads.mime_type like 'audio/%') \
+            and exists (select 1 from user_uploads \
+            where user_uploads.file = uploads.id \
+            and user_uploads.public = 1 \
+            and user_uploads.deleted is null)",
+        )
         .fetch_one(&self.pool)
         .await?
         .try_get(0)?;
 
         Ok((results, count))
     }
+
+    /// Set the storage tier of a file
+    pub async fn set_storage_class(
+        &self,
+        file: &Vec<u8>,
+        class: StorageClass,
+    ) -> Result<(), Error> {
+        sqlx::query(
+            "update uploads set storage_class = ?, storage_class_changed = current_timestamp \
+            where id = ?",
+        )
+        .bind(class)
+        .bind(file)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Add to the download counter of a file and update its last access time
+    pub async fn add_downloads(&self, file: &Vec<u8>, count: u64) -> Result<(), Error> {
+        sqlx::query(
+            "update uploads set downloads = downloads + ?, last_accessed = current_timestamp \
+            where id = ?",
+        )
+        .bind(count)
+        .bind(file)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Add served bytes to the current month's bandwidth usage of the file's first owner
+    pub async fn add_bandwidth(&self, file: &Vec<u8>, bytes: u64) -> Result<(), Error> {
+        sqlx::query(
+            "insert into user_bandwidth(user_id, month, bytes) \
+            select user_id, date_format(current_timestamp, '%Y-%m'), ? from user_uploads \
+            where file = ? order by created limit 1 \
+            on duplicate key update bytes = user_bandwidth.bytes + ?",
+        )
+        .bind(bytes)
+        .bind(file)
+        .bind(bytes)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Bandwidth used by a user in the current month
+    pub async fn get_user_bandwidth(&self, user_id: u64) -> Result<u64, Error> {
+        let bytes: Option<u64> = sqlx::query(
+            "select bytes from user_bandwidth \
+            where user_id = ? and month = date_format(current_timestamp, '%Y-%m')",
+        )
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?
+        .map(|r| r.try_get(0))
+        .transpose()?;
+        Ok(bytes.unwrap_or(0))
+    }
+
+    /// Get the first owner of a file, bandwidth for the file is accounted to this user
+    pub async fn get_file_bandwidth_owner(&self, file: &Vec<u8>) -> Result<Option<User>, Error> {
+        sqlx::query_as(
+            "select users.* from users, user_uploads \
+            where user_uploads.file = ? \
+            and users.id = user_uploads.user_id \
+            order by user_uploads.created \
+            limit 1",
+        )
+        .bind(file)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    /// List files which have not been downloaded for the given number of days
+    pub async fn list_unaccessed_files(
+        &self,
+        days: u32,
+        limit: u32,
+    ) -> Result<Vec<FileUpload>, Error> {
+        sqlx::query_as(
+            "select * from uploads \
+            where deleted is null \
+            and coalesce(last_accessed, created) < date_sub(current_timestamp, interval ? day) \
+            limit ?",
+        )
+        .bind(days)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// List hot files which have not been touched for the given number of days
+    pub async fn list_tiering_candidates(
+        &self,
+        days: u32,
+        offset: u32,
+        limit: u32,
+    ) -> Result<Vec<FileUpload>, Error> {
+        sqlx::query_as(
+            "select * from uploads \
+            where storage_class = 'hot' \
+            and greatest(coalesce(storage_class_changed, created), coalesce(last_accessed, created)) \
+            < date_sub(current_timestamp, interval ? day) \
+            order by id \
+            limit ? offset ?",
+        )
+        .bind(days)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn add_api_key(
+        &self,
+        user_id: u64,
+        key_hash: &Vec<u8>,
+        name: Option<&str>,
+    ) -> Result<u64, Error> {
+        sqlx::query("insert into api_keys(user_id,key_hash,name) values(?,?,?) returning id")
+            .bind(user_id)
+            .bind(key_hash)
+            .bind(name)
+            .fetch_one(&self.pool)
+            .await?
+            .try_get(0)
+    }
+
+    pub async fn list_api_keys(&self, user_id: u64) -> Result<Vec<ApiKey>, Error> {
+        sqlx::query_as("select * from api_keys where user_id = ? order by created desc")
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    pub async fn delete_api_key(&self, user_id: u64, id: u64) -> Result<u64, Error> {
+        Ok(
+            sqlx::query("delete from api_keys where user_id = ? and id = ?")
+                .bind(user_id)
+                .bind(id)
+                .execute(&self.pool)
+                .await?
+                .rows_affected(),
+        )
+    }
+
+    /// Get the owner of an API key by the hash of the key
+    pub async fn get_api_key_user(&self, key_hash: &Vec<u8>) -> Result<Option<User>, Error> {
+        sqlx::query_as(
+            "select users.* from users, api_keys \
+            where api_keys.key_hash = ? \
+            and users.id = api_keys.user_id",
+        )
+        .bind(key_hash)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    /// Store a portal session by the hash of its token
+    pub async fn add_session(
+        &self,
+        user_id: u64,
+        token_hash: &Vec<u8>,
+        expires: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        sqlx::query("insert into user_sessions(user_id,token_hash,expires) values(?,?,?)")
+            .bind(user_id)
+            .bind(token_hash)
+            .bind(expires)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Get the owner of a session by the hash of its token, [None] when the session expired
+    pub async fn get_session_user(&self, token_hash: &Vec<u8>) -> Result<Option<User>, Error> {
+        sqlx::query_as(
+            "select users.* from users, user_sessions \
+            where user_sessions.token_hash = ? \
+            and user_sessions.expires > current_timestamp \
+            and users.id = user_sessions.user_id",
+        )
+        .bind(token_hash)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    pub async fn delete_session(&self, token_hash: &Vec<u8>) -> Result<(), Error> {
+        sqlx::query("delete from user_sessions where token_hash = ?")
+            .bind(token_hash)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Remove expired portal sessions, returns the number of sessions removed
+    pub async fn delete_expired_sessions(&self) -> Result<u64, Error> {
+        Ok(
+            sqlx::query("delete from user_sessions where expires < current_timestamp")
+                .execute(&self.pool)
+                .await?
+                .rows_affected(),
+        )
+    }
+
+    /// List file ownerships which have expired as (file, user_id)
+    pub async fn list_expired_uploads(&self, limit: u32) -> Result<Vec<(Vec<u8>, u64)>, Error> {
+        sqlx::query_as(
+            "select file, user_id from user_uploads \
+            where expires < current_timestamp \
+            limit ?",
+        )
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Queue a processing job for a file, returns false when the file already has a job of this kind
+    pub async fn add_processing_job(&self, file: &Vec<u8>, kind: JobKind) -> Result<bool, Error> {
+        let res = sqlx::query(
+            "insert into processing_jobs(file,kind) \
+            select ?, ? from dual \
+            where not exists(select 1 from processing_jobs where file = ? and kind = ?)",
+        )
+        .bind(file)
+        .bind(kind)
+        .bind(file)
+        .bind(kind)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// Put jobs which were running when the server stopped back in the queue
+    pub async fn reset_running_jobs(&self) -> Result<u64, Error> {
+        Ok(
+            sqlx::query("update processing_jobs set state = 'queued' where state = 'running'")
+                .execute(&self.pool)
+                .await?
+                .rows_affected(),
+        )
+    }
+
+    /// Take the next queued job which is due and mark it as running
+    pub async fn claim_processing_job(&self) -> Result<Option<ProcessingJob>, Error> {
+        let mut tx = self.pool.begin().await?;
+        let job: Option<ProcessingJob> = sqlx::query_as(
+            "select * from processing_jobs \
+            where state = 'queued' and next_attempt <= current_timestamp \
+            order by next_attempt \
+            limit 1 \
+            for update skip locked",
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if let Some(j) = &job {
+            sqlx::query(
+                "update processing_jobs set state = 'running', attempts = attempts + 1 where id = ?",
+            )
+            .bind(j.id)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(job)
+    }
+
+    pub async fn complete_processing_job(&self, id: u64) -> Result<(), Error> {
+        sqlx::query("update processing_jobs set state = 'done', last_error = null where id = ?")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Record a job failure, the job is retried after `retry_secs` or marked as failed when [None]
+    pub async fn fail_processing_job(
+        &self,
+        id: u64,
+        error: &str,
+        retry_secs: Option<u64>,
+    ) -> Result<(), Error> {
+        let q = match retry_secs {
+            Some(s) => sqlx::query(
+                "update processing_jobs set state = 'queued', last_error = ?, \
+                next_attempt = date_add(current_timestamp, interval ? second) where id = ?",
+            )
+            .bind(error)
+            .bind(s),
+            None => sqlx::query(
+                "update processing_jobs set state = 'failed', last_error = ? where id = ?",
+            )
+            .bind(error),
+        };
+        q.bind(id).execute(&self.pool).await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "labels")]
+    pub async fn add_file_labels(&self, file: &Vec<u8>, labels: &[FileLabel]) -> Result<(), Error> {
+        let mut tx = self.pool.begin().await?;
+        for lbl in labels {
+            let q = sqlx::query("insert ignore into upload_labels(file,label,model) values(?,?,?)")
+                .bind(file)
+                .bind(&lbl.label)
+                .bind(&lbl.model);
+            tx.execute(q).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
 }
@@ -0,0 +1,129 @@
+use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Header, Status};
+use rocket::{async_trait, Request, Response};
+use tokio::io::BufReader;
+
+/// Responses smaller than this are not worth compressing
+const MIN_SIZE: usize = 1024;
+
+/// Non-text mime types which compress well
+const COMPRESSIBLE_TYPES: [&str; 7] = [
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "application/x-subrip",
+    "application/x-ndjson",
+    "image/svg+xml",
+    "image/x-icon",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    fn name(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// Pick an encoding from an `Accept-Encoding` header, brotli is preferred
+    fn negotiate(accept: &str) -> Option<Self> {
+        let accepted: Vec<&str> = accept
+            .split(',')
+            .filter_map(|e| {
+                let mut parts = e.split(';').map(|p| p.trim());
+                let name = parts.next()?;
+                // q=0 means the encoding is not acceptable
+                let rejected = parts.any(|p| {
+                    p.strip_prefix("q=")
+                        .and_then(|q| q.parse::<f32>().ok())
+                        .is_some_and(|q| q <= 0.0)
+                });
+                (!rejected).then_some(name)
+            })
+            .collect();
+        if accepted.iter().any(|e| e.eq_ignore_ascii_case("br")) {
+            Some(Encoding::Brotli)
+        } else if accepted
+            .iter()
+            .any(|e| e.eq_ignore_ascii_case("gzip") || *e == "*")
+        {
+            Some(Encoding::Gzip)
+        } else {
+            None
+        }
+    }
+}
+
+/// Check if a mime type is text-like, media formats are already compressed.
+/// Event streams are excluded as the encoders buffer output until the stream ends
+fn is_compressible(mime_type: &str) -> bool {
+    let mime_type = mime_type.split(';').next().unwrap_or("").trim();
+    if mime_type.eq_ignore_ascii_case("text/event-stream") {
+        return false;
+    }
+    mime_type.starts_with("text/")
+        || mime_type.ends_with("+json")
+        || mime_type.ends_with("+xml")
+        || COMPRESSIBLE_TYPES.contains(&mime_type)
+}
+
+/// Compresses text-like responses (API JSON, SVG, subtitles etc.) with brotli / gzip
+/// when the client sends `Accept-Encoding`
+pub struct ResponseCompression;
+
+#[async_trait]
+impl Fairing for ResponseCompression {
+    fn info(&self) -> Info {
+        Info {
+            name: "Response compression",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, response: &mut Response<'r>) {
+        let compressible = response
+            .content_type()
+            .is_some_and(|ct| is_compressible(&ct.to_string()));
+        if !compressible
+            || response.status() != Status::Ok
+            || response.headers().contains("Content-Encoding")
+        {
+            return;
+        }
+        response.adjoin_header(Header::new("Vary", "Accept-Encoding"));
+        // range requests refer to offsets in the uncompressed file
+        if req.headers().contains("Range") || response.headers().contains("Content-Range") {
+            return;
+        }
+        let encoding = match req
+            .headers()
+            .get("Accept-Encoding")
+            .find_map(Encoding::negotiate)
+        {
+            Some(e) => e,
+            None => return,
+        };
+        // streamed bodies have no size and are left as they are
+        match response.body_mut().size().await {
+            Some(s) if s >= MIN_SIZE => {}
+            _ => return,
+        }
+
+        let body = BufReader::new(response.body_mut().take());
+        match encoding {
+            Encoding::Brotli => response.set_streamed_body(BrotliEncoder::new(body)),
+            Encoding::Gzip => response.set_streamed_body(GzipEncoder::new(body)),
+        }
+        response.set_header(Header::new("Content-Encoding", encoding.name()));
+        response.remove_header("Content-Length");
+        // ranges of the compressed body can't be served
+        response.remove_header("Accept-Ranges");
+    }
+}
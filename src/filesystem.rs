@@ -1,59 +1,250 @@
+use std::collections::HashSet;
 use std::env::temp_dir;
+use std::ffi::CString;
+use std::fmt::{Display, Formatter};
 use std::fs;
 use std::io::SeekFrom;
 use std::path::{Path, PathBuf};
-use std::time::SystemTime;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
-use anyhow::Error;
+use anyhow::{bail, Error};
 use chrono::Utc;
-use log::info;
+use log::{info, warn};
 use serde::Serialize;
 use sha2::{Digest, Sha256};
 use tokio::fs::File;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 
-#[cfg(feature = "labels")]
-use crate::db::FileLabel;
+use crate::blocklist::Blocklist;
 use crate::db::FileUpload;
-#[cfg(feature = "labels")]
-use crate::processing::labeling::label_frame;
 #[cfg(feature = "media-compression")]
-use crate::processing::{compress_file, probe_file, FileProcessorResult};
+use crate::db::{FileVariant, VariantKind};
+#[cfg(feature = "media-compression")]
+use crate::processing::pool::ProcessingPool;
+#[cfg(feature = "media-compression")]
+use crate::processing::{compress_file, probe_file, FileProcessorResult, MediaInfo};
 use crate::settings::Settings;
+use crate::shutdown::Shutdown;
+use crate::svg::{is_svg_mime, sanitize_svg, sniff_svg, SNIFF_LEN};
 
 #[derive(Clone, Default, Serialize)]
 pub struct FileSystemResult {
     pub path: PathBuf,
     pub upload: FileUpload,
+    /// SHA-256 of the data as it was uploaded, before any transformations
+    #[serde(with = "hex")]
+    pub original_hash: Vec<u8>,
+}
+
+/// Reasons an upload is refused, as opposed to IO / processing failures
+#[derive(Debug)]
+pub enum UploadError {
+    /// The file hash is on a blocklist
+    Banned,
+    /// Storage volume is below the hard watermark
+    ServerFull,
+    /// The file type is not accepted
+    NotAllowed(String),
+    /// The file contents could not be parsed
+    Invalid(String),
+    /// Server is shutting down and not accepting new uploads
+    ShuttingDown,
+}
+
+impl Display for UploadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UploadError::Banned => write!(f, "File is banned"),
+            UploadError::ServerFull => write!(f, "Server is full"),
+            UploadError::NotAllowed(m) => write!(f, "{}", m),
+            UploadError::Invalid(m) => write!(f, "{}", m),
+            UploadError::ShuttingDown => write!(f, "Server is shutting down"),
+        }
+    }
 }
 
+impl std::error::Error for UploadError {}
+
+#[derive(Clone)]
 pub struct FileStore {
     settings: Settings,
+    blocklist: Option<Blocklist>,
+    shutdown: Shutdown,
+    /// Ids of files which are being moved back from cold storage
+    rehydrating: Arc<Mutex<HashSet<Vec<u8>>>>,
+    #[cfg(feature = "media-compression")]
+    pool: ProcessingPool,
 }
 
 impl FileStore {
     pub fn new(settings: Settings) -> Self {
-        Self { settings }
+        #[cfg(feature = "media-compression")]
+        let pool = {
+            let ps = settings.processing.as_ref();
+            ProcessingPool::new(
+                ps.and_then(|p| p.workers)
+                    .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get())),
+                ps.and_then(|p| p.queue_size).unwrap_or(100),
+                Duration::from_secs(ps.and_then(|p| p.timeout).unwrap_or(300)),
+            )
+        };
+        Self {
+            settings,
+            blocklist: None,
+            shutdown: Shutdown::new(),
+            rehydrating: Arc::new(Mutex::new(HashSet::new())),
+            #[cfg(feature = "media-compression")]
+            pool,
+        }
+    }
+
+    /// In-flight work tracking, uploads are rejected once the server is shutting down
+    pub fn shutdown(&self) -> &Shutdown {
+        &self.shutdown
+    }
+
+    /// Remove temporary files left by uploads, only safe when no uploads are running
+    pub fn clean_temp(&self) {
+        let dir = Self::temp_root();
+        if dir.exists() {
+            if let Err(e) = fs::remove_dir_all(&dir) {
+                warn!("Failed to remove temp files: {}", e);
+            }
+        }
+    }
+
+    /// Reject uploads and downloads of banned files
+    pub fn with_blocklist(mut self, blocklist: Blocklist) -> Self {
+        self.blocklist = Some(blocklist);
+        self
+    }
+
+    pub fn blocklist(&self) -> Option<&Blocklist> {
+        self.blocklist.as_ref()
     }
 
-    /// Get a file path by id
+    /// Check if a file hash is on the blocklist
+    pub fn is_banned(&self, id: &[u8]) -> bool {
+        self.blocklist.as_ref().is_some_and(|b| b.contains(id))
+    }
+
+    /// Worker pool for media processing jobs
+    #[cfg(feature = "media-compression")]
+    pub fn pool(&self) -> &ProcessingPool {
+        &self.pool
+    }
+
+    /// Get a file path by id, checking cold storage when the file is not in hot storage
     pub fn get(&self, id: &Vec<u8>) -> PathBuf {
-        self.map_path(id)
+        let path = self.map_path(id);
+        if !path.exists() {
+            if let Some(cold) = self.map_cold_path(id) {
+                if cold.exists() {
+                    return cold;
+                }
+            }
+        }
+        path
+    }
+
+    /// Free bytes on the storage volume
+    pub fn free_space(&self) -> Result<u64, Error> {
+        let path = CString::new(self.settings.storage_dir.as_bytes())?;
+        unsafe {
+            let mut stat: libc::statvfs = std::mem::zeroed();
+            if libc::statvfs(path.as_ptr(), &mut stat) != 0 {
+                return Err(Error::from(std::io::Error::last_os_error()));
+            }
+            Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+        }
+    }
+
+    /// Max number of bytes which can be accepted before reaching the hard watermark,
+    /// returns [None] when the free space is above the soft watermark
+    pub fn upload_headroom(&self) -> Result<Option<u64>, Error> {
+        let wm = match &self.settings.disk_watermark {
+            Some(w) => w,
+            None => return Ok(None),
+        };
+        let free = self.free_space()?;
+        if free > wm.soft_bytes {
+            return Ok(None);
+        }
+        warn!(
+            "Storage volume is low on space: free={:.3}GB",
+            free as f64 / 1024.0 / 1024.0 / 1024.0
+        );
+        Ok(Some(free.saturating_sub(wm.hard_bytes)))
+    }
+
+    /// Check there is enough free space to store a new file of `size` bytes
+    pub fn check_free_space(&self, size: u64) -> Result<(), Error> {
+        if let Some(headroom) = self.upload_headroom()? {
+            if headroom == 0 || size > headroom {
+                return Err(UploadError::ServerFull.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Move a file from hot storage to cold storage
+    pub async fn move_to_cold(&self, id: &Vec<u8>) -> Result<(), Error> {
+        let cold_path = match self.map_cold_path(id) {
+            Some(p) => p,
+            None => bail!("Cold storage is not configured"),
+        };
+        Self::move_file(&self.map_path(id), &cold_path).await
+    }
+
+    /// Move a file from cold storage back into hot storage, returns false when
+    /// the file is already being moved by another request
+    pub async fn rehydrate(&self, id: &Vec<u8>) -> Result<bool, Error> {
+        let cold_path = match self.map_cold_path(id) {
+            Some(p) => p,
+            None => bail!("Cold storage is not configured"),
+        };
+        if !self.rehydrating.lock().unwrap().insert(id.clone()) {
+            return Ok(false);
+        }
+        let res = Self::move_file(&cold_path, &self.map_path(id)).await;
+        self.rehydrating.lock().unwrap().remove(id);
+        res.map(|_| true)
     }
 
-    /// Store a new file
+    async fn move_file(src: &Path, dst: &Path) -> Result<(), Error> {
+        if !src.exists() {
+            bail!("File not found {}", src.to_str().unwrap());
+        }
+        tokio::fs::create_dir_all(dst.parent().unwrap()).await?;
+        // copy to a unique temp name first so readers never see a partial file
+        let tmp_dst = dst.with_extension(format!("{}.tmp", uuid::Uuid::new_v4()));
+        if let Err(e) = tokio::fs::copy(src, &tmp_dst).await {
+            let _ = tokio::fs::remove_file(&tmp_dst).await;
+            return Err(e.into());
+        }
+        tokio::fs::rename(&tmp_dst, dst).await?;
+        tokio::fs::remove_file(src).await?;
+        Ok(())
+    }
+
+    /// Store a new file, the file is stored as uploaded (besides SVG sanitizing),
+    /// compressed versions are created later as variants with [FileStore::transform]
     pub async fn put<TStream>(
         &self,
         stream: TStream,
         mime_type: &str,
-        compress: bool,
     ) -> Result<FileSystemResult, Error>
     where
         TStream: AsyncRead + Unpin,
     {
-        let result = self
-            .store_compress_file(stream, mime_type, compress)
-            .await?;
+        let _active = self.shutdown.begin().ok_or(UploadError::ShuttingDown)?;
+        let mut result = self.store_file(stream, mime_type).await?;
+        result.upload.original_hash = Some(result.original_hash.clone());
+        if self.is_banned(&result.upload.id) || self.is_banned(&result.original_hash) {
+            fs::remove_file(result.path)?;
+            return Err(UploadError::Banned.into());
+        }
         let dst_path = self.map_path(&result.upload.id);
         if dst_path.exists() {
             fs::remove_file(result.path)?;
@@ -75,17 +266,17 @@ impl FileStore {
         }
     }
 
-    async fn store_compress_file<TStream>(
+    async fn store_file<TStream>(
         &self,
         mut stream: TStream,
         mime_type: &str,
-        compress: bool,
     ) -> Result<FileSystemResult, Error>
     where
         TStream: AsyncRead + Unpin,
     {
         let random_id = uuid::Uuid::new_v4();
         let tmp_path = FileStore::map_temp(random_id);
+        fs::create_dir_all(Self::temp_root())?;
         let mut file = File::options()
             .create(true)
             .truncate(false)
@@ -93,104 +284,197 @@ impl FileStore {
             .read(true)
             .open(tmp_path.clone())
             .await?;
-        tokio::io::copy(&mut stream, &mut file).await?;
+        let original_hash = match self.copy_stream(&mut stream, &mut file).await {
+            Ok(h) => h,
+            Err(e) => {
+                drop(file);
+                let _ = fs::remove_file(&tmp_path);
+                return Err(e);
+            }
+        };
 
         info!("File saved to temp path: {}", tmp_path.to_str().unwrap());
 
+        if let Err(e) = self.sanitize_if_svg(mime_type, &mut file).await {
+            drop(file);
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+
         #[cfg(feature = "media-compression")]
-        if compress {
-            let start = SystemTime::now();
-            let proc_result = compress_file(tmp_path.clone(), mime_type)?;
-            if let FileProcessorResult::NewFile(new_temp) = proc_result {
-                let old_size = tmp_path.metadata()?.len();
-                let new_size = new_temp.result.metadata()?.len();
-                let time_compress = SystemTime::now().duration_since(start)?;
-                let start = SystemTime::now();
-
-                #[cfg(feature = "labels")]
-                let labels = if let Some(mp) = &self.settings.vit_model_path {
-                    label_frame(&new_temp.result, mp.clone())?
-                        .iter()
-                        .map(|l| FileLabel::new(l.clone(), "vit224".to_string()))
-                        .collect()
-                } else {
-                    vec![]
-                };
-
-                let time_labels = SystemTime::now().duration_since(start)?;
-
-                // delete old temp
-                fs::remove_file(tmp_path)?;
-                file = File::options()
-                    .create(true)
-                    .truncate(false)
-                    .write(true)
-                    .read(true)
-                    .open(new_temp.result.clone())
-                    .await?;
-                let n = file.metadata().await?.len();
-                let hash = FileStore::hash_file(&mut file).await?;
-
-                info!("Processed media: ratio={:.2}x, old_size={:.3}kb, new_size={:.3}kb, duration_compress={:.2}ms, duration_labels={:.2}ms",
-                    old_size as f32 / new_size as f32,
-                    old_size as f32 / 1024.0,
-                    new_size as f32 / 1024.0,
-                    time_compress.as_micros() as f64 / 1000.0,
-                    time_labels.as_micros() as f64 / 1000.0
-                );
-
-                return Ok(FileSystemResult {
-                    path: new_temp.result,
-                    upload: FileUpload {
-                        id: hash,
-                        name: "".to_string(),
-                        size: n,
-                        width: Some(new_temp.width as u32),
-                        height: Some(new_temp.height as u32),
-                        blur_hash: None,
-                        mime_type: new_temp.mime_type,
-                        #[cfg(feature = "labels")]
-                        labels,
-                        created: Utc::now(),
-                        ..Default::default()
-                    },
-                });
-            }
-        } else if let Ok(p) = probe_file(tmp_path.clone()) {
+        if let Ok(p) = self.probe(&tmp_path).await {
             let n = file.metadata().await?.len();
-            let hash = FileStore::hash_file(&mut file).await?;
+            let (hash, blake3) = self.hash_file(&mut file).await?;
             return Ok(FileSystemResult {
                 path: tmp_path,
                 upload: FileUpload {
                     id: hash,
+                    blake3,
                     name: "".to_string(),
                     size: n,
                     created: Utc::now(),
                     mime_type: mime_type.to_string(),
-                    width: p.map(|v| v.0 as u32),
-                    height: p.map(|v| v.1 as u32),
+                    width: p.width.map(|v| v as u32),
+                    height: p.height.map(|v| v as u32),
+                    duration: media_duration(mime_type, &p),
+                    blur_hash: p.blur_hash,
                     ..Default::default()
                 },
+                original_hash,
             });
         }
 
         let n = file.metadata().await?.len();
-        let hash = FileStore::hash_file(&mut file).await?;
+        let (hash, blake3) = self.hash_file(&mut file).await?;
         Ok(FileSystemResult {
             path: tmp_path,
             upload: FileUpload {
                 id: hash,
+                blake3,
                 name: "".to_string(),
                 size: n,
                 created: Utc::now(),
                 mime_type: mime_type.to_string(),
                 ..Default::default()
             },
+            original_hash,
         })
     }
 
-    async fn hash_file(file: &mut File) -> Result<Vec<u8>, Error> {
+    /// Compress an image or transcode a video according to the `[compression]` settings,
+    /// the result is stored as a variant of `upload`, [None] when the file is not compressed
+    #[cfg(feature = "media-compression")]
+    pub async fn transform(&self, upload: &FileUpload) -> Result<Option<FileVariant>, Error> {
+        let settings = self.settings.compression.clone().unwrap_or_default();
+        if !settings.is_enabled(&upload.mime_type) {
+            return Ok(None);
+        }
+        let start = SystemTime::now();
+        let (path, out, mime_type) = (
+            self.get(&upload.id),
+            self.temp_path()?,
+            upload.mime_type.clone(),
+        );
+        let new_file = match self
+            .pool
+            .run(move |cancel| compress_file(path, &out, &mime_type, &settings, cancel))
+            .await?
+        {
+            FileProcessorResult::NewFile(n) => n,
+            FileProcessorResult::Skip => return Ok(None),
+        };
+        let time_compress = SystemTime::now().duration_since(start)?;
+        let (id, size) = self.store_variant(&new_file.result).await?;
+        info!(
+            "Processed media: ratio={:.2}x, old_size={:.3}kb, new_size={:.3}kb, duration_compress={:.2}ms",
+            upload.size as f32 / size as f32,
+            upload.size as f32 / 1024.0,
+            size as f32 / 1024.0,
+            time_compress.as_micros() as f64 / 1000.0
+        );
+        Ok(Some(FileVariant {
+            file: upload.id.clone(),
+            id,
+            kind: if new_file.mime_type.starts_with("video/") {
+                VariantKind::Transcode
+            } else {
+                VariantKind::Conversion
+            },
+            size,
+            mime_type: new_file.mime_type,
+            width: Some(new_file.width as u32),
+            height: Some(new_file.height as u32),
+            codec: Some(new_file.codec),
+            created: Utc::now(),
+        }))
+    }
+
+    /// Read dimensions, duration and blurhash of a media file on the processing pool
+    #[cfg(feature = "media-compression")]
+    async fn probe(&self, path: &Path) -> Result<MediaInfo, Error> {
+        let path = path.to_path_buf();
+        self.pool.run(move |_| probe_file(path)).await
+    }
+
+    /// Copy an upload stream into a file, returns the SHA-256 hash of the stream
+    async fn copy_stream<TStream>(
+        &self,
+        stream: &mut TStream,
+        file: &mut File,
+    ) -> Result<Vec<u8>, Error>
+    where
+        TStream: AsyncRead + Unpin,
+    {
+        let buf_size = self
+            .settings
+            .request_limits
+            .as_ref()
+            .and_then(|l| l.upload_buffer_size)
+            .unwrap_or(64 * 1024);
+
+        let mut buf = vec![0; buf_size];
+        let mut hasher = Sha256::new();
+        loop {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            file.write_all(&buf[..n]).await?;
+            hasher.update(&buf[..n]);
+        }
+        file.flush().await?;
+        Ok(hasher.finalize().to_vec())
+    }
+
+    /// Sanitize files declared or detected as SVG, the declared type can't be trusted
+    async fn sanitize_if_svg(&self, mime_type: &str, file: &mut File) -> Result<(), Error> {
+        if is_svg_mime(mime_type) || self.sniff_svg(file).await? {
+            self.sanitize_svg(file).await
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Check the start of a file for an SVG document
+    async fn sniff_svg(&self, file: &mut File) -> Result<bool, Error> {
+        let mut head = Vec::with_capacity(SNIFF_LEN);
+        file.seek(SeekFrom::Start(0)).await?;
+        (&mut *file)
+            .take(SNIFF_LEN as u64 + 1)
+            .read_to_end(&mut head)
+            .await?;
+        let truncated = head.len() > SNIFF_LEN;
+        head.truncate(SNIFF_LEN);
+        Ok(sniff_svg(&head, truncated))
+    }
+
+    /// Replace the contents of an SVG file with its sanitized version
+    async fn sanitize_svg(&self, file: &mut File) -> Result<(), Error> {
+        if !self.settings.allow_svg.unwrap_or(true) {
+            return Err(UploadError::NotAllowed("SVG uploads are not allowed".to_string()).into());
+        }
+        let mut data = Vec::new();
+        file.seek(SeekFrom::Start(0)).await?;
+        file.read_to_end(&mut data).await?;
+        let clean = match sanitize_svg(&data) {
+            Ok(c) => c,
+            Err(e) => return Err(UploadError::Invalid(format!("Invalid SVG: {}", e)).into()),
+        };
+        file.set_len(0).await?;
+        file.seek(SeekFrom::Start(0)).await?;
+        file.write_all(&clean).await?;
+        file.flush().await?;
+        Ok(())
+    }
+
+    /// SHA-256 of a file, and the BLAKE3 digest when enabled in the settings
+    pub async fn hash_file(&self, file: &mut File) -> Result<(Vec<u8>, Option<Vec<u8>>), Error> {
         let mut hasher = Sha256::new();
+        let mut blake3 = self
+            .settings
+            .blake3
+            .unwrap_or(false)
+            .then(blake3::Hasher::new);
         file.seek(SeekFrom::Start(0)).await?;
         let mut buf = [0; 4096];
         loop {
@@ -199,20 +483,97 @@ impl FileStore {
                 break;
             }
             hasher.update(&buf[..n]);
+            if let Some(b) = blake3.as_mut() {
+                b.update(&buf[..n]);
+            }
         }
         let res = hasher.finalize();
-        Ok(res.to_vec())
+        Ok((
+            res.to_vec(),
+            blake3.map(|b| b.finalize().as_bytes().to_vec()),
+        ))
+    }
+
+    /// Directory for files which are still being uploaded / processed
+    fn temp_root() -> PathBuf {
+        temp_dir().join("route96")
     }
 
     fn map_temp(id: uuid::Uuid) -> PathBuf {
-        temp_dir().join(id.to_string())
+        Self::temp_root().join(id.to_string())
+    }
+
+    /// Delete a file, its preview image and variant blobs which are no longer used,
+    /// see [crate::db::Database::delete_file]
+    pub fn delete(&self, id: &Vec<u8>, variants: &[Vec<u8>]) -> Result<(), Error> {
+        let thumb = self.map_thumb_path(id);
+        if thumb.exists() {
+            fs::remove_file(thumb)?;
+        }
+        for v in variants {
+            let path = self.map_variant_path(v);
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        fs::remove_file(self.get(id))?;
+        Ok(())
+    }
+
+    /// Path of the preview image for a file created before variants were stored separately,
+    /// previews are always kept in hot storage
+    pub fn map_thumb_path(&self, id: &Vec<u8>) -> PathBuf {
+        self.map_path(id).with_extension("thumb")
+    }
+
+    /// Path of a derived blob (see [crate::db::FileVariant]), variants are always kept in hot storage
+    pub fn map_variant_path(&self, id: &Vec<u8>) -> PathBuf {
+        Self::map_path_in(Path::new(&self.settings.storage_dir).join("variants"), id)
+    }
+
+    /// New path in the temp directory, for uploads and variants which are being processed
+    pub fn temp_path(&self) -> Result<PathBuf, Error> {
+        fs::create_dir_all(Self::temp_root())?;
+        Ok(Self::map_temp(uuid::Uuid::new_v4()))
+    }
+
+    /// Move a derived blob into the variant storage, returns its hash and size
+    pub async fn store_variant(&self, path: &Path) -> Result<(Vec<u8>, u64), Error> {
+        let mut file = File::open(path).await?;
+        let size = file.metadata().await?.len();
+        let (id, _) = self.hash_file(&mut file).await?;
+        let dst = self.map_variant_path(&id);
+        if !dst.exists() {
+            fs::create_dir_all(dst.parent().unwrap())?;
+            fs::copy(path, &dst)?;
+        }
+        fs::remove_file(path)?;
+        Ok((id, size))
     }
 
     pub fn map_path(&self, id: &Vec<u8>) -> PathBuf {
+        Self::map_path_in(&self.settings.storage_dir, id)
+    }
+
+    pub fn map_cold_path(&self, id: &Vec<u8>) -> Option<PathBuf> {
+        self.settings
+            .cold_storage
+            .as_ref()
+            .map(|c| Self::map_path_in(&c.storage_dir, id))
+    }
+
+    fn map_path_in(dir: impl AsRef<Path>, id: &Vec<u8>) -> PathBuf {
         let id = hex::encode(id);
-        Path::new(&self.settings.storage_dir)
-            .join(&id[0..2])
-            .join(&id[2..4])
-            .join(id)
+        dir.as_ref().join(&id[0..2]).join(&id[2..4]).join(id)
+    }
+}
+
+/// Duration is only reported for audio / video, images can have a duration of a single frame
+#[cfg(feature = "media-compression")]
+fn media_duration(mime_type: &str, info: &MediaInfo) -> Option<f32> {
+    if mime_type.starts_with("video/") || mime_type.starts_with("audio/") {
+        info.duration
+    } else {
+        None
     }
 }
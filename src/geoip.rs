@@ -0,0 +1,26 @@
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Error;
+use maxminddb::{geoip2, Reader};
+
+/// Country lookup using a MaxMind GeoLite2 database
+#[derive(Clone)]
+pub struct GeoIp {
+    reader: Arc<Reader<Vec<u8>>>,
+}
+
+impl GeoIp {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        Ok(Self {
+            reader: Arc::new(Reader::open_readfile(path)?),
+        })
+    }
+
+    /// ISO 3166-1 country code of an IP address
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let c: geoip2::Country = self.reader.lookup(ip).ok()?;
+        c.country?.iso_code.map(|s| s.to_string())
+    }
+}
@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::{async_trait, Request};
+use sha2::{Digest, Sha256};
+
+use crate::db::{FileUpload, FileVariant};
+
+/// Longest accepted `Idempotency-Key` header
+const MAX_KEY_LEN: usize = 255;
+
+/// Max number of cached keys, the oldest results are evicted first
+const MAX_ENTRIES: usize = 10_000;
+
+/// Results of recent uploads by idempotency key, so that retried uploads
+/// return the original response instead of storing the file again
+#[derive(Clone)]
+pub struct IdempotencyCache {
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+    ttl: Duration,
+}
+
+/// Cached key with the [request_hash] of the first request using it
+struct Entry {
+    request: [u8; 32],
+    state: EntryState,
+}
+
+enum EntryState {
+    InProgress,
+    Done(Instant, Box<UploadResult>),
+}
+
+/// Stored upload with the compressed variant returned to the uploader, if any
+#[derive(Clone)]
+pub struct UploadResult {
+    pub upload: FileUpload,
+    pub variant: Option<FileVariant>,
+}
+
+pub enum IdempotencyState {
+    /// First request with this key, store the result with [IdempotencyGuard::complete]
+    New(IdempotencyGuard),
+    /// A request with this key is still running
+    InProgress,
+    /// Upload returned by the first request with this key
+    Done(Box<UploadResult>),
+    /// The key was used for a different request
+    Mismatch,
+}
+
+/// Hash of the request parameters a key is bound to, joined with a separator
+pub fn request_hash(parts: &[&str]) -> [u8; 32] {
+    let mut hash = Sha256::new();
+    for p in parts {
+        hash.update(p.as_bytes());
+        hash.update([0]);
+    }
+    hash.finalize().into()
+}
+
+/// Marks a key as in progress until the upload completes, the key is released
+/// when dropped without completing so that failed uploads can be retried
+pub struct IdempotencyGuard {
+    cache: IdempotencyCache,
+    key: String,
+    done: bool,
+}
+
+impl IdempotencyGuard {
+    pub fn complete(mut self, upload: &FileUpload, variant: Option<&FileVariant>) {
+        if let Some(e) = self.cache.entries.lock().unwrap().get_mut(&self.key) {
+            e.state = EntryState::Done(
+                Instant::now(),
+                Box::new(UploadResult {
+                    upload: upload.clone(),
+                    variant: variant.cloned(),
+                }),
+            );
+        }
+        self.done = true;
+    }
+}
+
+impl Drop for IdempotencyGuard {
+    fn drop(&mut self) {
+        if !self.done {
+            self.cache.entries.lock().unwrap().remove(&self.key);
+        }
+    }
+}
+
+impl IdempotencyCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(HashMap::new())),
+            ttl,
+        }
+    }
+
+    /// Start a request with a key, keys are scoped to the uploader and bound to
+    /// the [request_hash] of the first request
+    pub fn begin(&self, scope: &str, key: &str, request: [u8; 32]) -> IdempotencyState {
+        let key = format!("{}:{}", scope, key);
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, e| match &e.state {
+            EntryState::Done(t, _) => t.elapsed() < self.ttl,
+            EntryState::InProgress => true,
+        });
+        match entries.get(&key) {
+            Some(e) if e.request != request => IdempotencyState::Mismatch,
+            Some(Entry {
+                state: EntryState::Done(_, upload),
+                ..
+            }) => IdempotencyState::Done(upload.clone()),
+            Some(_) => IdempotencyState::InProgress,
+            None => {
+                if entries.len() >= MAX_ENTRIES {
+                    // requests in progress are never evicted, there is one per running upload
+                    let oldest = entries
+                        .iter()
+                        .filter_map(|(k, e)| match &e.state {
+                            EntryState::Done(t, _) => Some((k, *t)),
+                            EntryState::InProgress => None,
+                        })
+                        .min_by_key(|(_, t)| *t)
+                        .map(|(k, _)| k.clone());
+                    if let Some(k) = oldest {
+                        entries.remove(&k);
+                    }
+                }
+                entries.insert(
+                    key.clone(),
+                    Entry {
+                        request,
+                        state: EntryState::InProgress,
+                    },
+                );
+                IdempotencyState::New(IdempotencyGuard {
+                    cache: self.clone(),
+                    key,
+                    done: false,
+                })
+            }
+        }
+    }
+}
+
+/// `Idempotency-Key` request header, see [IdempotencyCache]
+pub struct Idempotency {
+    cache: IdempotencyCache,
+    pub key: Option<String>,
+}
+
+impl Idempotency {
+    /// Start a request using the header, or `fallback` (eg. the auth event id) when it was not sent,
+    /// [None] when there is no key. `request` is the [request_hash] of the upload parameters
+    pub fn begin(
+        &self,
+        scope: &str,
+        fallback: Option<String>,
+        request: [u8; 32],
+    ) -> Option<IdempotencyState> {
+        self.key
+            .clone()
+            .or(fallback)
+            .map(|k| self.cache.begin(scope, &k, request))
+    }
+}
+
+#[async_trait]
+impl<'r> FromRequest<'r> for Idempotency {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let cache = match request.rocket().state::<IdempotencyCache>() {
+            Some(c) => c.clone(),
+            None => return Outcome::Error((Status::new(500), "Idempotency cache not available")),
+        };
+        let key = match request
+            .headers()
+            .get_one("idempotency-key")
+            .map(|k| k.trim())
+        {
+            Some(k) if k.is_empty() || k.len() > MAX_KEY_LEN => {
+                return Outcome::Error((Status::new(400), "Invalid Idempotency-Key"))
+            }
+            k => k.map(|k| k.to_string()),
+        };
+        Outcome::Success(Idempotency { cache, key })
+    }
+}
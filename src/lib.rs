@@ -1,13 +1,31 @@
 #[cfg(feature = "analytics")]
 pub mod analytics;
+pub mod api_version;
 pub mod auth;
+pub mod backup;
+pub mod blocklist;
+pub mod cleanup;
 pub mod cors;
 pub mod db;
+pub mod encoding;
 pub mod filesystem;
+pub mod geoip;
+pub mod idempotency;
+pub mod limits;
+pub mod listener;
+pub mod notify;
 #[cfg(feature = "media-compression")]
 pub mod processing;
+pub mod progress;
 pub mod routes;
 pub mod settings;
+pub mod shutdown;
+pub mod stats;
+pub mod svg;
+pub mod throttle;
+pub mod tiering;
+#[cfg(feature = "tls")]
+pub mod tls;
 #[cfg(any(feature = "void-cat-redirects", feature = "bin-void-cat-migrate"))]
 pub mod void_db;
 pub mod webhook;
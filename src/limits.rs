@@ -0,0 +1,179 @@
+use std::future::Future;
+use std::io;
+use std::io::Cursor;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use rocket::data::ByteUnit;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::{Method, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::{async_trait, Data, Request, Response};
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+use crate::settings::Settings;
+
+/// Seconds without receiving data after which an upload is aborted, unless configured
+const DEFAULT_IDLE_TIMEOUT: u64 = 60;
+
+/// Path which no route handles, requests with too large headers are rewritten to it
+const HEADER_TOO_LARGE_PATH: &str = "/.well-known/route96/header-too-large";
+
+/// Timeouts and size limit for reading the body of a request, from
+/// [crate::settings::RequestLimits] and the overrides of the route handling the request
+#[derive(Debug, Clone, Copy)]
+pub struct StreamLimits {
+    pub idle_timeout: Duration,
+    pub read_timeout: Option<Duration>,
+    pub max_body_bytes: Option<u64>,
+}
+
+impl StreamLimits {
+    pub fn for_request(request: &Request<'_>) -> Self {
+        let limits = request
+            .rocket()
+            .state::<Settings>()
+            .and_then(|s| s.request_limits.as_ref());
+        let route = request.route().and_then(|r| {
+            limits?
+                .routes
+                .as_ref()?
+                .get(r.uri.unmounted_origin.path().as_str())
+        });
+        Self {
+            idle_timeout: Duration::from_secs(
+                route
+                    .and_then(|r| r.upload_idle_timeout)
+                    .or(limits.and_then(|l| l.upload_idle_timeout))
+                    .unwrap_or(DEFAULT_IDLE_TIMEOUT),
+            ),
+            read_timeout: route
+                .and_then(|r| r.read_timeout)
+                .or(limits.and_then(|l| l.read_timeout))
+                .map(Duration::from_secs),
+            max_body_bytes: route.and_then(|r| r.max_body_bytes),
+        }
+    }
+
+    /// Lower `limit` to the max body size of the route
+    pub fn limit(&self, limit: ByteUnit) -> ByteUnit {
+        match self.max_body_bytes {
+            Some(m) => limit.min(ByteUnit::from(m)),
+            None => limit,
+        }
+    }
+
+    /// Read `inner` with the timeouts of these limits
+    pub fn reader<R>(&self, inner: R) -> TimeoutReader<R> {
+        TimeoutReader {
+            inner,
+            idle_timeout: self.idle_timeout,
+            idle: Box::pin(tokio::time::sleep(self.idle_timeout)),
+            deadline: self.read_timeout.map(|t| Box::pin(tokio::time::sleep(t))),
+        }
+    }
+}
+
+#[async_trait]
+impl<'r> FromRequest<'r> for StreamLimits {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(StreamLimits::for_request(request))
+    }
+}
+
+/// Reader which fails when no data is received within the idle timeout,
+/// or when the whole stream takes longer than the read timeout
+pub struct TimeoutReader<R> {
+    inner: R,
+    idle_timeout: Duration,
+    idle: Pin<Box<Sleep>>,
+    deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for TimeoutReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(d) = this.deadline.as_mut() {
+            if d.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Upload timed out, read timeout reached",
+                )));
+            }
+        }
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(r) => {
+                this.idle.as_mut().reset(Instant::now() + this.idle_timeout);
+                Poll::Ready(r)
+            }
+            Poll::Pending => {
+                ready!(this.idle.as_mut().poll(cx));
+                Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "Upload timed out, no data received for {}s",
+                        this.idle_timeout.as_secs()
+                    ),
+                )))
+            }
+        }
+    }
+}
+
+/// Rejects requests with more than `max` bytes of headers with `431 Request Header Fields Too Large`.
+///
+/// Fairings can not respond to requests, so these requests are routed to a path without
+/// a route and the response is replaced.
+pub struct HeaderLimit {
+    max: usize,
+}
+
+/// Marks a request which exceeded the header limit
+struct HeaderTooLarge(bool);
+
+impl HeaderLimit {
+    pub fn new(max: usize) -> Self {
+        Self { max }
+    }
+}
+
+#[async_trait]
+impl Fairing for HeaderLimit {
+    fn info(&self) -> Info {
+        Info {
+            name: "Header limit",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _data: &mut Data<'_>) {
+        let size = req.uri().to_string().len()
+            + req
+                .headers()
+                .iter()
+                .map(|h| h.name().len() + h.value().len())
+                .sum::<usize>();
+        if size > self.max {
+            req.local_cache(|| HeaderTooLarge(true));
+            req.set_method(Method::Get);
+            req.set_uri(Origin::parse(HEADER_TOO_LARGE_PATH).unwrap());
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        if req.local_cache(|| HeaderTooLarge(false)).0 {
+            res.set_status(Status::new(431));
+            res.remove_header("Content-Type");
+            res.set_sized_body(0, Cursor::new(""));
+        }
+    }
+}
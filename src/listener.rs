@@ -0,0 +1,86 @@
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use anyhow::Error;
+use log::{error, info, warn};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{async_trait, Orbit, Rocket};
+use tokio::io::copy_bidirectional;
+use tokio::net::{TcpStream, UnixListener};
+
+/// Serves a Rocket instance on a unix socket by forwarding connections to its TCP listener.
+///
+/// Rocket 0.5 can only bind TCP sockets, the instance should listen on a loopback address.
+/// Any local user can connect to that port, so the permissions of the socket file do not
+/// restrict access to the server.
+/// Client IPs are taken from the reverse proxy headers (`X-Real-IP`).
+pub struct UnixSocketProxy {
+    path: PathBuf,
+}
+
+impl UnixSocketProxy {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    fn bind(&self) -> Result<UnixListener, Error> {
+        // socket file left from a previous run
+        if self.path.exists() {
+            fs::remove_file(&self.path)?;
+        }
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        Ok(UnixListener::bind(&self.path)?)
+    }
+}
+
+async fn forward(listener: UnixListener, target: SocketAddr, path: &Path) {
+    loop {
+        let (mut client, _) = match listener.accept().await {
+            Ok(c) => c,
+            Err(e) => {
+                error!("Failed to accept connection on {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        tokio::spawn(async move {
+            match TcpStream::connect(target).await {
+                Ok(mut upstream) => {
+                    let _ = copy_bidirectional(&mut client, &mut upstream).await;
+                }
+                Err(e) => warn!("Failed to connect to {}: {}", target, e),
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl Fairing for UnixSocketProxy {
+    fn info(&self) -> Info {
+        Info {
+            name: "Unix socket",
+            kind: Kind::Liftoff | Kind::Shutdown,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let target = SocketAddr::new(rocket.config().address, rocket.config().port);
+        match self.bind() {
+            Ok(listener) => {
+                info!("Listening on unix:{}", self.path.display());
+                let path = self.path.clone();
+                tokio::spawn(async move { forward(listener, target, &path).await });
+            }
+            Err(e) => {
+                error!("Failed to listen on unix:{}: {}", self.path.display(), e);
+                rocket.shutdown().notify();
+            }
+        }
+    }
+
+    async fn on_shutdown(&self, _rocket: &Rocket<Orbit>) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,46 @@
+use anyhow::Error;
+use nostr_sdk::nips::nip04;
+use nostr_sdk::{Client, EventBuilder, Keys, Kind, PublicKey, Tag};
+
+use crate::settings::NotificationSettings;
+
+/// Sends direct messages to users from the server's nostr key
+#[derive(Clone)]
+pub struct Notifier {
+    keys: Keys,
+    client: Client,
+    nip17: bool,
+}
+
+impl Notifier {
+    pub async fn new(settings: &NotificationSettings) -> Result<Self, Error> {
+        let keys = Keys::parse(&settings.nostr_key)?;
+        let client = Client::new(keys.clone());
+        for r in &settings.relays {
+            client.add_relay(r).await?;
+        }
+        client.connect().await;
+        Ok(Self {
+            keys,
+            client,
+            nip17: settings.nip17.unwrap_or(false),
+        })
+    }
+
+    /// Send an encrypted direct message (NIP-04, or NIP-17 when enabled)
+    pub async fn send_dm(&self, to: &PublicKey, message: &str) -> Result<(), Error> {
+        if self.nip17 {
+            self.client.send_private_msg(*to, message, []).await?;
+        } else {
+            let content = nip04::encrypt(self.keys.secret_key(), to, message)?;
+            let ev = EventBuilder::new(
+                Kind::EncryptedDirectMessage,
+                content,
+                [Tag::public_key(*to)],
+            )
+            .to_event(&self.keys)?;
+            self.client.send_event(ev).await?;
+        }
+        Ok(())
+    }
+}
@@ -1,40 +1,78 @@
-use std::path::PathBuf;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
+use crate::processing::pool::CancelToken;
 use crate::processing::probe::FFProbe;
+use crate::settings::{CompressionSettings, ImageFormat, VideoCodec};
 use anyhow::{bail, Error, Result};
-use ffmpeg_rs_raw::ffmpeg_sys_the_third::AVPixelFormat::AV_PIX_FMT_YUV420P;
-use ffmpeg_rs_raw::{Encoder, StreamType, Transcoder};
+use ffmpeg_rs_raw::ffmpeg_sys_the_third::AVCodecID::{
+    AV_CODEC_ID_AV1, AV_CODEC_ID_H264, AV_CODEC_ID_WEBP,
+};
+use ffmpeg_rs_raw::ffmpeg_sys_the_third::AVPixelFormat::{AV_PIX_FMT_RGBA, AV_PIX_FMT_YUV420P};
+use ffmpeg_rs_raw::ffmpeg_sys_the_third::{
+    av_frame_free, av_opt_set, av_packet_free, AVCodecContext, AVFrame,
+};
+use ffmpeg_rs_raw::{Decoder, Demuxer, Encoder, Scaler, StreamType, Transcoder};
+use log::warn;
 
 #[cfg(feature = "labels")]
 pub mod labeling;
+pub mod pool;
 mod probe;
+pub mod queue;
 
-pub struct WebpProcessor;
+/// Image compressor, converts images to WebP / AVIF
+pub struct ImageProcessor;
 
-impl Default for WebpProcessor {
+impl Default for ImageProcessor {
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl WebpProcessor {
+impl ImageProcessor {
     pub fn new() -> Self {
         Self
     }
 
-    pub fn process_file(&mut self, input: PathBuf, mime_type: &str) -> Result<FileProcessorResult> {
-        use ffmpeg_rs_raw::ffmpeg_sys_the_third::AVCodecID::AV_CODEC_ID_WEBP;
-
+    /// Compress an image trying each of the configured output formats in order,
+    /// the result is written to `out_file` with the extension of the format
+    pub fn process_file(
+        &mut self,
+        input: PathBuf,
+        out_file: &Path,
+        mime_type: &str,
+        settings: &CompressionSettings,
+    ) -> Result<FileProcessorResult> {
         if !mime_type.starts_with("image/") {
             bail!("MIME type not supported");
         }
 
-        if mime_type == "image/webp" {
-            return Ok(FileProcessorResult::Skip);
+        let mut last_error = Error::msg("No image formats configured");
+        for format in settings.image_formats() {
+            match self.encode(&input, out_file, mime_type, format, settings) {
+                Ok(r) => return Ok(r),
+                Err(e) => {
+                    warn!("Failed to encode {}: {}", format.extension(), e);
+                    last_error = e;
+                }
+            }
         }
+        Err(last_error)
+    }
 
-        let mut out_path = input.clone();
-        out_path.set_extension("compressed.webp");
+    fn encode(
+        &mut self,
+        input: &Path,
+        out_file: &Path,
+        mime_type: &str,
+        format: ImageFormat,
+        settings: &CompressionSettings,
+    ) -> Result<FileProcessorResult> {
+        let out_path = out_file.with_extension(format.extension());
         unsafe {
             let mut trans = Transcoder::new(input.to_str().unwrap(), out_path.to_str().unwrap())?;
 
@@ -45,25 +83,160 @@ impl WebpProcessor {
                 .find(|c| c.stream_type == StreamType::Video)
                 .ok_or(Error::msg("No image found, cant compress"))?;
 
-            let enc = Encoder::new(AV_CODEC_ID_WEBP)?
-                .with_height(image_stream.height as i32)
-                .with_width(image_stream.width as i32)
+            let (width, height) = fit_dimensions(
+                image_stream.width,
+                image_stream.height,
+                settings.max_image_width,
+                settings.max_image_height,
+            );
+            if mime_type == format.mime_type() && width == image_stream.width {
+                return Ok(FileProcessorResult::Skip);
+            }
+
+            let quality = settings.image_quality(format);
+            let (codec, key, value) = match format {
+                ImageFormat::Webp => (AV_CODEC_ID_WEBP, "quality", quality.to_string()),
+                // map quality to the AV1 crf scale 63-0
+                ImageFormat::Avif => (
+                    AV_CODEC_ID_AV1,
+                    "crf",
+                    ((100.0 - quality) * 0.63).round().to_string(),
+                ),
+            };
+            let enc = Encoder::new(codec)?
+                .with_height(height as i32)
+                .with_width(width as i32)
                 .with_pix_fmt(AV_PIX_FMT_YUV420P)
+                .with_options(|ctx| set_option(ctx, key, &value))
                 .open(None)?;
 
             trans.transcode_stream(image_stream, enc)?;
-            trans.run()?;
+            if let Err(e) = trans.run() {
+                let _ = std::fs::remove_file(&out_path);
+                return Err(e);
+            }
+
+            Ok(FileProcessorResult::NewFile(NewFileProcessorResult {
+                result: out_path,
+                mime_type: format.mime_type().to_string(),
+                codec: format.extension().to_string(),
+                width,
+                height,
+            }))
+        }
+    }
+}
+
+/// Video transcoder to MP4 (H.264 / AV1), audio streams are copied
+pub struct VideoProcessor;
+
+impl Default for VideoProcessor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VideoProcessor {
+    pub fn new() -> Self {
+        Self
+    }
+
+    /// Transcode a video, the result is written to `out_file` with the mp4 extension
+    pub fn process_file(
+        &mut self,
+        input: PathBuf,
+        out_file: &Path,
+        settings: &CompressionSettings,
+    ) -> Result<FileProcessorResult> {
+        let out_path = out_file.with_extension("mp4");
+        let codec = settings.video_codec.unwrap_or_default();
+        unsafe {
+            let mut trans = Transcoder::new(input.to_str().unwrap(), out_path.to_str().unwrap())?;
+
+            let probe = trans.prepare()?;
+            let video_stream = probe
+                .best_video()
+                .ok_or(Error::msg("No video found, cant compress"))?;
+
+            let (width, height) = fit_dimensions(
+                video_stream.width,
+                video_stream.height,
+                settings.max_video_width,
+                settings.max_video_height,
+            );
+            let crf = settings.video_crf.unwrap_or(23).to_string();
+            let preset = settings
+                .video_preset
+                .clone()
+                .unwrap_or("medium".to_string());
+            let enc = Encoder::new(match codec {
+                VideoCodec::H264 => AV_CODEC_ID_H264,
+                VideoCodec::Av1 => AV_CODEC_ID_AV1,
+            })?
+            .with_width(width as i32)
+            .with_height(height as i32)
+            .with_pix_fmt(AV_PIX_FMT_YUV420P)
+            .with_framerate(video_stream.fps)
+            .with_options(|ctx| {
+                set_option(ctx, "crf", &crf);
+                if codec == VideoCodec::H264 {
+                    set_option(ctx, "preset", &preset);
+                }
+            })
+            .open(None)?;
+
+            trans.transcode_stream(video_stream, enc)?;
+            for audio in probe
+                .streams
+                .iter()
+                .filter(|s| s.stream_type == StreamType::Audio)
+            {
+                trans.copy_stream(audio)?;
+            }
+            if let Err(e) = trans.run() {
+                let _ = std::fs::remove_file(&out_path);
+                return Err(e);
+            }
 
             Ok(FileProcessorResult::NewFile(NewFileProcessorResult {
                 result: out_path,
-                mime_type: "image/webp".to_string(),
-                width: image_stream.width,
-                height: image_stream.height,
+                mime_type: "video/mp4".to_string(),
+                codec: match codec {
+                    VideoCodec::H264 => "h264",
+                    VideoCodec::Av1 => "av1",
+                }
+                .to_string(),
+                width,
+                height,
             }))
         }
     }
 }
 
+/// Scale dimensions down to fit within the max width / height keeping the aspect ratio,
+/// dimensions are rounded to even numbers as required by yuv420p
+fn fit_dimensions(
+    width: usize,
+    height: usize,
+    max_width: Option<u32>,
+    max_height: Option<u32>,
+) -> (usize, usize) {
+    let scale_w = max_width.map_or(1.0, |m| m as f64 / width as f64);
+    let scale_h = max_height.map_or(1.0, |m| m as f64 / height as f64);
+    let scale = scale_w.min(scale_h);
+    if scale >= 1.0 || width == 0 || height == 0 {
+        return (width, height);
+    }
+    let even = |v: f64| ((v as usize) & !1).max(2);
+    (even(width as f64 * scale), even(height as f64 * scale))
+}
+
+/// Set a private option on an encoder (crf, preset, quality etc.)
+unsafe fn set_option(ctx: *mut AVCodecContext, key: &str, value: &str) {
+    let (key, value) = (CString::new(key).unwrap(), CString::new(value).unwrap());
+    av_opt_set((*ctx).priv_data, key.as_ptr(), value.as_ptr(), 0);
+}
+
 pub struct ProbeResult {
     pub streams: Vec<ProbeStream>,
 }
@@ -88,25 +261,259 @@ pub enum FileProcessorResult {
 pub struct NewFileProcessorResult {
     pub result: PathBuf,
     pub mime_type: String,
+    /// Codec of the encoded image / video stream
+    pub codec: String,
     pub width: usize,
     pub height: usize,
 }
 
-pub fn compress_file(in_file: PathBuf, mime_type: &str) -> Result<FileProcessorResult, Error> {
-    let proc = if mime_type.starts_with("image/") {
-        Some(WebpProcessor::new())
+/// Compress an image or transcode a video into `out_file` (extension is set by the output format),
+/// the output is removed when the job timed out while encoding
+pub fn compress_file(
+    in_file: PathBuf,
+    out_file: &Path,
+    mime_type: &str,
+    settings: &CompressionSettings,
+    cancel: &CancelToken,
+) -> Result<FileProcessorResult, Error> {
+    if !settings.is_enabled(mime_type) {
+        return Ok(FileProcessorResult::Skip);
+    }
+    let result = if mime_type.starts_with("image/") {
+        ImageProcessor::new().process_file(in_file, out_file, mime_type, settings)
+    } else if mime_type.starts_with("video/") {
+        VideoProcessor::new().process_file(in_file, out_file, settings)
     } else {
-        None
+        return Ok(FileProcessorResult::Skip);
     };
-    if let Some(mut proc) = proc {
-        proc.process_file(in_file, mime_type)
-    } else {
-        Ok(FileProcessorResult::Skip)
+    if cancel.is_cancelled() {
+        if let Ok(FileProcessorResult::NewFile(r)) = &result {
+            let _ = std::fs::remove_file(&r.result);
+        }
+        bail!("Processing job timed out");
     }
+    result
 }
 
-pub fn probe_file(in_file: PathBuf) -> Result<Option<(usize, usize)>> {
+/// Dimensions, duration and blurhash of a media file
+#[derive(Clone, Debug, Default)]
+pub struct MediaInfo {
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+    /// Duration in seconds
+    pub duration: Option<f32>,
+    pub blur_hash: Option<String>,
+}
+
+pub fn probe_file(in_file: PathBuf) -> Result<MediaInfo> {
     let proc = FFProbe::new();
-    let info = proc.process_file(in_file)?;
-    Ok(info.best_video().map(|v| (v.width, v.height)))
+    let info = proc.process_file(in_file.clone())?;
+    let video = info.best_video();
+    let blur_hash = match video {
+        Some(v) => match unsafe { blurhash(&in_file, v.index) } {
+            Ok(h) => Some(h),
+            Err(e) => {
+                warn!("Failed to compute blurhash: {}", e);
+                None
+            }
+        },
+        None => None,
+    };
+    Ok(MediaInfo {
+        width: video.map(|v| v.width),
+        height: video.map(|v| v.height),
+        duration: Some(info.duration).filter(|d| *d > 0.0),
+        blur_hash,
+    })
+}
+
+/// Size of the image the blurhash is computed from
+const BLURHASH_SIZE: usize = 32;
+
+/// Compute a blurhash from the first frame of a video stream
+unsafe fn blurhash(in_file: &Path, stream_index: usize) -> Result<String> {
+    let mut scaler = Scaler::new();
+    first_frame(in_file, stream_index, |frame| {
+        encode_blurhash(&mut scaler, frame)
+    })
+}
+
+/// Decode the first frame of a stream and pass it to `f`, the frame is freed afterwards
+unsafe fn first_frame<T, F>(in_file: &Path, stream_index: usize, f: F) -> Result<T>
+where
+    F: FnOnce(*mut AVFrame) -> Result<T>,
+{
+    let mut demuxer = Demuxer::new(in_file.to_str().unwrap())?;
+    let info = demuxer.probe_input()?;
+    let stream = info
+        .streams
+        .iter()
+        .find(|s| s.index == stream_index)
+        .ok_or(Error::msg("Stream not found"))?;
+
+    let mut decoder = Decoder::new();
+    decoder.setup_decoder(stream, None)?;
+    let mut f = Some(f);
+    loop {
+        let (mut pkt, pkt_stream) = demuxer.get_packet()?;
+        if pkt.is_null() {
+            bail!("No frames decoded");
+        }
+        if (*pkt_stream).index as usize != stream_index {
+            av_packet_free(&mut pkt);
+            continue;
+        }
+        let frames = decoder.decode_pkt(pkt)?;
+        av_packet_free(&mut pkt);
+
+        let mut result = None;
+        for (mut frame, _) in frames {
+            if let Some(f) = f.take() {
+                result = Some(f(frame));
+            }
+            av_frame_free(&mut frame);
+        }
+        if let Some(r) = result {
+            return r;
+        }
+    }
+}
+
+unsafe fn encode_blurhash(scaler: &mut Scaler, frame: *mut AVFrame) -> Result<String> {
+    let mut rgba = scaler.process_frame(
+        frame,
+        BLURHASH_SIZE as u16,
+        BLURHASH_SIZE as u16,
+        AV_PIX_FMT_RGBA,
+    )?;
+    // rows can be padded, copy them into a packed buffer
+    let mut pixels = Vec::with_capacity(BLURHASH_SIZE * BLURHASH_SIZE * 4);
+    for y in 0..BLURHASH_SIZE {
+        let row = (*rgba).data[0].add(y * (*rgba).linesize[0] as usize);
+        pixels.extend_from_slice(std::slice::from_raw_parts(row, BLURHASH_SIZE * 4));
+    }
+    av_frame_free(&mut rgba);
+    blurhash::encode(4, 3, BLURHASH_SIZE as u32, BLURHASH_SIZE as u32, &pixels)
+        .map_err(|e| Error::msg(e.to_string()))
+}
+
+/// Render a preview image of an image or video from its first frame, scaled to fit
+/// the thumbnail size. Returns the mime type and dimensions of the image written to `out_file`
+pub fn media_thumbnail(
+    in_file: &Path,
+    out_file: &Path,
+    settings: &CompressionSettings,
+    cancel: &CancelToken,
+) -> Result<(String, Option<(usize, usize)>)> {
+    let size = settings.thumbnail_size.unwrap_or(640);
+    let info = FFProbe::new().process_file(in_file.to_path_buf())?;
+    let video = info
+        .best_video()
+        .ok_or(Error::msg("No image or video stream found"))?;
+    let (width, height) = fit_dimensions(video.width, video.height, Some(size), Some(size));
+    let quality = settings.image_quality(ImageFormat::Webp).to_string();
+    let data = unsafe {
+        let mut scaler = Scaler::new();
+        first_frame(in_file, video.index, |frame| {
+            let mut scaled =
+                scaler.process_frame(frame, width as u16, height as u16, AV_PIX_FMT_YUV420P)?;
+            let mut enc = Encoder::new(AV_CODEC_ID_WEBP)?
+                .with_width(width as i32)
+                .with_height(height as i32)
+                .with_pix_fmt(AV_PIX_FMT_YUV420P)
+                .with_options(|ctx| set_option(ctx, "quality", &quality))
+                .open(None)?;
+            let pkts = enc.encode_frame(scaled);
+            av_frame_free(&mut scaled);
+            let mut pkts = pkts?;
+            // flush the encoder, webp packets are complete image files
+            match enc.encode_frame(std::ptr::null_mut()) {
+                Ok(p) => pkts.extend(p),
+                Err(e) => warn!("Failed to flush preview encoder: {}", e),
+            }
+            let mut data = Vec::new();
+            for mut pkt in pkts {
+                data.extend_from_slice(std::slice::from_raw_parts(
+                    (*pkt).data,
+                    (*pkt).size as usize,
+                ));
+                av_packet_free(&mut pkt);
+            }
+            Ok(data)
+        })?
+    };
+    if data.is_empty() {
+        bail!("No preview image encoded");
+    }
+    if cancel.is_cancelled() {
+        bail!("Processing job timed out");
+    }
+    std::fs::write(out_file, data)?;
+    Ok(("image/webp".to_string(), Some((width, height))))
+}
+
+/// Bytes at the start of a file searched for the PDF header
+const PDF_SNIFF_LEN: u64 = 1024;
+
+/// Check the file has a PDF header (`%PDF-`), readers accept it anywhere in the first 1024 bytes
+pub fn is_pdf(path: &Path) -> bool {
+    let mut head = Vec::new();
+    match File::open(path).and_then(|f| f.take(PDF_SNIFF_LEN).read_to_end(&mut head)) {
+        Ok(_) => head.windows(5).any(|w| w == b"%PDF-"),
+        Err(_) => false,
+    }
+}
+
+/// Render the first page of a PDF into a preview image using `pdftoppm` (poppler-utils),
+/// returns the mime type and dimensions (when re-encoded) of the image written to `out_file`.
+/// `pdftoppm` is killed when the job is cancelled.
+pub fn pdf_thumbnail(
+    in_file: &Path,
+    out_file: &Path,
+    settings: &CompressionSettings,
+    cancel: &CancelToken,
+) -> Result<(String, Option<(usize, usize)>)> {
+    if !is_pdf(in_file) {
+        bail!("File is not a PDF");
+    }
+    let size = settings.thumbnail_size.unwrap_or(640);
+    let prefix = out_file.with_extension("page");
+    // -singlefile writes <prefix>.png
+    let page = PathBuf::from(format!("{}.png", prefix.display()));
+    let mut child = Command::new("pdftoppm")
+        .args(["-png", "-f", "1", "-l", "1", "-singlefile", "-scale-to"])
+        .arg(size.to_string())
+        .arg(in_file)
+        .arg(&prefix)
+        .spawn()?;
+    let status = match cancel.wait(&mut child) {
+        Ok(s) => s,
+        Err(e) => {
+            let _ = std::fs::remove_file(&page);
+            return Err(e);
+        }
+    };
+    if !status.success() || !page.exists() {
+        let _ = std::fs::remove_file(&page);
+        bail!("Failed to render PDF page: {}", status);
+    }
+
+    // use the configured image format for the preview, fallback to the png
+    let (result, mime_type, dim) =
+        match ImageProcessor::new().process_file(page.clone(), out_file, "image/png", settings) {
+            Ok(FileProcessorResult::NewFile(r)) => {
+                (r.result, r.mime_type, Some((r.width, r.height)))
+            }
+            _ => (page.clone(), "image/png".to_string(), None),
+        };
+    if cancel.is_cancelled() {
+        let _ = std::fs::remove_file(&result);
+        let _ = std::fs::remove_file(&page);
+        bail!("Processing job timed out");
+    }
+    std::fs::rename(&result, out_file)?;
+    if page.exists() {
+        std::fs::remove_file(page)?;
+    }
+    Ok((mime_type, dim))
 }
@@ -0,0 +1,121 @@
+use std::process::{Child, ExitStatus};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{bail, Error, Result};
+use log::warn;
+use tokio::sync::{mpsc, oneshot};
+
+/// How often a cancelled job checks on its child process
+const CHILD_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+struct Job {
+    run: Box<dyn FnOnce() + Send>,
+    cancel: CancelToken,
+    timeout: Box<dyn FnOnce() + Send>,
+}
+
+/// Set when a job timed out, jobs check it to stop early and remove their output
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Wait for a child process to exit, the child is killed when the job is cancelled
+    pub fn wait(&self, child: &mut Child) -> Result<ExitStatus> {
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(status);
+            }
+            if self.is_cancelled() {
+                child.kill()?;
+                child.wait()?;
+                bail!("Processing job timed out");
+            }
+            std::thread::sleep(CHILD_POLL_INTERVAL);
+        }
+    }
+}
+
+/// Bounded pool of workers for CPU heavy media processing,
+/// jobs are queued on a channel and run on blocking threads
+#[derive(Clone)]
+pub struct ProcessingPool {
+    tx: mpsc::Sender<Job>,
+}
+
+impl ProcessingPool {
+    pub fn new(workers: usize, queue_size: usize, timeout: Duration) -> Self {
+        let (tx, rx) = mpsc::channel::<Job>(queue_size.max(1));
+        let rx = Arc::new(tokio::sync::Mutex::new(rx));
+        for i in 0..workers.max(1) {
+            let rx = rx.clone();
+            tokio::spawn(async move {
+                loop {
+                    let job = match rx.lock().await.recv().await {
+                        Some(j) => j,
+                        None => break,
+                    };
+                    let mut handle = tokio::task::spawn_blocking(job.run);
+                    if tokio::time::timeout(timeout, &mut handle).await.is_err() {
+                        warn!("Processing job on worker {} timed out", i);
+                        job.cancel.cancel();
+                        (job.timeout)();
+                        // child processes are killed, ffmpeg can't be interrupted and stops
+                        // after the current step, the worker stays busy until then so
+                        // timed out jobs don't pile up threads
+                        let _ = handle.await;
+                    }
+                }
+            });
+        }
+        Self { tx }
+    }
+
+    /// Run a job on the pool and wait for the result,
+    /// the job is skipped if the caller stops waiting before it was started
+    pub async fn run<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&CancelToken) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel::<Result<T>>();
+        let tx = Arc::new(Mutex::new(Some(tx)));
+        let tx_timeout = tx.clone();
+        let cancel = CancelToken::default();
+        let job_cancel = cancel.clone();
+        let job = Job {
+            run: Box::new(move || {
+                let waiting = tx.lock().unwrap().as_ref().is_some_and(|t| !t.is_closed());
+                if !waiting {
+                    return;
+                }
+                let r = f(&job_cancel);
+                if let Some(t) = tx.lock().unwrap().take() {
+                    let _ = t.send(r);
+                }
+            }),
+            cancel,
+            timeout: Box::new(move || {
+                if let Some(t) = tx_timeout.lock().unwrap().take() {
+                    let _ = t.send(Err(Error::msg("Processing job timed out")));
+                }
+            }),
+        };
+        if self.tx.send(job).await.is_err() {
+            bail!("Processing pool is not running");
+        }
+        match rx.await {
+            Ok(r) => r,
+            Err(_) => bail!("Processing job was cancelled"),
+        }
+    }
+}
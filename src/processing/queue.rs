@@ -0,0 +1,251 @@
+use std::time::Duration;
+
+use anyhow::{bail, Error};
+use chrono::Utc;
+use log::{error, info, warn};
+use tokio::task::JoinHandle;
+
+#[cfg(feature = "labels")]
+use crate::db::FileLabel;
+use crate::db::{Database, FileVariant, JobKind, ProcessingJob, VariantKind};
+use crate::filesystem::FileStore;
+#[cfg(feature = "labels")]
+use crate::processing::labeling::label_frame;
+use crate::processing::{is_pdf, media_thumbnail, pdf_thumbnail};
+use crate::progress::UploadProgressTracker;
+use crate::settings::Settings;
+
+/// Longest delay between attempts of a failed job in seconds
+const MAX_RETRY_DELAY: u64 = 6 * 60 * 60;
+
+/// Delay before the next attempt of a job which failed `attempts` times, doubling from 30s
+fn retry_delay(attempts: u32) -> u64 {
+    2u64.checked_pow(attempts.saturating_sub(1))
+        .map_or(MAX_RETRY_DELAY, |m| m.saturating_mul(30))
+        .min(MAX_RETRY_DELAY)
+}
+
+/// Runs background processing jobs stored in the database,
+/// failed jobs are retried with exponential backoff
+pub struct ProcessingQueue {
+    db: Database,
+    fs: FileStore,
+    progress: UploadProgressTracker,
+    settings: Settings,
+    interval: Duration,
+    max_attempts: u32,
+}
+
+impl ProcessingQueue {
+    pub fn new(
+        settings: &Settings,
+        db: Database,
+        fs: FileStore,
+        progress: UploadProgressTracker,
+    ) -> Self {
+        Self {
+            db,
+            fs,
+            progress,
+            settings: settings.clone(),
+            interval: Duration::from_secs(10),
+            max_attempts: settings
+                .processing
+                .as_ref()
+                .and_then(|p| p.max_attempts)
+                .unwrap_or(5),
+        }
+    }
+
+    pub fn start(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            // jobs which were running when the server stopped need to run again
+            match self.db.reset_running_jobs().await {
+                Ok(n) if n > 0 => info!("Re-queued {} interrupted processing jobs", n),
+                Ok(_) => {}
+                Err(e) => error!("Failed to reset processing jobs: {}", e),
+            }
+            if let Err(e) = self.migrate_thumbnails().await {
+                error!("Failed to migrate thumbnails: {}", e);
+            }
+            while !self.fs.shutdown().is_draining() {
+                if let Err(e) = self.run_once().await {
+                    error!("Processing queue failed: {}", e);
+                }
+                tokio::time::sleep(self.interval).await;
+            }
+        })
+    }
+
+    /// Move previews stored next to the file (`.thumb`) by older versions into variants,
+    /// previews which are missing are rendered again
+    async fn migrate_thumbnails(&self) -> Result<(), Error> {
+        let mut n = 0;
+        loop {
+            let files = self.db.list_legacy_thumbnails(100).await?;
+            if files.is_empty() {
+                break;
+            }
+            for f in files {
+                let path = self.fs.map_thumb_path(&f.id);
+                if !path.exists() {
+                    self.db.set_file_thumbnail(&f.id, None).await?;
+                    if is_pdf(&self.fs.get(&f.id)) {
+                        self.db
+                            .add_processing_job(&f.id, JobKind::Thumbnail)
+                            .await?;
+                    }
+                    continue;
+                }
+                let (id, size) = self.fs.store_variant(&path).await?;
+                self.db
+                    .add_file_variant(&FileVariant {
+                        file: f.id.clone(),
+                        id,
+                        kind: VariantKind::Thumbnail,
+                        size,
+                        mime_type: f.thumb_mime.clone().unwrap_or_default(),
+                        width: None,
+                        height: None,
+                        codec: None,
+                        created: Utc::now(),
+                    })
+                    .await?;
+                n += 1;
+            }
+        }
+        if n > 0 {
+            info!("Moved {} legacy thumbnails to variants", n);
+        }
+        Ok(())
+    }
+
+    async fn run_once(&self) -> Result<(), Error> {
+        loop {
+            // jobs which are still running at shutdown are re-queued on the next start
+            let _active = match self.fs.shutdown().begin() {
+                Some(a) => a,
+                None => break,
+            };
+            let job = match self.db.claim_processing_job().await? {
+                Some(j) => j,
+                None => break,
+            };
+            let res = self.process(&job).await;
+            // uploads waiting for their compressed version are done after the first attempt
+            if job.kind == JobKind::Transform {
+                self.progress.processing_done(&job.file);
+            }
+            match res {
+                Ok(()) => self.db.complete_processing_job(job.id).await?,
+                Err(e) => {
+                    let attempts = job.attempts + 1;
+                    let retry = if attempts < self.max_attempts {
+                        Some(retry_delay(attempts))
+                    } else {
+                        None
+                    };
+                    warn!(
+                        "Processing job {} ({:?}) for {} failed (attempt {}): {}",
+                        job.id,
+                        job.kind,
+                        hex::encode(&job.file),
+                        attempts,
+                        e
+                    );
+                    let msg: String = e.to_string().chars().take(1024).collect();
+                    self.db.fail_processing_job(job.id, &msg, retry).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn process(&self, job: &ProcessingJob) -> Result<(), Error> {
+        if !self.fs.get(&job.file).exists() {
+            bail!("File not found");
+        }
+        match job.kind {
+            JobKind::Label => self.label(&job.file).await,
+            JobKind::Thumbnail => self.thumbnail(&job.file).await,
+            JobKind::Transform => self.transform(&job.file).await,
+        }
+    }
+
+    async fn transform(&self, file: &Vec<u8>) -> Result<(), Error> {
+        let upload = match self.db.get_file(file).await? {
+            Some(u) => u,
+            None => bail!("File not found"),
+        };
+        if let Some(v) = self.fs.transform(&upload).await? {
+            self.db.add_file_variant(&v).await?;
+        }
+        Ok(())
+    }
+
+    async fn thumbnail(&self, file: &Vec<u8>) -> Result<(), Error> {
+        let (path, out) = (self.fs.get(file), self.fs.temp_path()?);
+        let settings = self.settings.compression.clone().unwrap_or_default();
+        let render_out = out.clone();
+        let (mime_type, dim) = self
+            .fs
+            .pool()
+            .run(move |cancel| {
+                if is_pdf(&path) {
+                    pdf_thumbnail(&path, &render_out, &settings, cancel)
+                } else {
+                    media_thumbnail(&path, &render_out, &settings, cancel)
+                }
+            })
+            .await?;
+        let (id, size) = self.fs.store_variant(&out).await?;
+        self.db
+            .add_file_variant(&FileVariant {
+                file: file.clone(),
+                id,
+                kind: VariantKind::Thumbnail,
+                size,
+                mime_type: mime_type.clone(),
+                width: dim.map(|(w, _)| w as u32),
+                height: dim.map(|(_, h)| h as u32),
+                codec: None,
+                created: Utc::now(),
+            })
+            .await?;
+        self.db.set_file_thumbnail(file, Some(&mime_type)).await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "labels")]
+    async fn label(&self, file: &Vec<u8>) -> Result<(), Error> {
+        let labels = label_file(&self.fs, &self.settings, file).await?;
+        self.db.add_file_labels(file, &labels).await?;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "labels"))]
+    async fn label(&self, _file: &Vec<u8>) -> Result<(), Error> {
+        bail!("Labels are not supported in this build")
+    }
+}
+
+/// Classify the contents of a stored image on the processing pool
+#[cfg(feature = "labels")]
+pub async fn label_file(
+    fs: &FileStore,
+    settings: &Settings,
+    file: &Vec<u8>,
+) -> Result<Vec<FileLabel>, Error> {
+    let model = match &settings.vit_model_path {
+        Some(m) => m.clone(),
+        None => bail!("No model configured"),
+    };
+    let path = fs.get(file);
+    Ok(fs
+        .pool()
+        .run(move |_| label_frame(&path, model))
+        .await?
+        .into_iter()
+        .map(|l| FileLabel::new(l, "vit224".to_string()))
+        .collect())
+}
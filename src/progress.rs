@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use rocket::data::{self, ByteUnit, DataStream, FromData};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::{async_trait, Data, Request};
+use serde::Serialize;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::limits::{StreamLimits, TimeoutReader};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadState {
+    /// Receiving bytes from the client
+    Receiving,
+    /// All bytes received, probing the file and running queued compression / transcoding
+    Processing,
+    Done,
+    Failed,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct UploadProgress {
+    pub state: UploadState,
+    pub received: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<u64>,
+}
+
+/// Upload ids which are not used within this time are released
+const RESERVATION_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Max number of reserved upload ids
+const MAX_RESERVATIONS: usize = 10_000;
+
+/// Max number of reserved upload ids for a single client IP
+const MAX_RESERVATIONS_PER_IP: usize = 16;
+
+/// Interval of removing expired reservations and processing entries
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Uploads are reported as done after this time when queued processing has not finished
+const PROCESSING_TTL: Duration = Duration::from_secs(60 * 60);
+
+type JobMap = Arc<Mutex<HashMap<String, watch::Receiver<UploadProgress>>>>;
+type ProcessingMap = Arc<Mutex<HashMap<Vec<u8>, (Instant, ProgressHandle)>>>;
+
+struct Reservation {
+    created: Instant,
+    ip: IpAddr,
+    tx: watch::Sender<UploadProgress>,
+}
+
+/// Tracks progress of in-flight uploads, upload ids are generated by the server
+/// with [UploadProgressTracker::reserve] so they cannot be guessed or reused by other clients
+#[derive(Clone, Default)]
+pub struct UploadProgressTracker {
+    jobs: JobMap,
+    reserved: Arc<Mutex<HashMap<String, Reservation>>>,
+    /// Handles of uploads waiting for queued processing, by file id
+    processing: ProcessingMap,
+}
+
+impl UploadProgressTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remove expired reservations and processing entries every [SWEEP_INTERVAL],
+    /// progress streams of expired reservations are closed
+    pub fn start_sweep(&self) -> JoinHandle<()> {
+        let tracker = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(SWEEP_INTERVAL).await;
+                tracker.sweep();
+            }
+        })
+    }
+
+    fn sweep(&self) {
+        self.sweep_reserved(&mut self.reserved.lock().unwrap());
+        self.processing.lock().unwrap().retain(|_, (t, h)| {
+            let keep = t.elapsed() < PROCESSING_TTL;
+            if !keep {
+                h.done();
+            }
+            keep
+        });
+    }
+
+    fn sweep_reserved(&self, reserved: &mut HashMap<String, Reservation>) {
+        let mut jobs = self.jobs.lock().unwrap();
+        reserved.retain(|id, r| {
+            let keep = r.created.elapsed() < RESERVATION_TTL;
+            if !keep {
+                jobs.remove(id);
+            }
+            keep
+        });
+    }
+
+    /// Create a new upload id for a client, progress can be subscribed to before the upload
+    /// starts. Fails with `429` when the client has too many ids reserved and `503` when
+    /// too many ids are reserved in total
+    pub fn reserve(&self, ip: IpAddr) -> Result<String, Status> {
+        let mut reserved = self.reserved.lock().unwrap();
+        self.sweep_reserved(&mut reserved);
+        if reserved.values().filter(|r| r.ip == ip).count() >= MAX_RESERVATIONS_PER_IP {
+            return Err(Status::TooManyRequests);
+        }
+        if reserved.len() >= MAX_RESERVATIONS {
+            return Err(Status::ServiceUnavailable);
+        }
+        let id = uuid::Uuid::new_v4().to_string();
+        let (tx, rx) = watch::channel(UploadProgress {
+            state: UploadState::Receiving,
+            received: 0,
+            total: None,
+        });
+        self.jobs.lock().unwrap().insert(id.clone(), rx);
+        reserved.insert(
+            id.clone(),
+            Reservation {
+                created: Instant::now(),
+                ip,
+                tx,
+            },
+        );
+        Ok(id)
+    }
+
+    /// Start tracking an upload with a reserved id, [None] when the id was not reserved
+    /// or is already used
+    pub fn start(&self, id: &str, total: Option<u64>) -> Option<ProgressHandle> {
+        let r = self.reserved.lock().unwrap().remove(id)?;
+        r.tx.send_modify(|p| p.total = total);
+        Some(ProgressHandle(Arc::new(ProgressJob {
+            id: id.to_string(),
+            tx: r.tx,
+            tracker: self.clone(),
+        })))
+    }
+
+    /// Queued processing of a file finished, the upload is reported as done
+    pub fn processing_done(&self, file: &[u8]) {
+        if let Some((_, h)) = self.processing.lock().unwrap().remove(file) {
+            h.done();
+        }
+    }
+
+    /// Subscribe to progress updates of an upload
+    pub fn subscribe(&self, id: &str) -> Option<watch::Receiver<UploadProgress>> {
+        self.jobs.lock().unwrap().get(id).cloned()
+    }
+}
+
+struct ProgressJob {
+    id: String,
+    tx: watch::Sender<UploadProgress>,
+    tracker: UploadProgressTracker,
+}
+
+impl Drop for ProgressJob {
+    fn drop(&mut self) {
+        // any upload which was not marked as done has failed
+        self.tx.send_if_modified(|p| {
+            if p.state != UploadState::Done {
+                p.state = UploadState::Failed;
+                true
+            } else {
+                false
+            }
+        });
+        self.tracker.jobs.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Handle for updating the progress of a single upload,
+/// the upload is removed from the tracker once all handles are dropped
+#[derive(Clone)]
+pub struct ProgressHandle(Arc<ProgressJob>);
+
+impl ProgressHandle {
+    pub fn add_received(&self, n: u64) {
+        self.0.tx.send_modify(|p| p.received += n);
+    }
+
+    pub fn set_state(&self, state: UploadState) {
+        self.0.tx.send_modify(|p| p.state = state);
+    }
+
+    pub fn done(&self) {
+        self.set_state(UploadState::Done);
+    }
+
+    /// Keep reporting the upload as processing until queued processing of the stored file
+    /// is done, see [UploadProgressTracker::processing_done]
+    pub fn wait_processing(self, file: &[u8]) {
+        self.0
+            .tracker
+            .processing
+            .lock()
+            .unwrap()
+            .insert(file.to_vec(), (Instant::now(), self.clone()));
+    }
+}
+
+/// Start tracking the upload of a request from the `X-Upload-Id` header
+fn start_request(request: &Request<'_>) -> Result<Option<ProgressHandle>, &'static str> {
+    let id = match request.headers().get_one("x-upload-id") {
+        Some(i) => i,
+        None => return Ok(None),
+    };
+    let tracker = match request.rocket().state::<UploadProgressTracker>() {
+        Some(t) => t,
+        None => return Ok(None),
+    };
+    let total = request
+        .headers()
+        .get_one("content-length")
+        .and_then(|v| v.parse().ok());
+    match tracker.start(id, total) {
+        Some(h) => Ok(Some(h)),
+        None => Err("Unknown upload id"),
+    }
+}
+
+#[async_trait]
+impl<'r> FromRequest<'r> for ProgressHandle {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        // cached so that route and form field guards share the same handle
+        match request.local_cache(|| start_request(request)) {
+            Ok(Some(h)) => Outcome::Success(h.clone()),
+            Ok(None) => Outcome::Forward(Status::Ok),
+            Err(e) => Outcome::Error((Status::BadRequest, *e)),
+        }
+    }
+}
+
+/// Request body of an upload, progress is reported while reading when the request
+/// has an `X-Upload-Id` header
+pub struct ProgressData<'r> {
+    data: Data<'r>,
+    limits: StreamLimits,
+    pub progress: Option<ProgressHandle>,
+}
+
+impl<'r> ProgressData<'r> {
+    /// Read the body up to `limit` bytes, with the timeouts and body limit of the route
+    pub fn open(self, limit: ByteUnit) -> ProgressReader<TimeoutReader<DataStream<'r>>> {
+        let stream = self.data.open(self.limits.limit(limit));
+        ProgressReader::new(self.limits.reader(stream), self.progress)
+    }
+}
+
+#[async_trait]
+impl<'r> FromData<'r> for ProgressData<'r> {
+    type Error = &'static str;
+
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let progress = match ProgressHandle::from_request(request).await {
+            Outcome::Success(p) => Some(p),
+            Outcome::Forward(_) => None,
+            Outcome::Error(e) => return data::Outcome::Error(e),
+        };
+        data::Outcome::Success(ProgressData {
+            data,
+            limits: StreamLimits::for_request(request),
+            progress,
+        })
+    }
+}
+
+/// Reader which reports received bytes to an upload progress handle
+pub struct ProgressReader<R> {
+    inner: R,
+    progress: Option<ProgressHandle>,
+}
+
+impl<R> ProgressReader<R> {
+    pub fn new(inner: R, progress: Option<ProgressHandle>) -> Self {
+        Self { inner, progress }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ProgressReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(())), Some(p)) = (&res, &self.progress) {
+            let n = buf.filled().len() - before;
+            if n > 0 {
+                p.add_received(n as u64);
+            } else if buf.remaining() > 0 {
+                // EOF, all data received
+                p.set_state(UploadState::Processing);
+            }
+        }
+        res
+    }
+}
@@ -0,0 +1,110 @@
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::{routes, Responder, Route, State};
+
+use crate::auth::nip98::Nip98Auth;
+use crate::db::Database;
+use crate::settings::Settings;
+
+pub fn account_routes() -> Vec<Route> {
+    routes![get_account]
+}
+
+#[derive(Serialize, Default)]
+#[serde(crate = "rocket::serde")]
+struct AccountResponseBase<T> {
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<T>,
+}
+
+#[derive(Responder)]
+enum AccountResponse<T> {
+    #[response(status = 500)]
+    GenericError(Json<AccountResponseBase<T>>),
+
+    #[response(status = 200)]
+    Ok(Json<AccountResponseBase<T>>),
+}
+
+impl<T> AccountResponse<T> {
+    pub fn error(msg: &str) -> Self {
+        Self::GenericError(Json(AccountResponseBase {
+            status: "error".to_string(),
+            message: Some(msg.to_string()),
+            data: None,
+        }))
+    }
+
+    pub fn success(msg: T) -> Self {
+        Self::Ok(Json(AccountResponseBase {
+            status: "success".to_string(),
+            message: None,
+            data: Some(msg),
+        }))
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Account {
+    /// Plan id, [None] when the user has the default plan
+    pub plan: Option<String>,
+    pub bandwidth: BandwidthUsage,
+    pub storage: StorageUsage,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct StorageUsage {
+    /// Size of the user's files including their variants
+    pub used: u64,
+    /// Storage quota from the user's plan
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u64>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct BandwidthUsage {
+    /// Bytes served for this user's files in the current month
+    pub used: u64,
+    /// Monthly allowance from the user's plan
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u64>,
+}
+
+#[rocket::get("/account")]
+async fn get_account(
+    auth: Nip98Auth,
+    db: &State<Database>,
+    settings: &State<Settings>,
+) -> AccountResponse<Account> {
+    let pubkey_vec = auth.pubkey().to_bytes().to_vec();
+    let user = match db.get_user(&pubkey_vec).await {
+        Ok(u) => u,
+        Err(_) => return AccountResponse::error("User not found"),
+    };
+    let used = match db.get_user_bandwidth(user.id).await {
+        Ok(b) => b,
+        Err(e) => return AccountResponse::error(&format!("Could not get bandwidth: {}", e)),
+    };
+    let stored = match db.get_user_storage(&pubkey_vec).await {
+        Ok(s) => s,
+        Err(e) => return AccountResponse::error(&format!("Could not get storage: {}", e)),
+    };
+    let plan = settings.user_plan(user.plan.as_deref());
+    AccountResponse::success(Account {
+        plan: user.plan,
+        bandwidth: BandwidthUsage {
+            used,
+            limit: plan.as_ref().and_then(|p| p.bandwidth_bytes),
+        },
+        storage: StorageUsage {
+            used: stored,
+            limit: plan.as_ref().and_then(|p| p.max_storage_bytes),
+        },
+    })
+}
@@ -1,14 +1,31 @@
+use std::collections::HashMap;
+
 use crate::auth::nip98::Nip98Auth;
-use crate::db::{Database, FileUpload, User};
-use crate::routes::{Nip94Event, PagedResult};
+use crate::db::{AuditAction, AuditEntry, Database, FileUpload, NewAuditEntry, Report, User};
+use crate::filesystem::FileStore;
+use crate::notify::Notifier;
+use crate::routes::{parse_file_id, Nip94Event, PagedResult};
 use crate::settings::Settings;
+use crate::stats::DownloadStats;
+use log::warn;
+use nostr::PublicKey;
 use rocket::serde::json::Json;
 use rocket::serde::Serialize;
 use rocket::{routes, Responder, Route, State};
 use sqlx::{Error, Row};
 
 pub fn admin_routes() -> Vec<Route> {
-    routes![admin_list_files, admin_get_self]
+    routes![
+        admin_list_files,
+        admin_get_self,
+        admin_set_user_plan,
+        admin_get_stats,
+        admin_list_users,
+        admin_takedown_file,
+        admin_list_audit_log,
+        admin_list_reports,
+        admin_dismiss_report
+    ]
 }
 
 #[derive(Serialize, Default)]
@@ -90,6 +107,255 @@ async fn admin_list_files(
     }
 }
 
+#[derive(Serialize, Default)]
+#[serde(crate = "rocket::serde")]
+struct StorageStats {
+    pub files: u64,
+    pub total_size: u64,
+    /// Size of thumbnails / transcodes, not included in `total_size`
+    pub variant_size: u64,
+    pub users: u64,
+    /// Free space on the storage volume
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub free_space: Option<u64>,
+    /// Downloads by country since startup (requires `geoip_database`)
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub downloads_by_country: HashMap<String, u64>,
+}
+
+#[rocket::get("/stats")]
+async fn admin_get_stats(
+    auth: Nip98Auth,
+    db: &State<Database>,
+    fs: &State<FileStore>,
+    stats: &State<DownloadStats>,
+) -> AdminResponse<StorageStats> {
+    let pubkey_vec = auth.event.pubkey.to_bytes().to_vec();
+    match db.get_user(&pubkey_vec).await {
+        Ok(user) if user.is_admin => {}
+        Ok(_) => return AdminResponse::error("User is not an admin"),
+        Err(_) => return AdminResponse::error("User not found"),
+    };
+
+    match db.get_storage_stats().await {
+        Ok(mut s) => {
+            s.free_space = fs.free_space().ok();
+            s.downloads_by_country = stats.countries();
+            AdminResponse::success(s)
+        }
+        Err(e) => AdminResponse::error(&format!("Could not get stats: {}", e)),
+    }
+}
+
+#[rocket::get("/users?<page>&<count>")]
+async fn admin_list_users(
+    auth: Nip98Auth,
+    page: u32,
+    count: u32,
+    db: &State<Database>,
+) -> AdminResponse<PagedResult<User>> {
+    let pubkey_vec = auth.event.pubkey.to_bytes().to_vec();
+    let server_count = count.clamp(1, 5_000);
+    match db.get_user(&pubkey_vec).await {
+        Ok(user) if user.is_admin => {}
+        Ok(_) => return AdminResponse::error("User is not an admin"),
+        Err(_) => return AdminResponse::error("User not found"),
+    };
+
+    match db.list_users(page * server_count, server_count).await {
+        Ok((users, total)) => AdminResponse::success(PagedResult {
+            count: users.len() as u32,
+            page,
+            total: total as u32,
+            files: users,
+        }),
+        Err(e) => AdminResponse::error(&format!("Could not list users: {}", e)),
+    }
+}
+
+#[rocket::put("/user/<pubkey>/plan?<plan>")]
+async fn admin_set_user_plan(
+    auth: Nip98Auth,
+    pubkey: &str,
+    plan: Option<&str>,
+    db: &State<Database>,
+    settings: &State<Settings>,
+) -> AdminResponse<()> {
+    let pubkey_vec = auth.event.pubkey.to_bytes().to_vec();
+    match db.get_user(&pubkey_vec).await {
+        Ok(user) if user.is_admin => {}
+        Ok(_) => return AdminResponse::error("User is not an admin"),
+        Err(_) => return AdminResponse::error("User not found"),
+    };
+
+    if let Some(p) = plan {
+        if !settings.plans().contains_key(p) {
+            return AdminResponse::error("Unknown plan");
+        }
+    }
+    let target = match hex::decode(pubkey) {
+        Ok(p) if p.len() == 32 => p,
+        _ => return AdminResponse::error("Invalid pubkey"),
+    };
+    if let Err(e) = db.upsert_user(&target).await {
+        return AdminResponse::error(&format!("Could not save user: {}", e));
+    }
+    let audit = NewAuditEntry {
+        actor: &pubkey_vec,
+        action: AuditAction::SetPlan,
+        target: pubkey.to_string(),
+        reason: plan,
+    };
+    match db.set_user_plan(&target, plan, &audit).await {
+        Ok(_) => AdminResponse::success(()),
+        Err(e) => AdminResponse::error(&format!("Could not set plan: {}", e)),
+    }
+}
+
+/// Remove and ban a file for all users, owners are sent a DM explaining why when `notify` is set
+#[allow(clippy::too_many_arguments)]
+#[rocket::delete("/file/<sha256>?<reason>&<notify>")]
+async fn admin_takedown_file(
+    auth: Nip98Auth,
+    sha256: &str,
+    reason: Option<&str>,
+    notify: Option<bool>,
+    db: &State<Database>,
+    fs: &State<FileStore>,
+    settings: &State<Settings>,
+    notifier: &State<Option<Notifier>>,
+) -> AdminResponse<()> {
+    let pubkey_vec = auth.event.pubkey.to_bytes().to_vec();
+    match db.get_user(&pubkey_vec).await {
+        Ok(user) if user.is_admin => {}
+        Ok(_) => return AdminResponse::error("User is not an admin"),
+        Err(_) => return AdminResponse::error("User not found"),
+    };
+
+    let id = match parse_file_id(sha256) {
+        Ok(i) => i,
+        Err(e) => return AdminResponse::error(&e.to_string()),
+    };
+    match db.get_file(&id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return AdminResponse::error("File not found"),
+        Err(e) => return AdminResponse::error(&format!("Could not get file: {}", e)),
+    }
+    let owners = if notify.unwrap_or(false) {
+        if notifier.is_none() {
+            return AdminResponse::error("Notifications are not configured");
+        }
+        db.get_file_owners(&id).await.unwrap_or_default()
+    } else {
+        vec![]
+    };
+    let variants = match db.takedown_file(&id, &pubkey_vec, reason).await {
+        Ok(v) => v,
+        Err(e) => return AdminResponse::error(&format!("Failed to delete (db): {}", e)),
+    };
+    if let Some(b) = fs.blocklist() {
+        b.add(&id);
+    }
+    if let Err(e) = fs.delete(&id, &variants) {
+        return AdminResponse::error(&format!("Failed to delete (fs): {}", e));
+    }
+    if let Some(n) = notifier.inner() {
+        let msg = format!(
+            "Your file {}/{} was removed by the server admins.\nReason: {}",
+            settings.public_url,
+            hex::encode(&id),
+            reason.unwrap_or("not specified")
+        );
+        for o in owners {
+            let pk = match PublicKey::from_slice(&o.pubkey) {
+                Ok(pk) => pk,
+                Err(_) => continue,
+            };
+            if let Err(e) = n.send_dm(&pk, &msg).await {
+                warn!("Failed to send takedown notice to {}: {}", pk.to_hex(), e);
+            }
+        }
+    }
+    AdminResponse::success(())
+}
+
+#[rocket::get("/audit?<page>&<count>")]
+async fn admin_list_audit_log(
+    auth: Nip98Auth,
+    page: u32,
+    count: u32,
+    db: &State<Database>,
+) -> AdminResponse<PagedResult<AuditEntry>> {
+    let pubkey_vec = auth.event.pubkey.to_bytes().to_vec();
+    let server_count = count.clamp(1, 5_000);
+    match db.get_user(&pubkey_vec).await {
+        Ok(user) if user.is_admin => {}
+        Ok(_) => return AdminResponse::error("User is not an admin"),
+        Err(_) => return AdminResponse::error("User not found"),
+    };
+
+    match db.list_audit_log(page * server_count, server_count).await {
+        Ok((entries, total)) => AdminResponse::success(PagedResult {
+            count: entries.len() as u32,
+            page,
+            total: total as u32,
+            files: entries,
+        }),
+        Err(e) => AdminResponse::error(&format!("Could not list audit log: {}", e)),
+    }
+}
+
+/// Reports which have not been reviewed, oldest first,
+/// reports are removed when the file is taken down
+#[rocket::get("/reports?<page>&<count>")]
+async fn admin_list_reports(
+    auth: Nip98Auth,
+    page: u32,
+    count: u32,
+    db: &State<Database>,
+) -> AdminResponse<PagedResult<Report>> {
+    let pubkey_vec = auth.event.pubkey.to_bytes().to_vec();
+    let server_count = count.clamp(1, 5_000);
+    match db.get_user(&pubkey_vec).await {
+        Ok(user) if user.is_admin => {}
+        Ok(_) => return AdminResponse::error("User is not an admin"),
+        Err(_) => return AdminResponse::error("User not found"),
+    };
+
+    match db.list_reports(page * server_count, server_count).await {
+        Ok((reports, total)) => AdminResponse::success(PagedResult {
+            count: reports.len() as u32,
+            page,
+            total: total as u32,
+            files: reports,
+        }),
+        Err(e) => AdminResponse::error(&format!("Could not list reports: {}", e)),
+    }
+}
+
+/// Mark a report as reviewed without removing the file
+#[rocket::delete("/report/<id>")]
+async fn admin_dismiss_report(auth: Nip98Auth, id: u64, db: &State<Database>) -> AdminResponse<()> {
+    let pubkey_vec = auth.event.pubkey.to_bytes().to_vec();
+    match db.get_user(&pubkey_vec).await {
+        Ok(user) if user.is_admin => {}
+        Ok(_) => return AdminResponse::error("User is not an admin"),
+        Err(_) => return AdminResponse::error("User not found"),
+    };
+
+    let audit = NewAuditEntry {
+        actor: &pubkey_vec,
+        action: AuditAction::DismissReport,
+        target: id.to_string(),
+        reason: None,
+    };
+    match db.dismiss_report(id, &audit).await {
+        Ok(true) => AdminResponse::success(()),
+        Ok(false) => AdminResponse::error("Report not found"),
+        Err(e) => AdminResponse::error(&format!("Could not dismiss report: {}", e)),
+    }
+}
+
 impl Database {
     pub async fn list_all_files(
         &self,
@@ -112,4 +378,133 @@ impl Database {
             .try_get(0)?;
         Ok((results, count))
     }
+
+    async fn get_storage_stats(&self) -> Result<StorageStats, Error> {
+        let row = sqlx::query(
+            "select count(u.id), cast(coalesce(sum(u.size), 0) as unsigned) from uploads u",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        let users: i64 = sqlx::query("select count(u.id) from users u")
+            .fetch_one(&self.pool)
+            .await?
+            .try_get(0)?;
+        // variants can be shared by multiple files
+        let variant_size: u64 = sqlx::query(
+            "select cast(coalesce(sum(v.size), 0) as unsigned) \
+            from (select distinct id, size from upload_variants) v",
+        )
+        .fetch_one(&self.pool)
+        .await?
+        .try_get(0)?;
+        Ok(StorageStats {
+            files: row.try_get::<i64, _>(0)? as u64,
+            total_size: row.try_get(1)?,
+            variant_size,
+            users: users as u64,
+            ..Default::default()
+        })
+    }
+
+    pub async fn list_users(&self, offset: u32, limit: u32) -> Result<(Vec<User>, i64), Error> {
+        let results: Vec<User> = sqlx::query_as(
+            "select u.* \
+            from users u \
+            order by u.created desc, u.id desc \
+            limit ? offset ?",
+        )
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
+        let count: i64 = sqlx::query("select count(u.id) from users u")
+            .fetch_one(&self.pool)
+            .await?
+            .try_get(0)?;
+        Ok((results, count))
+    }
+
+    pub async fn list_audit_log(
+        &self,
+        offset: u32,
+        limit: u32,
+    ) -> Result<(Vec<AuditEntry>, i64), Error> {
+        let results: Vec<AuditEntry> = sqlx::query_as(
+            "select a.* \
+            from audit_log a \
+            order by a.id desc \
+            limit ? offset ?",
+        )
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
+        let count: i64 = sqlx::query("select count(a.id) from audit_log a")
+            .fetch_one(&self.pool)
+            .await?
+            .try_get(0)?;
+        Ok((results, count))
+    }
+
+    pub async fn list_reports(&self, offset: u32, limit: u32) -> Result<(Vec<Report>, i64), Error> {
+        let results: Vec<Report> = sqlx::query_as(
+            "select r.id, r.file, u.pubkey as reporter, r.reason, r.created \
+            from reports r \
+            join users u on u.id = r.reporter_id \
+            where r.reviewed = 0 \
+            order by r.created asc \
+            limit ? offset ?",
+        )
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
+        let count: i64 = sqlx::query("select count(r.id) from reports r where r.reviewed = 0")
+            .fetch_one(&self.pool)
+            .await?
+            .try_get(0)?;
+        Ok((results, count))
+    }
+
+    /// Mark a report as reviewed, returns false when there is no open report with this id
+    pub async fn dismiss_report(&self, id: u64, audit: &NewAuditEntry<'_>) -> Result<bool, Error> {
+        let mut tx = self.pool.begin().await?;
+        let dismissed =
+            sqlx::query("update reports set reviewed = 1 where id = ? and reviewed = 0")
+                .bind(id)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected()
+                > 0;
+        if dismissed {
+            audit.insert(&mut tx).await?;
+        }
+        tx.commit().await?;
+        Ok(dismissed)
+    }
+
+    /// Delete a file for all users and ban its hash so it can't be uploaded again,
+    /// returns the variant blobs which can be removed from storage
+    pub async fn takedown_file(
+        &self,
+        file: &Vec<u8>,
+        actor: &Vec<u8>,
+        reason: Option<&str>,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        let mut tx = self.pool.begin().await?;
+        let unused = Self::delete_file_variants(&mut tx, file).await?;
+        Self::ban_hash(&mut tx, file, reason).await?;
+        for action in [AuditAction::Takedown, AuditAction::Ban] {
+            NewAuditEntry {
+                actor,
+                action,
+                target: hex::encode(file),
+                reason,
+            }
+            .insert(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(unused)
+    }
 }
@@ -0,0 +1,31 @@
+use std::path::PathBuf;
+
+use rocket::http::ContentType;
+use rocket::{routes, Route};
+use rust_embed::RustEmbed;
+
+/// Static files of the admin UI, built into the binary
+#[derive(RustEmbed)]
+#[folder = "admin_ui/"]
+struct AdminUi;
+
+pub fn admin_ui_routes() -> Vec<Route> {
+    routes![admin_ui]
+}
+
+/// Serve the admin UI, `/admin` serves `index.html` and unknown paths are not found
+/// so that admin API requests which fail to match a route don't get the page.
+/// Ranked after the admin API routes which share the same prefix
+#[rocket::get("/<path..>", rank = 20)]
+async fn admin_ui(path: PathBuf) -> Option<(ContentType, Vec<u8>)> {
+    let name = match path.to_str()? {
+        "" => "index.html",
+        p => p,
+    };
+    let file = AdminUi::get(name)?;
+    let ct = name
+        .rsplit_once('.')
+        .and_then(|(_, ext)| ContentType::from_extension(ext))
+        .unwrap_or(ContentType::Binary);
+    Some((ct, file.data.into_owned()))
+}
@@ -0,0 +1,133 @@
+use chrono::{DateTime, Utc};
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::{routes, Responder, Route, State};
+
+use crate::auth::api_key::ApiKeyAuth;
+use crate::auth::nip98::Nip98Auth;
+use crate::db::{ApiKey, Database};
+
+pub fn api_key_routes() -> Vec<Route> {
+    routes![create_key, list_keys, delete_key]
+}
+
+#[derive(Serialize, Default)]
+#[serde(crate = "rocket::serde")]
+struct ApiKeyResponseBase<T> {
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<T>,
+}
+
+#[derive(Responder)]
+enum ApiKeyResponse<T> {
+    #[response(status = 500)]
+    GenericError(Json<ApiKeyResponseBase<T>>),
+
+    #[response(status = 403)]
+    Forbidden(Json<ApiKeyResponseBase<T>>),
+
+    #[response(status = 200)]
+    Ok(Json<ApiKeyResponseBase<T>>),
+}
+
+impl<T> ApiKeyResponse<T> {
+    pub fn error(msg: &str) -> Self {
+        Self::GenericError(Json(ApiKeyResponseBase {
+            status: "error".to_string(),
+            message: Some(msg.to_string()),
+            data: None,
+        }))
+    }
+
+    pub fn forbidden(msg: &str) -> Self {
+        Self::Forbidden(Json(ApiKeyResponseBase {
+            status: "error".to_string(),
+            message: Some(msg.to_string()),
+            data: None,
+        }))
+    }
+
+    pub fn success(msg: T) -> Self {
+        Self::Ok(Json(ApiKeyResponseBase {
+            status: "success".to_string(),
+            message: None,
+            data: Some(msg),
+        }))
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct NewApiKey {
+    pub id: u64,
+    /// The API key, this is only returned once
+    pub key: String,
+    pub name: Option<String>,
+    pub created: DateTime<Utc>,
+}
+
+#[rocket::post("/keys?<name>")]
+async fn create_key(
+    auth: Nip98Auth,
+    name: Option<&str>,
+    db: &State<Database>,
+) -> ApiKeyResponse<NewApiKey> {
+    // a delegation can be limited by kind / time, keys would outlive those conditions
+    if auth.delegator.is_some() {
+        return ApiKeyResponse::forbidden("Delegated auth can not be used to manage API keys");
+    }
+    let pubkey_vec = auth.pubkey().to_bytes().to_vec();
+    let user_id = match db.upsert_user(&pubkey_vec).await {
+        Ok(u) => u,
+        Err(e) => return ApiKeyResponse::error(&format!("Could not save user: {}", e)),
+    };
+    let key = ApiKeyAuth::generate_key();
+    match db
+        .add_api_key(user_id, &ApiKeyAuth::hash_key(&key), name)
+        .await
+    {
+        Ok(id) => ApiKeyResponse::success(NewApiKey {
+            id,
+            key,
+            name: name.map(|n| n.to_string()),
+            created: Utc::now(),
+        }),
+        Err(e) => ApiKeyResponse::error(&format!("Could not create key: {}", e)),
+    }
+}
+
+#[rocket::get("/keys")]
+async fn list_keys(auth: Nip98Auth, db: &State<Database>) -> ApiKeyResponse<Vec<ApiKey>> {
+    if auth.delegator.is_some() {
+        return ApiKeyResponse::forbidden("Delegated auth can not be used to manage API keys");
+    }
+    let pubkey_vec = auth.pubkey().to_bytes().to_vec();
+    let user_id = match db.get_user_id(&pubkey_vec).await {
+        Ok(u) => u,
+        Err(_) => return ApiKeyResponse::success(vec![]),
+    };
+    match db.list_api_keys(user_id).await {
+        Ok(keys) => ApiKeyResponse::success(keys),
+        Err(e) => ApiKeyResponse::error(&format!("Could not list keys: {}", e)),
+    }
+}
+
+#[rocket::delete("/keys/<id>")]
+async fn delete_key(auth: Nip98Auth, id: u64, db: &State<Database>) -> ApiKeyResponse<()> {
+    if auth.delegator.is_some() {
+        return ApiKeyResponse::forbidden("Delegated auth can not be used to manage API keys");
+    }
+    let pubkey_vec = auth.pubkey().to_bytes().to_vec();
+    let user_id = match db.get_user_id(&pubkey_vec).await {
+        Ok(u) => u,
+        Err(_) => return ApiKeyResponse::error("User not found"),
+    };
+    match db.delete_api_key(user_id, id).await {
+        Ok(0) => ApiKeyResponse::error("Key not found"),
+        Ok(_) => ApiKeyResponse::success(()),
+        Err(e) => ApiKeyResponse::error(&format!("Could not delete key: {}", e)),
+    }
+}
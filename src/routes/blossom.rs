@@ -1,20 +1,30 @@
 use std::collections::HashMap;
-use std::fs;
 
-use log::error;
+use anyhow::Error;
+use chrono::Utc;
+use log::{error, info};
 use nostr::prelude::hex;
 use nostr::{Alphabet, SingleLetterTag, TagKind};
 use rocket::data::ByteUnit;
+use rocket::fairing::{Fairing, Info, Kind};
 use rocket::http::{Header, Status};
 use rocket::response::Responder;
 use rocket::serde::json::Json;
 use rocket::{routes, Data, Request, Response, Route, State};
 use serde::{Deserialize, Serialize};
 
-use crate::auth::blossom::BlossomAuth;
-use crate::db::{Database, FileUpload};
-use crate::filesystem::FileStore;
-use crate::routes::{delete_file, Nip94Event};
+use crate::auth::blossom::{AuthFailure, BlossomAuth};
+use crate::auth::request::RequestAuth;
+use crate::db::{Database, FileUpload, FileVariant};
+use crate::filesystem::{FileStore, UploadError};
+use crate::idempotency::{request_hash, Idempotency, IdempotencyGuard, IdempotencyState};
+use crate::progress::ProgressData;
+#[cfg(feature = "media-compression")]
+use crate::routes::queue_processing;
+use crate::routes::{
+    check_file_limit, delete_file, discard_blob, get_upload_plan, save_upload, Nip94Event,
+    RequestError,
+};
 use crate::settings::Settings;
 use crate::webhook::Webhook;
 
@@ -40,17 +50,47 @@ impl BlobDescriptor {
             size: value.size,
             mime_type: Some(value.mime_type.clone()),
             created: value.created.timestamp() as u64,
-            nip94: Some(
-                Nip94Event::from_upload(settings, value)
-                    .tags
-                    .iter()
-                    .map(|r| (r[0].clone(), r[1].clone()))
-                    .collect(),
-            ),
+            nip94: Some(nip94_tags(Nip94Event::from_upload(settings, value))),
+        }
+    }
+
+    /// Descriptor of the compressed version of an upload (BUD-05), the original
+    /// is referenced by the `ox` tag
+    pub fn from_variant(settings: &Settings, upload: &FileUpload, variant: &FileVariant) -> Self {
+        let id_hex = hex::encode(&variant.id);
+        Self {
+            url: format!("{}/variant/{}", settings.public_url, &id_hex),
+            sha256: id_hex,
+            size: variant.size,
+            mime_type: Some(variant.mime_type.clone()),
+            created: upload.created.timestamp() as u64,
+            nip94: Some(nip94_tags(
+                Nip94Event::from_upload(settings, upload).with_variant(settings, upload, variant),
+            )),
+        }
+    }
+
+    /// Descriptor returned for an upload, the compressed variant when there is one
+    fn from_result(
+        settings: &Settings,
+        upload: &FileUpload,
+        variant: Option<&FileVariant>,
+    ) -> Self {
+        match variant {
+            Some(v) => Self::from_variant(settings, upload, v),
+            None => Self::from_upload(settings, upload),
         }
     }
 }
 
+fn nip94_tags(event: Nip94Event) -> HashMap<String, String> {
+    event
+        .tags
+        .iter()
+        .map(|r| (r[0].clone(), r[1].clone()))
+        .collect()
+}
+
 #[derive(Serialize, Deserialize)]
 struct BlossomError {
     pub message: String,
@@ -72,37 +112,110 @@ impl BlossomError {
     }
 }
 
-#[derive(Responder)]
 enum BlossomResponse {
-    #[response(status = 500)]
-    GenericError(Json<BlossomError>),
-
-    #[response(status = 200)]
+    Error(Status, String),
     BlobDescriptor(Json<BlobDescriptor>),
-
-    #[response(status = 200)]
     BlobDescriptorList(Json<Vec<BlobDescriptor>>),
-
     StatusOnly(Status),
 }
 
 impl BlossomResponse {
     pub fn error(msg: impl Into<String>) -> Self {
-        Self::GenericError(Json(BlossomError::new(msg.into())))
+        Self::Error(Status::InternalServerError, msg.into())
+    }
+
+    pub fn bad_request(msg: impl Into<String>) -> Self {
+        Self::Error(Status::BadRequest, msg.into())
+    }
+
+    pub fn unauthorized(msg: impl Into<String>) -> Self {
+        Self::Error(Status::Unauthorized, msg.into())
+    }
+
+    pub fn forbidden(msg: impl Into<String>) -> Self {
+        Self::Error(Status::Forbidden, msg.into())
+    }
+
+    pub fn too_large(msg: impl Into<String>) -> Self {
+        Self::Error(Status::PayloadTooLarge, msg.into())
+    }
+
+    /// Report an error with the status of its cause, unknown errors are server errors
+    pub fn from_error(e: &Error) -> Self {
+        Self::Error(error_status(e), format!("{:#}", e))
+    }
+}
+
+impl<'r> Responder<'r, 'static> for BlossomResponse {
+    fn respond_to(self, request: &'r Request<'_>) -> rocket::response::Result<'static> {
+        match self {
+            BlossomResponse::Error(status, msg) => {
+                let reason = x_reason(&msg);
+                Response::build_from(Json(BlossomError::new(msg)).respond_to(request)?)
+                    .status(status)
+                    .header(reason)
+                    .ok()
+            }
+            BlossomResponse::BlobDescriptor(d) => d.respond_to(request),
+            BlossomResponse::BlobDescriptorList(l) => l.respond_to(request),
+            BlossomResponse::StatusOnly(s) => s.respond_to(request),
+        }
+    }
+}
+
+/// Map errors caused by the request to their status code
+fn error_status(e: &Error) -> Status {
+    if let Some(r) = e.downcast_ref::<RequestError>() {
+        return r.status;
     }
+    match e.downcast_ref::<UploadError>() {
+        Some(UploadError::Banned) => Status::Forbidden,
+        Some(UploadError::ServerFull) => Status::InsufficientStorage,
+        Some(UploadError::NotAllowed(_)) => Status::UnsupportedMediaType,
+        Some(UploadError::Invalid(_)) => Status::BadRequest,
+        Some(UploadError::ShuttingDown) => Status::ServiceUnavailable,
+        None => Status::InternalServerError,
+    }
+}
+
+/// `X-Reason` header, header values can only contain visible ASCII
+fn x_reason(msg: &str) -> Header<'static> {
+    let value: String = msg
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() {
+                c
+            } else {
+                ' '
+            }
+        })
+        .collect();
+    Header::new("X-Reason", value)
 }
 
 struct BlossomHead {
-    pub msg: Option<&'static str>,
+    pub error: Option<(Status, &'static str)>,
+}
+
+impl BlossomHead {
+    fn ok() -> Self {
+        Self { error: None }
+    }
+
+    fn error(status: Status, msg: &'static str) -> Self {
+        Self {
+            error: Some((status, msg)),
+        }
+    }
 }
 
 impl<'r> Responder<'r, 'static> for BlossomHead {
     fn respond_to(self, _request: &'r Request<'_>) -> rocket::response::Result<'static> {
         let mut response = Response::new();
-        match self.msg {
-            Some(m) => {
-                response.set_status(Status::InternalServerError);
-                response.set_header(Header::new("x-upload-message", m));
+        match self.error {
+            Some((status, m)) => {
+                response.set_status(status);
+                response.set_header(x_reason(m));
             }
             None => {
                 response.set_status(Status::Ok);
@@ -112,6 +225,33 @@ impl<'r> Responder<'r, 'static> for BlossomHead {
     }
 }
 
+/// Adds an `X-Reason` header to error responses which did not set one,
+/// such as requests rejected by the auth guards
+pub struct BlossomReason;
+
+#[rocket::async_trait]
+impl Fairing for BlossomReason {
+    fn info(&self) -> Info {
+        Info {
+            name: "Blossom X-Reason header",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, response: &mut Response<'r>) {
+        let status = response.status();
+        if status.code < 400 || response.headers().contains("X-Reason") {
+            return;
+        }
+        let reason = req
+            .local_cache(|| AuthFailure(None))
+            .0
+            .or(status.reason())
+            .unwrap_or("Request failed");
+        response.set_header(x_reason(reason));
+    }
+}
+
 fn check_method(event: &nostr::Event, method: &str) -> bool {
     if let Some(t) = event.tags.iter().find_map(|t| {
         if t.kind() == TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::T)) {
@@ -125,16 +265,34 @@ fn check_method(event: &nostr::Event, method: &str) -> bool {
     false
 }
 
+/// Check the blob hash against the `x` tags of the auth event
+fn check_hash(event: &nostr::Event, sha256: &str) -> bool {
+    event.tags.iter().any(|t| {
+        t.kind() == TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::X))
+            && t.content().is_some_and(|x| x.eq_ignore_ascii_case(sha256))
+    })
+}
+
 #[rocket::delete("/<sha256>")]
 async fn delete_blob(
     sha256: &str,
-    auth: BlossomAuth,
+    auth: RequestAuth<BlossomAuth>,
     fs: &State<FileStore>,
     db: &State<Database>,
+    settings: &State<Settings>,
 ) -> BlossomResponse {
-    match delete_file(sha256, &auth.event, fs, db).await {
+    let pubkey = match auth.user() {
+        Some(p) => p,
+        None => return BlossomResponse::unauthorized("Auth header not found"),
+    };
+    if let Some(ev) = auth.event() {
+        if !check_method(ev, "delete") {
+            return BlossomResponse::unauthorized("Invalid request method tag");
+        }
+    }
+    match delete_file(sha256, &pubkey, fs, db, settings).await {
         Ok(()) => BlossomResponse::StatusOnly(Status::Ok),
-        Err(e) => BlossomResponse::error(format!("Failed to delete file: {}", e)),
+        Err(e) => BlossomResponse::from_error(&e.context("Failed to delete file")),
     }
 }
 
@@ -147,7 +305,7 @@ async fn list_files(
     let id = if let Ok(i) = hex::decode(pubkey) {
         i
     } else {
-        return BlossomResponse::error("invalid pubkey");
+        return BlossomResponse::bad_request("invalid pubkey");
     };
     match db.list_files(&id, 0, 10_000).await {
         Ok((files, _count)) => BlossomResponse::BlobDescriptorList(Json(
@@ -161,173 +319,378 @@ async fn list_files(
 }
 
 #[rocket::head("/upload")]
-async fn upload_head(auth: BlossomAuth, settings: &State<Settings>) -> BlossomHead {
+async fn upload_head(
+    auth: BlossomAuth,
+    fs: &State<FileStore>,
+    db: &State<Database>,
+    settings: &State<Settings>,
+) -> BlossomHead {
     if !check_method(&auth.event, "upload") {
-        return BlossomHead {
-            msg: Some("Invalid auth method tag"),
-        };
+        return BlossomHead::error(Status::Unauthorized, "Invalid auth method tag");
     }
 
+    let pubkey_vec = auth.pubkey().to_bytes().to_vec();
+    let plan = match get_upload_plan(&pubkey_vec, db, settings).await {
+        Some(p) => p,
+        None => return BlossomHead::error(Status::Forbidden, "No upload plan available"),
+    };
+    if check_file_limit(
+        &pubkey_vec,
+        Some(&plan),
+        auth.x_content_length.unwrap_or(0),
+        db,
+        settings,
+    )
+    .await
+    .is_err()
+    {
+        return BlossomHead::error(Status::Forbidden, "File limit reached");
+    }
+    let max_upload_bytes = plan.max_upload_bytes.min(settings.max_upload_bytes);
     if let Some(z) = auth.x_content_length {
-        if z > settings.max_upload_bytes {
-            return BlossomHead {
-                msg: Some("File too large"),
-            };
+        if z > max_upload_bytes {
+            return BlossomHead::error(Status::PayloadTooLarge, "File too large");
+        }
+        if fs.check_free_space(z).is_err() {
+            return BlossomHead::error(Status::InsufficientStorage, "Server is full");
         }
     } else {
-        return BlossomHead {
-            msg: Some("Missing x-content-length header"),
-        };
+        return BlossomHead::error(Status::BadRequest, "Missing x-content-length header");
     }
 
-    if auth.x_sha_256.is_none() {
-        return BlossomHead {
-            msg: Some("Missing x-sha-256 header"),
-        };
+    match &auth.x_sha_256 {
+        Some(x) => {
+            if !check_hash(&auth.event, x) {
+                return BlossomHead::error(
+                    Status::Unauthorized,
+                    "Auth event x tag does not match x-sha-256 header",
+                );
+            }
+        }
+        None => return BlossomHead::error(Status::BadRequest, "Missing x-sha-256 header"),
     }
 
     if auth.x_content_type.is_none() {
-        return BlossomHead {
-            msg: Some("Missing x-content-type header"),
-        };
+        return BlossomHead::error(Status::BadRequest, "Missing x-content-type header");
     }
 
     // check whitelist
     if let Some(wl) = &settings.whitelist {
-        if !wl.contains(&auth.event.pubkey.to_hex()) {
-            return BlossomHead {
-                msg: Some("Not on whitelist"),
-            };
+        if !wl.contains(&auth.pubkey().to_hex()) {
+            return BlossomHead::error(Status::Forbidden, "Not on whitelist");
         }
     }
 
-    BlossomHead { msg: None }
+    BlossomHead::ok()
 }
 
 #[rocket::put("/upload", data = "<data>")]
 async fn upload(
-    auth: BlossomAuth,
+    auth: RequestAuth<BlossomAuth>,
     fs: &State<FileStore>,
     db: &State<Database>,
     settings: &State<Settings>,
     webhook: &State<Option<Webhook>>,
-    data: Data<'_>,
+    idempotency: Idempotency,
+    data: ProgressData<'_>,
 ) -> BlossomResponse {
-    process_upload("upload", false, auth, fs, db, settings, webhook, data).await
+    let guard = match idempotency.begin(
+        &auth.idempotency_scope(),
+        event_key(&auth),
+        upload_hash("upload", &auth),
+    ) {
+        Some(IdempotencyState::New(g)) => Some(g),
+        Some(s) => return retry_response(settings, s),
+        None => None,
+    };
+    let res = process_upload("upload", auth, fs, db, settings, webhook, data).await;
+    upload_response(settings, guard, res)
 }
 
+/// Upload a blob which is compressed / transcoded (BUD-05), the original is kept
 #[cfg(feature = "media-compression")]
 #[rocket::put("/media", data = "<data>")]
 async fn upload_media(
-    auth: BlossomAuth,
+    auth: RequestAuth<BlossomAuth>,
     fs: &State<FileStore>,
     db: &State<Database>,
     settings: &State<Settings>,
     webhook: &State<Option<Webhook>>,
-    data: Data<'_>,
+    idempotency: Idempotency,
+    data: ProgressData<'_>,
 ) -> BlossomResponse {
-    process_upload("media", true, auth, fs, db, settings, webhook, data).await
+    let guard = match idempotency.begin(
+        &auth.idempotency_scope(),
+        event_key(&auth),
+        upload_hash("media", &auth),
+    ) {
+        Some(IdempotencyState::New(g)) => Some(g),
+        Some(s) => return retry_response(settings, s),
+        None => None,
+    };
+    let res = process_upload("media", auth, fs, db, settings, webhook, data).await;
+    upload_response(settings, guard, res)
 }
 
-async fn process_upload(
-    method: &str,
-    compress: bool,
-    auth: BlossomAuth,
-    fs: &State<FileStore>,
-    db: &State<Database>,
-    settings: &State<Settings>,
-    webhook: &State<Option<Webhook>>,
-    data: Data<'_>,
+/// Retries return the original response, the auth event id is used as the idempotency key
+/// when the event is only valid for a single blob
+fn event_key(auth: &RequestAuth<BlossomAuth>) -> Option<String> {
+    auth.event()
+        .filter(|ev| {
+            ev.tags
+                .iter()
+                .filter(|t| {
+                    t.kind() == TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::X))
+                })
+                .count()
+                == 1
+        })
+        .map(|ev| ev.id.to_hex())
+}
+
+/// Idempotency keys are bound to the endpoint, blob type, size and hash of the upload
+fn upload_hash(method: &str, auth: &RequestAuth<BlossomAuth>) -> [u8; 32] {
+    let x = auth
+        .event()
+        .and_then(|ev| {
+            ev.tags.iter().find_map(|t| {
+                if t.kind() == TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::X)) {
+                    t.content()
+                } else {
+                    None
+                }
+            })
+        })
+        .unwrap_or_default();
+    request_hash(&[
+        method,
+        &auth.content_type().unwrap_or_default(),
+        &auth.content_length().unwrap_or(0).to_string(),
+        x,
+    ])
+}
+
+/// Response for a retried upload which is in progress or already done
+fn retry_response(settings: &Settings, state: IdempotencyState) -> BlossomResponse {
+    match state {
+        IdempotencyState::Done(r) => BlossomResponse::BlobDescriptor(Json(
+            BlobDescriptor::from_result(settings, &r.upload, r.variant.as_ref()),
+        )),
+        IdempotencyState::Mismatch => BlossomResponse::Error(
+            Status::UnprocessableEntity,
+            "Idempotency-Key was used for a different upload".to_string(),
+        ),
+        _ => BlossomResponse::Error(
+            Status::Conflict,
+            "An upload with this Idempotency-Key is in progress".to_string(),
+        ),
+    }
+}
+
+/// Response for a stored upload, the result is kept for retries with the same idempotency key
+fn upload_response(
+    settings: &Settings,
+    guard: Option<IdempotencyGuard>,
+    res: Result<(FileUpload, Option<FileVariant>), BlossomResponse>,
 ) -> BlossomResponse {
-    if !check_method(&auth.event, method) {
-        return BlossomResponse::error("Invalid request method tag");
+    match res {
+        Ok((upload, variant)) => {
+            if let Some(g) = guard {
+                g.complete(&upload, variant.as_ref());
+            }
+            BlossomResponse::BlobDescriptor(Json(BlobDescriptor::from_result(
+                settings,
+                &upload,
+                variant.as_ref(),
+            )))
+        }
+        Err(r) => r,
     }
+}
 
-    let name = auth.event.tags.iter().find_map(|t| {
-        if t.kind() == TagKind::Name {
-            t.content()
-        } else {
-            None
+/// Store an uploaded blob, `method` is the endpoint ("upload" / "media") which must match
+/// the `t` tag of the auth event, blobs uploaded to "media" are compressed and
+/// returned with their compressed variant
+async fn process_upload(
+    method: &str,
+    auth: RequestAuth<BlossomAuth>,
+    fs: &FileStore,
+    db: &Database,
+    settings: &Settings,
+    webhook: &Option<Webhook>,
+    data: ProgressData<'_>,
+) -> Result<(FileUpload, Option<FileVariant>), BlossomResponse> {
+    if let Some(ev) = auth.event() {
+        if !check_method(ev, method) {
+            return Err(BlossomResponse::unauthorized("Invalid request method tag"));
         }
-    });
-    let size = auth.event.tags.iter().find_map(|t| {
-        if t.kind() == TagKind::Size {
-            t.content().and_then(|v| v.parse::<u64>().ok())
-        } else {
-            None
+    }
+    if let RequestAuth::Anonymous(a) = &auth {
+        // anonymous uploads must always be checked by the webhook
+        if webhook.is_none() {
+            return Err(BlossomResponse::unauthorized(
+                "Anonymous uploads are not available",
+            ));
         }
+        if !a.check_rate_limit() {
+            return Err(BlossomResponse::Error(
+                Status::TooManyRequests,
+                "Rate limit exceeded".to_string(),
+            ));
+        }
+    }
+
+    let name = auth.event().and_then(|ev| {
+        ev.tags.iter().find_map(|t| {
+            if t.kind() == TagKind::Name {
+                t.content()
+            } else {
+                None
+            }
+        })
     });
+    let size = auth.content_length();
+    let plan = match &auth {
+        RequestAuth::Anonymous(_) => None,
+        _ => match get_upload_plan(&auth.pubkey(), db, settings).await {
+            Some(p) => Some(p),
+            None => return Err(BlossomResponse::forbidden("No upload plan available")),
+        },
+    };
+    if let (RequestAuth::ApiKey(_), Some(p)) = (&auth, &plan) {
+        if p.require_nostr_auth.unwrap_or(false) {
+            return Err(BlossomResponse::unauthorized(
+                "Upload plan requires nostr auth",
+            ));
+        }
+    }
+    if let Err(e) = check_file_limit(
+        &auth.pubkey(),
+        plan.as_ref(),
+        size.unwrap_or(0),
+        db,
+        settings,
+    )
+    .await
+    {
+        return Err(BlossomResponse::from_error(&e));
+    }
+    let max_upload_bytes = match (&plan, &settings.anonymous_uploads) {
+        (Some(p), _) => p.max_upload_bytes.min(settings.max_upload_bytes),
+        (None, Some(a)) => a.max_upload_bytes,
+        (None, None) => settings.max_upload_bytes,
+    };
     if let Some(z) = size {
-        if z > settings.max_upload_bytes {
-            return BlossomResponse::error("File too large");
+        if z > max_upload_bytes {
+            return Err(BlossomResponse::too_large("File too large"));
         }
     }
+    // without a declared size the upload can be as large as the limit
+    if let Err(e) = fs.check_free_space(size.unwrap_or(max_upload_bytes)) {
+        return Err(BlossomResponse::from_error(&e));
+    }
     let mime_type = auth
-        .content_type
+        .content_type()
         .unwrap_or("application/octet-stream".to_string());
 
     // check whitelist
     if let Some(wl) = &settings.whitelist {
-        if !wl.contains(&auth.event.pubkey.to_hex()) {
-            return BlossomResponse::error("Not on whitelist");
+        if !matches!(auth, RequestAuth::Anonymous(_)) && !wl.contains(&hex::encode(auth.pubkey())) {
+            return Err(BlossomResponse::forbidden("Not on whitelist"));
         }
     }
+    let progress = data.progress.clone();
     match fs
-        .put(
-            data.open(ByteUnit::from(settings.max_upload_bytes)),
-            &mime_type,
-            compress,
-        )
+        .put(data.open(ByteUnit::from(max_upload_bytes)), &mime_type)
         .await
     {
         Ok(mut blob) => {
+            if let RequestAuth::Nostr(a) = &auth {
+                // auth event must be signed for this exact blob
+                if !check_hash(&a.event, &hex::encode(&blob.original_hash)) {
+                    discard_blob(db, &blob).await;
+                    return Err(BlossomResponse::unauthorized(
+                        "Auth event x tag does not match blob hash",
+                    ));
+                }
+            }
             blob.upload.name = name.unwrap_or("").to_owned();
 
-            let pubkey_vec = auth.event.pubkey.to_bytes().to_vec();
+            if let (RequestAuth::Anonymous(a), Some(anon)) = (&auth, &settings.anonymous_uploads) {
+                info!("Anonymous upload from {}", a.ip);
+                blob.upload.expires =
+                    Some(Utc::now() + chrono::Duration::days(anon.ttl_days as i64));
+            }
+            if let Some(days) = plan.as_ref().and_then(|p| p.expiration_days) {
+                blob.upload.expires = Some(Utc::now() + chrono::Duration::days(days as i64));
+            }
+            let pubkey_vec = auth.pubkey();
+            let delegate = match &auth {
+                RequestAuth::Nostr(a) if a.delegator.is_some() => {
+                    Some(a.event.pubkey.to_bytes().to_vec())
+                }
+                _ => None,
+            };
             if let Some(wh) = webhook.as_ref() {
                 match wh.store_file(&pubkey_vec, blob.clone()).await {
                     Ok(store) => {
                         if !store {
-                            let _ = fs::remove_file(blob.path);
-                            return BlossomResponse::error("Upload rejected");
+                            discard_blob(db, &blob).await;
+                            return Err(BlossomResponse::forbidden("Upload rejected"));
                         }
                     }
                     Err(e) => {
-                        let _ = fs::remove_file(blob.path);
-                        return BlossomResponse::error(format!(
+                        discard_blob(db, &blob).await;
+                        return Err(BlossomResponse::error(format!(
                             "Internal error, failed to call webhook: {}",
                             e
-                        ));
+                        )));
                     }
                 }
             }
             let user_id = match db.upsert_user(&pubkey_vec).await {
                 Ok(u) => u,
                 Err(e) => {
-                    return BlossomResponse::error(format!("Failed to save file (db): {}", e));
+                    discard_blob(db, &blob).await;
+                    return Err(BlossomResponse::error(format!(
+                        "Failed to save file (db): {}",
+                        e
+                    )));
                 }
             };
-            if let Err(e) = db.add_file(&blob.upload, user_id).await {
-                error!("{}", e.to_string());
-                let _ = fs::remove_file(blob.path);
-                if let Some(dbe) = e.as_database_error() {
-                    if let Some(c) = dbe.code() {
-                        if c == "23000" {
-                            return BlossomResponse::error("File already exists");
-                        }
-                    }
-                }
-                BlossomResponse::error(format!("Error saving file (db): {}", e))
+            if let Err(e) = save_upload(db, &mut blob.upload, user_id, delegate.as_ref()).await {
+                error!("{}", e);
+                discard_blob(db, &blob).await;
+                Err(BlossomResponse::error(format!(
+                    "Error saving file (db): {}",
+                    e
+                )))
             } else {
-                BlossomResponse::BlobDescriptor(Json(BlobDescriptor::from_upload(
+                #[cfg(feature = "media-compression")]
+                let variant = queue_processing(
+                    fs,
+                    db,
                     settings,
-                    &blob.upload,
-                )))
+                    &mut blob.upload,
+                    method == "media",
+                    progress,
+                )
+                .await;
+                #[cfg(not(feature = "media-compression"))]
+                let variant = {
+                    if let Some(p) = progress {
+                        p.done();
+                    }
+                    None
+                };
+                Ok((blob.upload, variant))
             }
         }
         Err(e) => {
             error!("{}", e.to_string());
-            BlossomResponse::error(format!("Error saving file (disk): {}", e))
+            Err(BlossomResponse::from_error(
+                &e.context("Error saving file (disk)"),
+            ))
         }
     }
 }
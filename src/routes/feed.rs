@@ -0,0 +1,154 @@
+use quick_xml::escape::escape;
+use rocket::http::{ContentType, Status};
+use rocket::serde::json::Json;
+use rocket::{routes, Route, State};
+
+use crate::db::{Database, FileUpload};
+use crate::filesystem::FileStore;
+use crate::routes::{Nip94Event, PagedResult};
+use crate::settings::Settings;
+
+/// Number of files on each page of the feed
+const PAGE_SIZE: u32 = 50;
+
+pub fn feed_routes() -> Vec<Route> {
+    routes![feed_html, feed_json, feed_rss]
+}
+
+/// Load a page of the public feed, 404 when the feed is disabled
+async fn load_page(
+    page: u32,
+    fs: &FileStore,
+    db: &Database,
+    settings: &Settings,
+) -> Result<(Vec<FileUpload>, u32), Status> {
+    if !settings.public_feed.unwrap_or(false) {
+        return Err(Status::NotFound);
+    }
+    let (files, total) = db
+        .list_public_files(page * PAGE_SIZE, PAGE_SIZE)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    Ok((
+        files.into_iter().filter(|f| !fs.is_banned(&f.id)).collect(),
+        total as u32,
+    ))
+}
+
+fn file_url(settings: &Settings, f: &FileUpload) -> String {
+    format!("{}/{}", settings.public_url, hex::encode(&f.id))
+}
+
+fn file_title(f: &FileUpload) -> String {
+    if f.name.is_empty() {
+        hex::encode(&f.id)
+    } else {
+        f.name.clone()
+    }
+}
+
+#[rocket::get("/feed.json?<page>")]
+async fn feed_json(
+    page: Option<u32>,
+    fs: &State<FileStore>,
+    db: &State<Database>,
+    settings: &State<Settings>,
+) -> Result<Json<PagedResult<Nip94Event>>, Status> {
+    let page = page.unwrap_or(0);
+    let (files, total) = load_page(page, fs, db, settings).await?;
+    Ok(Json(PagedResult {
+        count: PAGE_SIZE,
+        page,
+        total,
+        files: files
+            .iter()
+            .map(|f| Nip94Event::from_upload(settings, f))
+            .collect(),
+    }))
+}
+
+#[rocket::get("/feed.rss")]
+async fn feed_rss(
+    fs: &State<FileStore>,
+    db: &State<Database>,
+    settings: &State<Settings>,
+) -> Result<(ContentType, String), Status> {
+    let (files, _) = load_page(0, fs, db, settings).await?;
+    let mut items = String::new();
+    for f in &files {
+        let url = file_url(settings, f);
+        items.push_str(&format!(
+            "<item><title>{}</title><link>{}</link><guid>{}</guid><pubDate>{}</pubDate>\
+            <enclosure url=\"{}\" length=\"{}\" type=\"{}\"/></item>",
+            escape(&file_title(f)),
+            escape(&url),
+            escape(&url),
+            f.created.to_rfc2822(),
+            escape(&url),
+            f.size,
+            escape(&f.mime_type)
+        ));
+    }
+    let feed = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+        <rss version=\"2.0\"><channel><title>route96</title><link>{}/feed</link>\
+        <description>Recent public uploads</description>{}</channel></rss>",
+        escape(&settings.public_url),
+        items
+    );
+    Ok((ContentType::new("application", "rss+xml"), feed))
+}
+
+#[rocket::get("/feed?<page>")]
+async fn feed_html(
+    page: Option<u32>,
+    fs: &State<FileStore>,
+    db: &State<Database>,
+    settings: &State<Settings>,
+) -> Result<(ContentType, String), Status> {
+    let page = page.unwrap_or(0);
+    let (files, total) = load_page(page, fs, db, settings).await?;
+    let mut items = String::new();
+    for f in &files {
+        let url = escape(&file_url(settings, f)).to_string();
+        let title = escape(&file_title(f)).to_string();
+        let preview = if f.thumb_mime.is_some() {
+            format!(
+                "<img src=\"{}/thumb/{}\" alt=\"{}\" loading=\"lazy\">",
+                escape(&settings.public_url),
+                hex::encode(&f.id),
+                title
+            )
+        } else if f.mime_type.starts_with("image/") {
+            format!("<img src=\"{}\" alt=\"{}\" loading=\"lazy\">", url, title)
+        } else if f.mime_type.starts_with("video/") {
+            format!("<video src=\"{}\" preload=\"metadata\" muted></video>", url)
+        } else {
+            format!("<audio src=\"{}\" preload=\"none\" controls></audio>", url)
+        };
+        items.push_str(&format!(
+            "<a class=\"item\" href=\"{}\" title=\"{}\">{}</a>",
+            url, title, preview
+        ));
+    }
+    let mut nav = String::new();
+    if page > 0 {
+        nav.push_str(&format!("<a href=\"?page={}\">Newer</a>", page - 1));
+    }
+    if (page + 1) * PAGE_SIZE < total {
+        nav.push_str(&format!("<a href=\"?page={}\">Older</a>", page + 1));
+    }
+    let html = format!(
+        "<!doctype html><html><head><meta charset=\"utf-8\">\
+        <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+        <title>Public uploads</title>\
+        <link rel=\"alternate\" type=\"application/rss+xml\" href=\"feed.rss\">\
+        <style>body{{background:#111;color:#eee;font-family:sans-serif;margin:1em}}\
+        .grid{{display:grid;grid-template-columns:repeat(auto-fill,minmax(200px,1fr));gap:8px}}\
+        .item img,.item video,.item audio{{width:100%;height:200px;object-fit:cover;background:#222}}\
+        nav a{{color:#eee;margin-right:1em}}</style></head>\
+        <body><h1>Public uploads</h1><div class=\"grid\">{}</div><nav>{}</nav></body></html>",
+        items, nav
+    );
+    Ok((ContentType::HTML, html))
+}
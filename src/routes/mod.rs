@@ -1,24 +1,52 @@
-use std::fs;
+use std::fmt::{Display, Formatter};
 use std::fs::File;
+use std::io::{Seek, SeekFrom};
+use std::net::IpAddr;
 use std::str::FromStr;
 
-use crate::db::{Database, FileUpload};
-use crate::filesystem::FileStore;
+use crate::auth::anonymous::ANONYMOUS_PUBKEY;
+#[cfg(feature = "media-compression")]
+use crate::db::JobKind;
+use crate::db::{
+    AuditAction, Database, FileUpload, FileVariant, NewAuditEntry, StorageClass, VariantKind,
+};
+use crate::filesystem::{FileStore, FileSystemResult};
+use crate::geoip::GeoIp;
+#[cfg(feature = "media-compression")]
+use crate::processing::is_pdf;
+#[cfg(feature = "labels")]
+use crate::processing::queue::label_file;
+use crate::progress::{ProgressHandle, UploadProgressTracker};
+pub use crate::routes::account::account_routes;
 pub use crate::routes::admin::admin_routes;
+#[cfg(feature = "admin-ui")]
+pub use crate::routes::admin_ui::admin_ui_routes;
+pub use crate::routes::api_keys::api_key_routes;
 #[cfg(feature = "blossom")]
-pub use crate::routes::blossom::blossom_routes;
+pub use crate::routes::blossom::{blossom_routes, BlossomReason};
+pub use crate::routes::feed::feed_routes;
+pub use crate::routes::multipart::{multipart_routes, MultipartUploads};
 #[cfg(feature = "nip96")]
-pub use crate::routes::nip96::nip96_routes;
-use crate::settings::Settings;
+pub use crate::routes::nip96::{nip96_api_routes, nip96_routes};
+pub use crate::routes::portal::portal_routes;
+pub use crate::routes::report::report_routes;
+use crate::settings::{PlanSettings, Settings};
+use crate::stats::DownloadStats;
+#[cfg(feature = "media-compression")]
+use crate::svg::is_svg_mime;
+use crate::throttle::ThrottledReader;
+use crate::tiering::StorageTiering;
 #[cfg(feature = "void-cat-redirects")]
 use crate::void_db::VoidCatDb;
 use anyhow::Error;
-use nostr::Event;
+use log::warn;
+use nostr::{PublicKey, ToBech32};
 use rocket::fs::NamedFile;
 use rocket::http::{ContentType, Header, Status};
-#[cfg(feature = "void-cat-redirects")]
+use rocket::response::stream::{Event as StreamEvent, EventStream};
 use rocket::response::Redirect;
-use rocket::response::Responder;
+use rocket::response::{Responder, Response};
+use rocket::serde::json::Json;
 use rocket::serde::Serialize;
 use rocket::{Request, State};
 
@@ -27,11 +55,56 @@ mod blossom;
 #[cfg(feature = "nip96")]
 mod nip96;
 
+mod account;
 mod admin;
+#[cfg(feature = "admin-ui")]
+mod admin_ui;
+mod api_keys;
+mod feed;
+mod multipart;
+mod portal;
+mod report;
 
 pub struct FilePayload {
     pub file: File,
     pub info: FileUpload,
+    /// Limit the download speed to this many bytes/s
+    pub throttle: Option<u64>,
+    /// Counts the bytes sent
+    pub stats: DownloadStats,
+}
+
+/// Parse a single `Range: bytes=` header into an inclusive byte range of a file of `size` bytes,
+/// [None] when the header should be ignored and the whole file served
+fn parse_range(header: &str, size: u64) -> Result<Option<(u64, u64)>, ()> {
+    let spec = match header.trim().strip_prefix("bytes=") {
+        // multiple ranges are not supported, serve the whole file
+        Some(s) if !s.contains(',') => s.trim(),
+        _ => return Ok(None),
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(r) => r,
+        None => return Ok(None),
+    };
+    let range = match (start.trim(), end.trim()) {
+        ("", "") => return Ok(None),
+        ("", n) => {
+            let n: u64 = n.parse().map_err(|_| ())?;
+            if n == 0 {
+                return Err(());
+            }
+            (size.saturating_sub(n), size.saturating_sub(1))
+        }
+        (s, "") => (s.parse().map_err(|_| ())?, size.saturating_sub(1)),
+        (s, e) => {
+            let e: u64 = e.parse().map_err(|_| ())?;
+            (s.parse().map_err(|_| ())?, e.min(size.saturating_sub(1)))
+        }
+    };
+    if size == 0 || range.0 > range.1 || range.0 >= size {
+        return Err(());
+    }
+    Ok(Some(range))
 }
 
 #[derive(Clone, Debug, Serialize, Default)]
@@ -42,6 +115,30 @@ struct Nip94Event {
     pub tags: Vec<Vec<String>>,
 }
 
+/// Error caused by the request itself, reported with `status` instead of a server error
+#[derive(Debug)]
+struct RequestError {
+    pub status: Status,
+    pub message: String,
+}
+
+impl RequestError {
+    pub fn new(status: Status, msg: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: msg.into(),
+        }
+    }
+}
+
+impl Display for RequestError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for RequestError {}
+
 #[derive(Serialize, Default)]
 #[serde(crate = "rocket::serde")]
 struct PagedResult<T> {
@@ -63,12 +160,24 @@ impl Nip94Event {
             vec!["m".to_string(), upload.mime_type.clone()],
             vec!["size".to_string(), upload.size.to_string()],
         ];
+        if let Some(ox) = &upload.original_hash {
+            tags.push(vec!["ox".to_string(), hex::encode(ox)]);
+        }
         if let Some(bh) = &upload.blur_hash {
             tags.push(vec!["blurhash".to_string(), bh.clone()]);
         }
         if let (Some(w), Some(h)) = (upload.width, upload.height) {
             tags.push(vec!["dim".to_string(), format!("{}x{}", w, h)])
         }
+        if let Some(d) = upload.duration {
+            tags.push(vec!["duration".to_string(), d.to_string()]);
+        }
+        if upload.thumb_mime.is_some() {
+            tags.push(vec![
+                "thumb".to_string(),
+                format!("{}/thumb/{}", &settings.public_url, hex::encode(&upload.id)),
+            ]);
+        }
         #[cfg(feature = "labels")]
         for l in &upload.labels {
             let val = if l.label.contains(',') {
@@ -86,11 +195,192 @@ impl Nip94Event {
             tags,
         }
     }
+
+    /// Describe the compressed `variant` of the upload instead of the upload itself,
+    /// `ox` is the hash of the original which is still served at its own url
+    pub fn with_variant(
+        mut self,
+        settings: &Settings,
+        upload: &FileUpload,
+        variant: &FileVariant,
+    ) -> Self {
+        let hex_id = hex::encode(&variant.id);
+        let original = upload.original_hash.as_ref().unwrap_or(&upload.id);
+        let mut tags = vec![
+            vec![
+                "url".to_string(),
+                format!("{}/variant/{}", &settings.public_url, &hex_id),
+            ],
+            vec!["x".to_string(), hex_id],
+            vec!["m".to_string(), variant.mime_type.clone()],
+            vec!["size".to_string(), variant.size.to_string()],
+            vec!["ox".to_string(), hex::encode(original)],
+        ];
+        if let (Some(w), Some(h)) = (variant.width, variant.height) {
+            tags.push(vec!["dim".to_string(), format!("{}x{}", w, h)])
+        }
+        self.tags
+            .retain(|t| !matches!(t[0].as_str(), "url" | "x" | "m" | "size" | "ox" | "dim"));
+        tags.append(&mut self.tags);
+        self.tags = tags;
+        self
+    }
 }
 
-impl<'r> Responder<'r, 'static> for FilePayload {
+/// Metadata of a single blob, returned by `/meta/<sha256>`
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct FileMetadata {
+    pub sha256: String,
+    /// BLAKE3 digest, only stored when enabled in the settings
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blake3: Option<String>,
+    pub size: u64,
+    #[serde(rename = "type")]
+    pub mime_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blurhash: Option<String>,
+    /// Duration of audio / video files in seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f32>,
+    pub labels: Vec<String>,
+    pub created: i64,
+    /// npub of the first uploader, not set for anonymous uploads
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uploader: Option<String>,
+    pub downloads: u64,
+    /// Time of the last download, downloads are counted in batches
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_accessed: Option<i64>,
+    /// Thumbnails, transcodes and conversions of this file
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub variants: Vec<VariantMetadata>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct VariantMetadata {
+    #[serde(flatten)]
+    pub variant: FileVariant,
+    pub url: String,
+}
+
+impl VariantMetadata {
+    pub fn new(settings: &Settings, variant: FileVariant) -> Self {
+        Self {
+            url: format!(
+                "{}/variant/{}",
+                settings.public_url,
+                hex::encode(&variant.id)
+            ),
+            variant,
+        }
+    }
+}
+
+impl FileMetadata {
+    /// Metadata as `X-` response headers, so it can be read with a HEAD request
+    fn headers(&self) -> Vec<Header<'static>> {
+        let mut headers = vec![
+            Header::new("X-Sha-256", self.sha256.clone()),
+            Header::new("X-Content-Length", self.size.to_string()),
+            Header::new("X-Content-Type", self.mime_type.clone()),
+            Header::new("X-Created", self.created.to_string()),
+        ];
+        if let Some(b) = &self.blake3 {
+            headers.push(Header::new("X-Blake3", b.clone()));
+        }
+        if let (Some(w), Some(h)) = (self.width, self.height) {
+            headers.push(Header::new("X-Dimensions", format!("{}x{}", w, h)));
+        }
+        if let Some(bh) = &self.blurhash {
+            headers.push(Header::new("X-Blurhash", bh.clone()));
+        }
+        if let Some(d) = self.duration {
+            headers.push(Header::new("X-Duration", d.to_string()));
+        }
+        if !self.labels.is_empty() {
+            let labels: String = self
+                .labels
+                .join(",")
+                .chars()
+                .filter(|c| c.is_ascii() && !c.is_ascii_control())
+                .collect();
+            headers.push(Header::new("X-Labels", labels));
+        }
+        if let Some(u) = &self.uploader {
+            headers.push(Header::new("X-Uploader", u.clone()));
+        }
+        headers.push(Header::new("X-Downloads", self.downloads.to_string()));
+        if let Some(la) = self.last_accessed {
+            headers.push(Header::new("X-Last-Accessed", la.to_string()));
+        }
+        headers
+    }
+}
+
+impl<'r> Responder<'r, 'static> for FileMetadata {
     fn respond_to(self, request: &'r Request<'_>) -> rocket::response::Result<'static> {
-        let mut response = self.file.respond_to(request)?;
+        let headers = self.headers();
+        let mut response = Json(self).respond_to(request)?;
+        for h in headers {
+            response.set_header(h);
+        }
+        Ok(response)
+    }
+}
+
+impl<'r> Responder<'r, 'static> for FilePayload {
+    fn respond_to(mut self, request: &'r Request<'_>) -> rocket::response::Result<'static> {
+        let size = self
+            .file
+            .metadata()
+            .map_err(|_| Status::InternalServerError)?
+            .len();
+        let range = match request.headers().get_one("range") {
+            Some(r) => match parse_range(r, size) {
+                Ok(r) => r,
+                Err(()) => {
+                    return Response::build()
+                        .status(Status::RangeNotSatisfiable)
+                        .header(Header::new("content-range", format!("bytes */{}", size)))
+                        .ok();
+                }
+            },
+            None => None,
+        };
+        let (start, len) = match range {
+            Some((start, end)) => (start, end - start + 1),
+            None => (0, size),
+        };
+        if start > 0 {
+            self.file
+                .seek(SeekFrom::Start(start))
+                .map_err(|_| Status::InternalServerError)?;
+        }
+        let file = tokio::fs::File::from_std(self.file);
+        let mut response = Response::new();
+        match self.throttle {
+            Some(rate) => response.set_sized_body(
+                len as usize,
+                ThrottledReader::new(self.stats.reader(&self.info.id, file, len), rate),
+            ),
+            None => {
+                response.set_sized_body(len as usize, self.stats.reader(&self.info.id, file, len))
+            }
+        }
+        response.set_header(Header::new("accept-ranges", "bytes"));
+        if let Some((start, end)) = range {
+            response.set_status(Status::PartialContent);
+            response.set_header(Header::new(
+                "content-range",
+                format!("bytes {}-{}/{}", start, end, size),
+            ));
+        }
         if let Ok(ct) = ContentType::from_str(&self.info.mime_type) {
             response.set_header(ct);
         }
@@ -98,53 +388,312 @@ impl<'r> Responder<'r, 'static> for FilePayload {
             "content-disposition",
             format!("inline; filename=\"{}\"", self.info.name),
         ));
+        // files served from cold storage are reported as a cache miss
+        response.set_header(Header::new(
+            "x-cache",
+            match self.info.storage_class {
+                StorageClass::Hot => "HIT",
+                StorageClass::Cold => "MISS",
+            },
+        ));
         Ok(response)
     }
 }
 
-async fn delete_file(
-    sha256: &str,
-    auth: &Event,
+/// Get the upload plan of a user from their DB record, new users get the default plan
+async fn get_upload_plan(
+    pubkey: &Vec<u8>,
+    db: &Database,
+    settings: &Settings,
+) -> Option<PlanSettings> {
+    let plan = db.get_user(pubkey).await.ok().and_then(|u| u.plan);
+    settings.user_plan(plan.as_deref())
+}
+
+/// Check a user has not reached the maximum number of files or the storage quota of their plan,
+/// `size` is the size of the new upload when known. Anonymous uploads have no plan,
+/// the global file limit applies to all anonymous files together
+async fn check_file_limit(
+    pubkey: &Vec<u8>,
+    plan: Option<&PlanSettings>,
+    size: u64,
+    db: &Database,
+    settings: &Settings,
+) -> Result<(), Error> {
+    if let Some(limit) = plan
+        .and_then(|p| p.max_files)
+        .or(settings.max_files_per_user)
+    {
+        let count = db.count_user_files(pubkey).await?;
+        if count >= limit {
+            return Err(RequestError::new(
+                Status::Forbidden,
+                format!("File limit reached ({} files)", limit),
+            )
+            .into());
+        }
+    }
+    if let Some(limit) = plan.and_then(|p| p.max_storage_bytes) {
+        let used = db.get_user_storage(pubkey).await?;
+        if used.saturating_add(size) > limit {
+            return Err(RequestError::new(
+                Status::Forbidden,
+                format!("Storage quota reached ({} bytes)", limit),
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Check the monthly bandwidth allowance of the file owner, returns a download speed limit
+/// when the allowance is used up or [Status::TooManyRequests] when downloads are blocked
+async fn check_bandwidth(
+    id: &Vec<u8>,
+    db: &Database,
+    settings: &Settings,
+) -> Result<Option<u64>, Status> {
+    let has_limits = settings
+        .plans
+        .as_ref()
+        .is_some_and(|p| p.values().any(|p| p.bandwidth_bytes.is_some()));
+    if !has_limits {
+        return Ok(None);
+    }
+    let owner = match db.get_file_bandwidth_owner(id).await {
+        Ok(Some(u)) => u,
+        _ => return Ok(None),
+    };
+    let plan = match settings.user_plan(owner.plan.as_deref()) {
+        Some(p) => p,
+        None => return Ok(None),
+    };
+    let limit = match plan.bandwidth_bytes {
+        Some(l) => l,
+        None => return Ok(None),
+    };
+    let used = db.get_user_bandwidth(owner.id).await.unwrap_or(0);
+    if used < limit {
+        return Ok(None);
+    }
+    match plan.bandwidth_throttle {
+        Some(r) => Ok(Some(r)),
+        None => Err(Status::TooManyRequests),
+    }
+}
+
+/// Images and videos get a preview image from their first frame, SVGs can't be decoded
+#[cfg(feature = "media-compression")]
+fn has_media_thumbnail(mime_type: &str) -> bool {
+    (mime_type.starts_with("image/") || mime_type.starts_with("video/")) && !is_svg_mime(mime_type)
+}
+
+/// Label and compress a new upload and queue background processing jobs (previews),
+/// labels and the compressed variant are created right away so they are part of the
+/// upload response, and queued when they fail. `transform` is set when the uploader allows
+/// compression, returns the compressed variant. When compression is queued the upload
+/// progress is reported as done once the compressed version is stored.
+#[cfg(feature = "media-compression")]
+async fn queue_processing(
     fs: &FileStore,
     db: &Database,
+    settings: &Settings,
+    upload: &mut FileUpload,
+    transform: bool,
+    progress: Option<ProgressHandle>,
+) -> Option<FileVariant> {
+    let mut jobs = vec![];
+    #[cfg(feature = "labels")]
+    if settings.vit_model_path.is_some() && upload.mime_type.starts_with("image/") {
+        match label_file(fs, settings, &upload.id).await {
+            Ok(labels) => match db.add_file_labels(&upload.id, &labels).await {
+                Ok(()) => upload.labels = labels,
+                Err(e) => warn!("Failed to save labels: {}", e),
+            },
+            Err(e) => {
+                warn!("Failed to label {}: {}", hex::encode(&upload.id), e);
+                jobs.push(JobKind::Label);
+            }
+        }
+    }
+    let mut variant = None;
+    if transform
+        && settings
+            .compression
+            .clone()
+            .unwrap_or_default()
+            .is_enabled(&upload.mime_type)
+    {
+        match fs.transform(upload).await {
+            Ok(Some(v)) => match db.add_file_variant(&v).await {
+                Ok(()) => variant = Some(v),
+                Err(e) => {
+                    warn!("Failed to save variant: {}", e);
+                    jobs.push(JobKind::Transform);
+                }
+            },
+            Ok(None) => {}
+            Err(e) => {
+                warn!("Failed to compress {}: {}", hex::encode(&upload.id), e);
+                jobs.push(JobKind::Transform);
+            }
+        }
+    }
+    // previews are rendered for PDFs by content, the declared type is not checked
+    if is_pdf(&fs.get(&upload.id)) || has_media_thumbnail(&upload.mime_type) {
+        jobs.push(JobKind::Thumbnail);
+    }
+    let mut transform_queued = false;
+    for kind in jobs {
+        match db.add_processing_job(&upload.id, kind).await {
+            Ok(added) => transform_queued |= added && kind == JobKind::Transform,
+            Err(e) => warn!("Failed to queue {:?} job: {}", kind, e),
+        }
+    }
+    match progress {
+        Some(p) if transform_queued => p.wait_processing(&upload.id),
+        Some(p) => p.done(),
+        None => {}
+    }
+    variant
+}
+
+/// Remove the stored blob of a rejected upload, files which are already
+/// stored for other uploads (deduplicated) are kept
+async fn discard_blob(db: &Database, blob: &FileSystemResult) {
+    if let Ok(None) = db.get_file(&blob.upload.id).await {
+        let _ = std::fs::remove_file(&blob.path);
+    }
+}
+
+/// Save an upload for a user, when the file was already stored (deduplicated) the
+/// stored metadata replaces `upload` so the response matches later requests for it
+async fn save_upload(
+    db: &Database,
+    upload: &mut FileUpload,
+    user_id: u64,
+    delegate: Option<&Vec<u8>>,
 ) -> Result<(), Error> {
+    if !db.add_file(upload, user_id, delegate).await? {
+        if let Some(f) = db.get_file(&upload.id).await? {
+            // the original file, expiry and labels belong to this upload
+            *upload = FileUpload {
+                original_hash: upload.original_hash.take(),
+                expires: upload.expires,
+                #[cfg(feature = "labels")]
+                labels: std::mem::take(&mut upload.labels),
+                ..f
+            };
+        }
+    }
+    Ok(())
+}
+
+/// Parse a file id from a path segment, ignoring any file extension
+fn parse_file_id(sha256: &str) -> Result<Vec<u8>, Error> {
     let sha256 = if sha256.contains(".") {
         sha256.split('.').next().unwrap()
     } else {
         sha256
     };
-    let id = if let Ok(i) = hex::decode(sha256) {
-        i
-    } else {
-        return Err(Error::msg("Invalid file id"));
-    };
-
-    if id.len() != 32 {
-        return Err(Error::msg("Invalid file id"));
+    match hex::decode(sha256) {
+        Ok(i) if i.len() == 32 => Ok(i),
+        _ => Err(RequestError::new(Status::BadRequest, "Invalid file id").into()),
     }
+}
+
+/// Delete a file owned by `pubkey` by its stored hash or original (pre-transform) hash,
+/// the file is moved to the trash unless `trash_retention_days` is 0
+async fn delete_file(
+    sha256: &str,
+    pubkey: &PublicKey,
+    fs: &FileStore,
+    db: &Database,
+    settings: &Settings,
+) -> Result<(), Error> {
+    let pubkey_vec = pubkey.to_bytes().to_vec();
+    let hash = parse_file_id(sha256)?;
+    let id = db
+        .get_user_file_id(&pubkey_vec, &hash)
+        .await?
+        .unwrap_or(hash);
     if let Ok(Some(_info)) = db.get_file(&id).await {
-        let pubkey_vec = auth.pubkey.to_bytes().to_vec();
         let owners = db.get_file_owners(&id).await?;
 
         let this_owner = match owners.iter().find(|o| o.pubkey.eq(&pubkey_vec)) {
             Some(o) => o,
-            None => return Err(Error::msg("You dont own this file, you cannot delete it")),
+            None => {
+                return Err(RequestError::new(
+                    Status::Forbidden,
+                    "You dont own this file, you cannot delete it",
+                )
+                .into())
+            }
         };
-        if let Err(e) = db.delete_file_owner(&id, this_owner.id).await {
-            return Err(Error::msg(format!("Failed to delete (db): {}", e)));
-        }
-        // only 1 owner was left, delete file completely
-        if owners.len() == 1 {
-            if let Err(e) = db.delete_file(&id).await {
-                return Err(Error::msg(format!("Failed to delete (fs): {}", e)));
+        let audit = NewAuditEntry {
+            actor: &pubkey_vec,
+            action: AuditAction::Delete,
+            target: hex::encode(&id),
+            reason: None,
+        };
+        if settings.trash_retention_days.unwrap_or(7) > 0 {
+            if let Err(e) = db.trash_file(&id, this_owner.id, &audit).await {
+                return Err(Error::msg(format!("Failed to delete (db): {}", e)));
+            }
+        } else {
+            if let Err(e) = db.delete_file_owner(&id, this_owner.id, Some(&audit)).await {
+                return Err(Error::msg(format!("Failed to delete (db): {}", e)));
             }
-            if let Err(e) = fs::remove_file(fs.get(&id)) {
-                return Err(Error::msg(format!("Failed to delete (fs): {}", e)));
+            // only 1 owner was left, delete file completely
+            if owners.len() == 1 {
+                let variants = match db.delete_file(&id).await {
+                    Ok(v) => v,
+                    Err(e) => return Err(Error::msg(format!("Failed to delete (db): {}", e))),
+                };
+                if let Err(e) = fs.delete(&id, &variants) {
+                    return Err(Error::msg(format!("Failed to delete (fs): {}", e)));
+                }
             }
         }
         Ok(())
     } else {
-        Err(Error::msg("File not found"))
+        Err(RequestError::new(Status::NotFound, "File not found").into())
+    }
+}
+
+/// Take a file out of the trash of `pubkey`
+async fn restore_file(sha256: &str, pubkey: &PublicKey, db: &Database) -> Result<(), Error> {
+    let id = parse_file_id(sha256)?;
+    let pubkey_vec = pubkey.to_bytes().to_vec();
+    let user_id = db.upsert_user(&pubkey_vec).await?;
+    let audit = NewAuditEntry {
+        actor: &pubkey_vec,
+        action: AuditAction::Restore,
+        target: hex::encode(&id),
+        reason: None,
+    };
+    if db.restore_file(&id, user_id, &audit).await? {
+        Ok(())
+    } else {
+        Err(RequestError::new(Status::NotFound, "File not found in trash").into())
+    }
+}
+
+/// Add or remove a file from the public feed
+async fn set_file_public(
+    sha256: &str,
+    pubkey: &PublicKey,
+    public: bool,
+    db: &Database,
+) -> Result<(), Error> {
+    let id = parse_file_id(sha256)?;
+    if db
+        .set_file_public(&id, &pubkey.to_bytes().to_vec(), public)
+        .await?
+    {
+        Ok(())
+    } else {
+        Err(RequestError::new(Status::NotFound, "File not found").into())
     }
 }
 
@@ -166,6 +715,10 @@ pub async fn get_blob(
     sha256: &str,
     fs: &State<FileStore>,
     db: &State<Database>,
+    settings: &State<Settings>,
+    stats: &State<DownloadStats>,
+    geoip: &State<Option<GeoIp>>,
+    ip: Option<IpAddr>,
 ) -> Result<FilePayload, Status> {
     let sha256 = if sha256.contains(".") {
         sha256.split('.').next().unwrap()
@@ -181,16 +734,52 @@ pub async fn get_blob(
     if id.len() != 32 {
         return Err(Status::NotFound);
     }
+    if fs.is_banned(&id) {
+        return Err(Status::NotFound);
+    }
     if let Ok(Some(info)) = db.get_file(&id).await {
+        if info.deleted.is_some() {
+            return Err(Status::NotFound);
+        }
+        let throttle = check_bandwidth(&id, db, settings).await?;
+        let throttle = match (
+            throttle,
+            settings
+                .download_throttle
+                .as_ref()
+                .and_then(|t| t.rate_for(&info.mime_type)),
+        ) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
         if let Ok(f) = File::open(fs.get(&id)) {
-            return Ok(FilePayload { file: f, info });
+            stats.track(&id);
+            if let Some(country) = geoip.as_ref().zip(ip).and_then(|(g, ip)| g.country(ip)) {
+                stats.track_country(&country);
+            }
+            // serve directly from cold storage and move it back in the background
+            if info.storage_class == StorageClass::Cold {
+                let fs = fs.inner().clone();
+                let db = db.inner().clone();
+                tokio::spawn(async move {
+                    if let Err(e) = StorageTiering::rehydrate(&db, &fs, &id).await {
+                        warn!("Failed to rehydrate {}: {}", hex::encode(&id), e);
+                    }
+                });
+            }
+            return Ok(FilePayload {
+                file: f,
+                info,
+                throttle,
+                stats: stats.inner().clone(),
+            });
         }
     }
     Err(Status::NotFound)
 }
 
 #[rocket::head("/<sha256>")]
-pub async fn head_blob(sha256: &str, fs: &State<FileStore>) -> Status {
+pub async fn head_blob(sha256: &str, fs: &State<FileStore>, db: &State<Database>) -> Status {
     let sha256 = if sha256.contains(".") {
         sha256.split('.').next().unwrap()
     } else {
@@ -205,6 +794,15 @@ pub async fn head_blob(sha256: &str, fs: &State<FileStore>) -> Status {
     if id.len() != 32 {
         return Status::NotFound;
     }
+    if fs.is_banned(&id) {
+        return Status::NotFound;
+    }
+    if let Ok(Some(FileUpload {
+        deleted: Some(_), ..
+    })) = db.get_file(&id).await
+    {
+        return Status::NotFound;
+    }
     if fs.get(&id).exists() {
         Status::Ok
     } else {
@@ -212,6 +810,177 @@ pub async fn head_blob(sha256: &str, fs: &State<FileStore>) -> Status {
     }
 }
 
+/// File metadata as JSON, HEAD requests get the same data in `X-` headers
+#[rocket::get("/meta/<sha256>")]
+pub async fn get_metadata(
+    sha256: &str,
+    fs: &State<FileStore>,
+    db: &State<Database>,
+    settings: &State<Settings>,
+) -> Result<FileMetadata, Status> {
+    let id = match parse_file_id(sha256) {
+        Ok(i) if !fs.is_banned(&i) => i,
+        _ => return Err(Status::NotFound),
+    };
+    let info = match db.get_file(&id).await {
+        Ok(Some(f)) if f.deleted.is_none() => f,
+        Ok(_) => return Err(Status::NotFound),
+        Err(_) => return Err(Status::InternalServerError),
+    };
+    let uploader = match db.get_file_uploader(&id).await {
+        Ok(u) => u
+            .filter(|u| u.pubkey != ANONYMOUS_PUBKEY)
+            .and_then(|u| PublicKey::from_slice(&u.pubkey).ok())
+            .and_then(|p| p.to_bech32().ok()),
+        Err(_) => return Err(Status::InternalServerError),
+    };
+    #[cfg(feature = "labels")]
+    let labels = db
+        .get_file_labels(&id)
+        .await
+        .map_err(|_| Status::InternalServerError)?
+        .into_iter()
+        .map(|l| l.label)
+        .collect();
+    #[cfg(not(feature = "labels"))]
+    let labels = vec![];
+    let variants = db
+        .get_file_variants(&id)
+        .await
+        .map_err(|_| Status::InternalServerError)?
+        .into_iter()
+        .map(|v| VariantMetadata::new(settings, v))
+        .collect();
+
+    Ok(FileMetadata {
+        sha256: hex::encode(&info.id),
+        blake3: info.blake3.as_ref().map(hex::encode),
+        size: info.size,
+        mime_type: info.mime_type,
+        width: info.width,
+        height: info.height,
+        blurhash: info.blur_hash,
+        duration: info.duration,
+        labels,
+        created: info.created.timestamp(),
+        uploader,
+        downloads: info.downloads,
+        last_accessed: info.last_accessed.map(|t| t.timestamp()),
+        variants,
+    })
+}
+
+/// Redirect to a file by its BLAKE3 digest
+#[rocket::get("/blake3/<hash>")]
+pub async fn get_blob_blake3(
+    hash: &str,
+    db: &State<Database>,
+    settings: &State<Settings>,
+) -> Result<Redirect, Status> {
+    let hash = parse_file_id(hash).map_err(|_| Status::NotFound)?;
+    match db.get_file_by_blake3(&hash).await {
+        Ok(Some(f)) if f.deleted.is_none() => Ok(Redirect::found(format!(
+            "{}/{}",
+            &settings.public_url,
+            hex::encode(&f.id)
+        ))),
+        Ok(_) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/// Preview image of a file, see [JobKind::Thumbnail]
+#[rocket::get("/thumb/<sha256>")]
+pub async fn get_thumbnail(
+    sha256: &str,
+    fs: &State<FileStore>,
+    db: &State<Database>,
+) -> Result<(ContentType, NamedFile), Status> {
+    let id = match hex::decode(sha256) {
+        Ok(i) if i.len() == 32 && !fs.is_banned(&i) => i,
+        _ => return Err(Status::NotFound),
+    };
+    match db.get_file(&id).await {
+        Ok(Some(FileUpload {
+            thumb_mime: Some(_),
+            deleted: None,
+            ..
+        })) => {}
+        _ => return Err(Status::NotFound),
+    };
+    let (path, mime_type) = match db.get_file_variant(&id, VariantKind::Thumbnail).await {
+        Ok(Some(v)) => (fs.map_variant_path(&v.id), v.mime_type),
+        Ok(None) => return Err(Status::NotFound),
+        Err(_) => return Err(Status::InternalServerError),
+    };
+    match NamedFile::open(path).await {
+        Ok(f) => Ok((
+            ContentType::from_str(&mime_type).unwrap_or(ContentType::Binary),
+            f,
+        )),
+        Err(_) => Err(Status::NotFound),
+    }
+}
+
+/// Derived blob (thumbnail, transcode, conversion) by its own hash, see [FileVariant]
+#[rocket::get("/variant/<sha256>")]
+pub async fn get_variant(
+    sha256: &str,
+    fs: &State<FileStore>,
+    db: &State<Database>,
+) -> Result<(ContentType, NamedFile), Status> {
+    let id = match hex::decode(sha256) {
+        Ok(i) if i.len() == 32 && !fs.is_banned(&i) => i,
+        _ => return Err(Status::NotFound),
+    };
+    let variant = match db.get_variant(&id).await {
+        Ok(Some(v)) if !fs.is_banned(&v.file) => v,
+        Ok(_) => return Err(Status::NotFound),
+        Err(_) => return Err(Status::InternalServerError),
+    };
+    match NamedFile::open(fs.map_variant_path(&variant.id)).await {
+        Ok(f) => Ok((
+            ContentType::from_str(&variant.mime_type).unwrap_or(ContentType::Binary),
+            f,
+        )),
+        Err(_) => Err(Status::NotFound),
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct UploadId {
+    pub id: String,
+}
+
+/// Create an upload id for progress tracking, sent with the upload in the `X-Upload-Id` header,
+/// the number of ids is limited per client IP
+#[rocket::post("/progress")]
+pub async fn reserve_upload_id(
+    ip: IpAddr,
+    tracker: &State<UploadProgressTracker>,
+) -> Result<Json<UploadId>, Status> {
+    tracker.reserve(ip).map(|id| Json(UploadId { id }))
+}
+
+/// Stream upload progress events for an upload started with the `X-Upload-Id` header
+#[rocket::get("/progress/<id>")]
+pub async fn upload_progress(
+    id: &str,
+    tracker: &State<UploadProgressTracker>,
+) -> Option<EventStream![]> {
+    let mut rx = tracker.subscribe(id)?;
+    Some(EventStream! {
+        loop {
+            let p = rx.borrow_and_update().clone();
+            yield StreamEvent::json(&p);
+            if rx.changed().await.is_err() {
+                break;
+            }
+        }
+    })
+}
+
 #[cfg(feature = "void-cat-redirects")]
 #[rocket::get("/d/<id>")]
 pub async fn void_cat_redirect(
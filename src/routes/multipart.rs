@@ -0,0 +1,555 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use log::{error, info, warn};
+use nostr::PublicKey;
+use rocket::data::ToByteUnit;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::{routes, Data, FromForm, Responder, Route, State};
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::auth::nip98::Nip98Auth;
+use crate::auth::request::RequestAuth;
+use crate::db::{Database, FileUpload};
+use crate::filesystem::FileStore;
+use crate::idempotency::{request_hash, IdempotencyCache, IdempotencyState};
+use crate::limits::StreamLimits;
+#[cfg(feature = "media-compression")]
+use crate::routes::queue_processing;
+use crate::routes::{check_file_limit, discard_blob, get_upload_plan, save_upload, Nip94Event};
+use crate::settings::Settings;
+use crate::webhook::Webhook;
+
+/// Maximum number of parts in a single upload
+const MAX_PARTS: u32 = 10_000;
+
+/// Uploads which are not completed within this time are removed
+const UPLOAD_TTL: Duration = Duration::from_secs(60 * 60 * 24);
+
+pub fn multipart_routes() -> Vec<Route> {
+    routes![create_upload, upload_part, complete_upload, abort_upload]
+}
+
+#[derive(Serialize, Default)]
+#[serde(crate = "rocket::serde")]
+struct MultipartResponseBase<T> {
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<T>,
+}
+
+#[derive(Responder)]
+enum MultipartResponse<T> {
+    #[response(status = 500)]
+    GenericError(Json<MultipartResponseBase<T>>),
+
+    #[response(status = 200)]
+    Ok(Json<MultipartResponseBase<T>>),
+}
+
+impl<T> MultipartResponse<T> {
+    pub fn error(msg: &str) -> Self {
+        Self::GenericError(Json(MultipartResponseBase {
+            status: "error".to_string(),
+            message: Some(msg.to_string()),
+            data: None,
+        }))
+    }
+
+    pub fn success(msg: T) -> Self {
+        Self::Ok(Json(MultipartResponseBase {
+            status: "success".to_string(),
+            message: None,
+            data: Some(msg),
+        }))
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct MultipartUploadInfo {
+    pub id: String,
+    pub size: u64,
+    pub max_parts: u32,
+}
+
+/// Query of [create_upload]
+#[derive(FromForm)]
+struct CreateUpload<'r> {
+    size: u64,
+    mime_type: Option<&'r str>,
+    name: Option<&'r str>,
+}
+
+/// Pubkey of the uploader, multipart uploads are not available to anonymous users
+fn uploader(auth: &RequestAuth<Nip98Auth>) -> Result<PublicKey, &'static str> {
+    auth.user().ok_or("Auth header not found")
+}
+
+struct MultipartUpload {
+    pubkey: PublicKey,
+    size: u64,
+    mime_type: String,
+    name: Option<String>,
+    /// Size of each received part by part number
+    parts: HashMap<u32, u64>,
+    /// Size of the parts which are being uploaded by part number
+    reserved: HashMap<u32, u64>,
+    created: Instant,
+}
+
+impl MultipartUpload {
+    /// Check all parts were received and add up to the size of the upload
+    fn check_complete(&self) -> Result<(), &'static str> {
+        if !self.reserved.is_empty() {
+            return Err("Parts are still being uploaded");
+        }
+        let n_parts = self.parts.len() as u32;
+        if n_parts == 0 || (0..n_parts).any(|p| !self.parts.contains_key(&p)) {
+            return Err("Missing parts");
+        }
+        if self.parts.values().sum::<u64>() != self.size {
+            return Err("Upload size does not match");
+        }
+        Ok(())
+    }
+}
+
+/// In-progress multipart uploads, parts are stored in `<storage_dir>/multipart/<id>/<part>`
+#[derive(Clone)]
+pub struct MultipartUploads {
+    dir: PathBuf,
+    uploads: Arc<Mutex<HashMap<String, MultipartUpload>>>,
+    /// Results of completed uploads, see [complete_upload]
+    completed: IdempotencyCache,
+}
+
+impl MultipartUploads {
+    pub fn new(settings: &Settings, completed: IdempotencyCache) -> Self {
+        let dir = Path::new(&settings.storage_dir).join("multipart");
+        // uploads are only tracked in memory, parts left from a previous run can't be completed
+        if dir.exists() {
+            if let Err(e) = fs::remove_dir_all(&dir) {
+                warn!("Failed to remove old multipart uploads: {}", e);
+            }
+        }
+        Self {
+            dir,
+            uploads: Arc::new(Mutex::new(HashMap::new())),
+            completed,
+        }
+    }
+
+    fn upload_dir(&self, id: &str) -> PathBuf {
+        self.dir.join(id)
+    }
+
+    fn part_path(&self, id: &str, part: u32) -> PathBuf {
+        self.upload_dir(id).join(part.to_string())
+    }
+
+    fn create(
+        &self,
+        pubkey: PublicKey,
+        size: u64,
+        mime_type: String,
+        name: Option<String>,
+    ) -> io::Result<String> {
+        self.remove_expired();
+        let id = uuid::Uuid::new_v4().to_string();
+        fs::create_dir_all(self.upload_dir(&id))?;
+        self.uploads.lock().unwrap().insert(
+            id.clone(),
+            MultipartUpload {
+                pubkey,
+                size,
+                mime_type,
+                name,
+                parts: HashMap::new(),
+                reserved: HashMap::new(),
+                created: Instant::now(),
+            },
+        );
+        Ok(id)
+    }
+
+    /// Reserve `size` bytes of the upload for `part`, a previous upload of the part is replaced.
+    /// Reservations are made under the lock so parallel parts can't exceed the upload size
+    fn reserve_part(
+        &self,
+        id: &str,
+        pubkey: &PublicKey,
+        part: u32,
+        size: u64,
+    ) -> Result<(), &'static str> {
+        let mut uploads = self.uploads.lock().unwrap();
+        let upload = match uploads.get_mut(id).filter(|u| u.pubkey == *pubkey) {
+            Some(u) => u,
+            None => return Err("Upload not found"),
+        };
+        if upload.reserved.contains_key(&part) {
+            return Err("Part is already being uploaded");
+        }
+        let used: u64 = upload
+            .parts
+            .iter()
+            .chain(upload.reserved.iter())
+            .filter(|(n, _)| **n != part)
+            .map(|(_, s)| *s)
+            .sum();
+        if used.saturating_add(size) > upload.size {
+            return Err("Part exceeds upload size");
+        }
+        upload.parts.remove(&part);
+        upload.reserved.insert(part, size);
+        Ok(())
+    }
+
+    /// Release the reservation of a part, the part is added when it was saved
+    fn finish_part(&self, id: &str, part: u32, saved: bool) -> bool {
+        match self.uploads.lock().unwrap().get_mut(id) {
+            Some(u) => {
+                if let Some(size) = u.reserved.remove(&part) {
+                    if saved {
+                        u.parts.insert(part, size);
+                    }
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove a completed upload from the list of in-progress uploads, files are kept
+    /// until [Self::cleanup]. Incomplete uploads are kept so missing parts can be sent
+    fn take_complete(&self, id: &str, pubkey: &PublicKey) -> Result<MultipartUpload, &'static str> {
+        let mut uploads = self.uploads.lock().unwrap();
+        match uploads.get(id).filter(|u| u.pubkey == *pubkey) {
+            Some(u) => u.check_complete()?,
+            None => return Err("Upload not found"),
+        }
+        uploads.remove(id).ok_or("Upload not found")
+    }
+
+    /// Remove an upload from the list of in-progress uploads, files are kept until [Self::cleanup]
+    fn take(&self, id: &str, pubkey: &PublicKey) -> Option<MultipartUpload> {
+        let mut uploads = self.uploads.lock().unwrap();
+        if uploads.get(id).is_some_and(|u| u.pubkey == *pubkey) {
+            uploads.remove(id)
+        } else {
+            None
+        }
+    }
+
+    fn cleanup(&self, id: &str) {
+        if let Err(e) = fs::remove_dir_all(self.upload_dir(id)) {
+            warn!("Failed to remove multipart upload {}: {}", id, e);
+        }
+    }
+
+    fn remove_expired(&self) {
+        let expired: Vec<String> = {
+            let mut uploads = self.uploads.lock().unwrap();
+            let ids: Vec<String> = uploads
+                .iter()
+                .filter(|(_, u)| u.created.elapsed() > UPLOAD_TTL)
+                .map(|(id, _)| id.clone())
+                .collect();
+            for id in &ids {
+                uploads.remove(id);
+            }
+            ids
+        };
+        for id in expired {
+            info!("Removing expired multipart upload {}", id);
+            self.cleanup(&id);
+        }
+    }
+}
+
+/// Reads a list of files one after the other
+struct PartsReader {
+    parts: VecDeque<PathBuf>,
+    current: Option<tokio::fs::File>,
+}
+
+impl AsyncRead for PartsReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if self.current.is_none() {
+                match self.parts.pop_front() {
+                    Some(p) => {
+                        let f = fs::File::open(p)?;
+                        self.current = Some(tokio::fs::File::from_std(f));
+                    }
+                    None => return Poll::Ready(Ok(())),
+                }
+            }
+            let filled = buf.filled().len();
+            if let Some(f) = self.current.as_mut() {
+                ready!(Pin::new(f).poll_read(cx, buf))?;
+            }
+            if buf.filled().len() > filled {
+                return Poll::Ready(Ok(()));
+            }
+            // end of this part
+            self.current = None;
+        }
+    }
+}
+
+/// Start a multipart upload of `size` bytes, parts can then be uploaded in parallel
+#[rocket::post("/multipart?<params..>")]
+async fn create_upload(
+    params: CreateUpload<'_>,
+    auth: RequestAuth<Nip98Auth>,
+    fs: &State<FileStore>,
+    db: &State<Database>,
+    settings: &State<Settings>,
+    uploads: &State<MultipartUploads>,
+) -> MultipartResponse<MultipartUploadInfo> {
+    let pubkey = match uploader(&auth) {
+        Ok(p) => p,
+        Err(e) => return MultipartResponse::error(e),
+    };
+    let size = params.size;
+    let pubkey_vec = pubkey.to_bytes().to_vec();
+    let plan = match get_upload_plan(&pubkey_vec, db, settings).await {
+        Some(p) => p,
+        None => return MultipartResponse::error("No upload plan available"),
+    };
+    if matches!(auth, RequestAuth::ApiKey(_)) && plan.require_nostr_auth.unwrap_or(false) {
+        return MultipartResponse::error("Upload plan requires nostr auth");
+    }
+    if let Err(e) = check_file_limit(&pubkey_vec, Some(&plan), size, db, settings).await {
+        return MultipartResponse::error(&e.to_string());
+    }
+    if size == 0 || size > plan.max_upload_bytes.min(settings.max_upload_bytes) {
+        return MultipartResponse::error("File too large");
+    }
+    if let Err(e) = fs.check_free_space(size) {
+        return MultipartResponse::error(&e.to_string());
+    }
+    if let Some(wl) = &settings.whitelist {
+        if !wl.contains(&pubkey.to_hex()) {
+            return MultipartResponse::error("Not on whitelist");
+        }
+    }
+    let mime_type = params
+        .mime_type
+        .unwrap_or("application/octet-stream")
+        .to_string();
+    match uploads.create(pubkey, size, mime_type, params.name.map(|n| n.to_string())) {
+        Ok(id) => MultipartResponse::success(MultipartUploadInfo {
+            id,
+            size,
+            max_parts: MAX_PARTS,
+        }),
+        Err(e) => MultipartResponse::error(&format!("Could not create upload: {}", e)),
+    }
+}
+
+/// Upload a single part, parts are numbered from 0 and can be sent in any order.
+/// The `Content-Length` of the part is reserved from the size of the upload
+#[rocket::put("/multipart/<id>/<part>", data = "<data>")]
+async fn upload_part(
+    id: &str,
+    part: u32,
+    auth: RequestAuth<Nip98Auth>,
+    uploads: &State<MultipartUploads>,
+    limits: StreamLimits,
+    data: Data<'_>,
+) -> MultipartResponse<()> {
+    let pubkey = match uploader(&auth) {
+        Ok(p) => p,
+        Err(e) => return MultipartResponse::error(e),
+    };
+    if part >= MAX_PARTS {
+        return MultipartResponse::error("Invalid part number");
+    }
+    let size = match auth.content_length() {
+        Some(s) if s > 0 => s,
+        _ => return MultipartResponse::error("Missing Content-Length"),
+    };
+    if limits.max_body_bytes.is_some_and(|m| size > m) {
+        return MultipartResponse::error("Part too large");
+    }
+    if let Err(e) = uploads.reserve_part(id, &pubkey, part, size) {
+        return MultipartResponse::error(e);
+    }
+    let path = uploads.part_path(id, part);
+    let mut reader = limits.reader(data.open((size + 1).bytes()));
+    let res = match save_part(&mut reader, &path).await {
+        Ok(n) if n == size => Ok(()),
+        Ok(_) => Err("Part does not match Content-Length".to_string()),
+        Err(e) => Err(format!("Could not save part: {}", e)),
+    };
+    if res.is_err() {
+        let _ = fs::remove_file(&path);
+    }
+    if !uploads.finish_part(id, part, res.is_ok()) {
+        let _ = fs::remove_file(&path);
+        return MultipartResponse::error("Upload not found");
+    }
+    match res {
+        Ok(()) => MultipartResponse::success(()),
+        Err(e) => MultipartResponse::error(&e),
+    }
+}
+
+async fn save_part<R: AsyncRead + Unpin>(reader: &mut R, path: &Path) -> io::Result<u64> {
+    let mut out = tokio::fs::File::create(path).await?;
+    tokio::io::copy(reader, &mut out).await
+}
+
+/// Join all parts into a single file and store it like a regular upload,
+/// completing the same upload again returns the original result
+#[rocket::post("/multipart/<id>/complete")]
+async fn complete_upload(
+    id: &str,
+    auth: RequestAuth<Nip98Auth>,
+    fs: &State<FileStore>,
+    db: &State<Database>,
+    settings: &State<Settings>,
+    webhook: &State<Option<Webhook>>,
+    uploads: &State<MultipartUploads>,
+) -> MultipartResponse<Nip94Event> {
+    let pubkey = match uploader(&auth) {
+        Ok(p) => p,
+        Err(e) => return MultipartResponse::error(e),
+    };
+    let guard = match uploads.completed.begin(
+        &pubkey.to_hex(),
+        &format!("multipart:{}", id),
+        request_hash(&[id]),
+    ) {
+        IdempotencyState::Done(r) => {
+            return MultipartResponse::success(Nip94Event::from_upload(settings, &r.upload))
+        }
+        IdempotencyState::InProgress | IdempotencyState::Mismatch => {
+            return MultipartResponse::error("Upload is already being completed")
+        }
+        IdempotencyState::New(g) => g,
+    };
+    let upload = match uploads.take_complete(id, &pubkey) {
+        Ok(u) => u,
+        Err(e) => return MultipartResponse::error(e),
+    };
+    let parts = (0..upload.parts.len() as u32)
+        .map(|p| uploads.part_path(id, p))
+        .collect();
+    let res = store_upload(&upload, parts, &pubkey, fs, db, settings, webhook).await;
+    uploads.cleanup(id);
+    match res {
+        Ok(file) => {
+            guard.complete(&file, None);
+            MultipartResponse::success(Nip94Event::from_upload(settings, &file))
+        }
+        Err(e) => e,
+    }
+}
+
+/// Store the joined `parts` of an upload
+async fn store_upload(
+    upload: &MultipartUpload,
+    parts: VecDeque<PathBuf>,
+    pubkey: &PublicKey,
+    fs: &FileStore,
+    db: &Database,
+    settings: &Settings,
+    webhook: &Option<Webhook>,
+) -> Result<FileUpload, MultipartResponse<Nip94Event>> {
+    let reader = PartsReader {
+        parts,
+        current: None,
+    };
+    let mut blob = match fs.put(reader, &upload.mime_type).await {
+        Ok(b) => b,
+        Err(e) => {
+            error!("{}", e);
+            return Err(MultipartResponse::error(&format!(
+                "Could not save file: {}",
+                e
+            )));
+        }
+    };
+    blob.upload.name = upload.name.clone().unwrap_or_default();
+
+    let pubkey_vec = pubkey.to_bytes().to_vec();
+    let plan = get_upload_plan(&pubkey_vec, db, settings).await;
+    if let Some(days) = plan.as_ref().and_then(|p| p.expiration_days) {
+        blob.upload.expires = Some(Utc::now() + chrono::Duration::days(days as i64));
+    }
+    if let Some(wh) = webhook.as_ref() {
+        match wh.store_file(&pubkey_vec, blob.clone()).await {
+            Ok(true) => {}
+            Ok(false) => {
+                discard_blob(db, &blob).await;
+                return Err(MultipartResponse::error("Upload rejected"));
+            }
+            Err(e) => {
+                discard_blob(db, &blob).await;
+                return Err(MultipartResponse::error(&format!(
+                    "Internal error, failed to call webhook: {}",
+                    e
+                )));
+            }
+        }
+    }
+    let user_id = match db.upsert_user(&pubkey_vec).await {
+        Ok(u) => u,
+        Err(e) => {
+            discard_blob(db, &blob).await;
+            return Err(MultipartResponse::error(&format!(
+                "Could not save user: {}",
+                e
+            )));
+        }
+    };
+    if let Err(e) = save_upload(db, &mut blob.upload, user_id, None).await {
+        error!("{}", e);
+        discard_blob(db, &blob).await;
+        return Err(MultipartResponse::error(&format!(
+            "Could not save file (db): {}",
+            e
+        )));
+    }
+    #[cfg(feature = "media-compression")]
+    queue_processing(fs, db, settings, &mut blob.upload, false, None).await;
+
+    Ok(blob.upload)
+}
+
+/// Cancel a multipart upload and remove all uploaded parts
+#[rocket::delete("/multipart/<id>")]
+async fn abort_upload(
+    id: &str,
+    auth: RequestAuth<Nip98Auth>,
+    uploads: &State<MultipartUploads>,
+) -> MultipartResponse<()> {
+    let pubkey = match uploader(&auth) {
+        Ok(p) => p,
+        Err(e) => return MultipartResponse::error(e),
+    };
+    match uploads.take(id, &pubkey) {
+        Some(_) => {
+            uploads.cleanup(id);
+            MultipartResponse::success(())
+        }
+        None => MultipartResponse::error("Upload not found"),
+    }
+}
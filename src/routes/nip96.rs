@@ -1,21 +1,31 @@
 use std::collections::HashMap;
-use std::fs;
-use std::ops::Sub;
-use std::time::Duration;
-
-use log::error;
-use nostr::Timestamp;
-use rocket::data::ToByteUnit;
-use rocket::form::Form;
-use rocket::fs::TempFile;
-use rocket::serde::json::Json;
+use std::path::PathBuf;
+
+use chrono::Utc;
+use log::{error, info, warn};
+use nostr::PublicKey;
+use rocket::data::{Limits, ToByteUnit};
+use rocket::form::{self, DataField, Form, FromFormField};
+use rocket::serde::json::{serde_json, Json};
 use rocket::serde::Serialize;
-use rocket::{routes, FromForm, Responder, Route, State};
+use rocket::{async_trait, routes, Data, FromForm, Responder, Route, State};
+use sha2::{Digest, Sha256};
+use tokio::fs::File;
 
-use crate::auth::nip98::Nip98Auth;
-use crate::db::{Database, FileUpload};
+use crate::api_version::ApiVersion;
+use crate::auth::nip98::{Nip98Auth, MAX_AUTH_AGE};
+use crate::auth::request::RequestAuth;
+use crate::db::{Database, FileUpload, FileVariant};
 use crate::filesystem::FileStore;
-use crate::routes::{delete_file, Nip94Event, PagedResult};
+use crate::idempotency::{request_hash, Idempotency, IdempotencyState};
+use crate::limits::StreamLimits;
+use crate::progress::{ProgressHandle, ProgressReader};
+#[cfg(feature = "media-compression")]
+use crate::routes::queue_processing;
+use crate::routes::{
+    check_file_limit, delete_file, discard_blob, get_upload_plan, restore_file, save_upload,
+    set_file_public, Nip94Event, PagedResult,
+};
 use crate::settings::Settings;
 use crate::webhook::Webhook;
 
@@ -71,11 +81,22 @@ enum Nip96Response {
     #[response(status = 500)]
     GenericError(Json<Nip96UploadResult>),
 
+    /// The `Idempotency-Key` was used for a different upload
+    #[response(status = 422)]
+    KeyMismatch(Json<Nip96UploadResult>),
+
     #[response(status = 200)]
     UploadResult(Json<Nip96UploadResult>),
 
     #[response(status = 200)]
     FileList(Json<PagedResult<Nip94Event>>),
+
+    #[response(status = 200)]
+    BatchDelete(Json<Nip96BatchDeleteResult>),
+
+    /// Some files could not be deleted, see [Nip96BatchDeleteResult::failed]
+    #[response(status = 207)]
+    BatchDeleteFailed(Json<Nip96BatchDeleteResult>),
 }
 
 impl Nip96Response {
@@ -87,6 +108,14 @@ impl Nip96Response {
         }))
     }
 
+    fn key_mismatch() -> Self {
+        Nip96Response::KeyMismatch(Json(Nip96UploadResult {
+            status: "error".to_string(),
+            message: Some("Idempotency-Key was used for a different upload".to_string()),
+            ..Default::default()
+        }))
+    }
+
     fn success(msg: &str) -> Self {
         Nip96Response::UploadResult(Json(Nip96UploadResult {
             status: "success".to_string(),
@@ -108,19 +137,87 @@ struct Nip96UploadResult {
     pub nip94_event: Option<Nip94Event>,
 }
 
+#[derive(Serialize, Default)]
+#[serde(crate = "rocket::serde")]
+struct Nip96BatchDeleteResult {
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// Hashes which were deleted
+    pub deleted: Vec<String>,
+    /// Error message by hash for files which could not be deleted
+    pub failed: HashMap<String, String>,
+}
+
+/// Maximum number of hashes accepted by the batch delete endpoint
+const MAX_BATCH_DELETE: usize = 1000;
+
+/// Max size of the batch delete body, hashes may have a file extension
+const MAX_BATCH_DELETE_BODY: usize = MAX_BATCH_DELETE * 128;
+
 impl Nip96UploadResult {
-    pub fn from_upload(settings: &Settings, upload: &FileUpload) -> Self {
+    /// Result of an upload, describing the compressed `variant` when the file was compressed
+    pub fn from_upload(
+        settings: &Settings,
+        upload: &FileUpload,
+        variant: Option<&FileVariant>,
+    ) -> Self {
+        let event = Nip94Event::from_upload(settings, upload);
         Self {
             status: "success".to_string(),
-            nip94_event: Some(Nip94Event::from_upload(settings, upload)),
+            nip94_event: Some(match variant {
+                Some(v) => event.with_variant(settings, upload, v),
+                None => event,
+            }),
             ..Default::default()
         }
     }
 }
 
+/// File of an upload form, streamed to the temp directory while the form is parsed
+/// so that progress is reported while receiving, removed when dropped
+struct Nip96File {
+    path: PathBuf,
+    /// Progress of the upload, finished once the file is processed
+    progress: Option<ProgressHandle>,
+}
+
+impl Drop for Nip96File {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[async_trait]
+impl<'r> FromFormField<'r> for Nip96File {
+    async fn from_data(field: DataField<'r, '_>) -> form::Result<'r, Self> {
+        let fs = match field.request.rocket().state::<FileStore>() {
+            Some(fs) => fs,
+            None => return Err(form::Error::validation("File store not available").into()),
+        };
+        let progress = field.request.guard::<ProgressHandle>().await.succeeded();
+        let limits = StreamLimits::for_request(field.request);
+        let limit = limits.limit(field.request.limits().get("file").unwrap_or(Limits::FILE));
+        let path = fs
+            .temp_path()
+            .map_err(|e| form::Error::validation(e.to_string()))?;
+        let file = Nip96File {
+            path,
+            progress: progress.clone(),
+        };
+        let mut out = File::create(&file.path).await?;
+        let mut reader = ProgressReader::new(limits.reader(field.data.open(limit + 1)), progress);
+        let n = tokio::io::copy(&mut reader, &mut out).await?;
+        if n > limit.as_u64() {
+            return Err(form::Error::validation("File too large").into());
+        }
+        Ok(file)
+    }
+}
+
 #[derive(FromForm)]
 struct Nip96Form<'r> {
-    file: TempFile<'r>,
+    file: Nip96File,
     expiration: Option<usize>,
     size: u64,
     alt: Option<&'r str>,
@@ -129,25 +226,76 @@ struct Nip96Form<'r> {
     #[allow(dead_code)]
     content_type: Option<&'r str>,
     no_transform: Option<bool>,
+    /// Show the file in the public feed
+    public: Option<bool>,
 }
 
 pub fn nip96_routes() -> Vec<Route> {
-    routes![get_info_doc, upload, delete, list_files]
+    routes![
+        get_info_doc,
+        upload,
+        delete,
+        delete_batch,
+        list_files,
+        list_trash,
+        restore,
+        set_public
+    ]
+}
+
+/// Routes served under the versioned API prefix
+pub fn nip96_api_routes() -> Vec<Route> {
+    routes![
+        upload,
+        delete,
+        delete_batch,
+        list_files,
+        list_trash,
+        restore,
+        set_public
+    ]
 }
 
 #[rocket::get("/.well-known/nostr/nip96.json")]
-async fn get_info_doc(settings: &State<Settings>) -> Json<Nip96InfoDoc> {
-    let mut plans = HashMap::new();
-    plans.insert(
-        "free".to_string(),
-        Nip96Plan {
-            is_nip98_required: true,
-            max_byte_size: settings.max_upload_bytes,
-            ..Default::default()
-        },
-    );
+async fn get_info_doc(settings: &State<Settings>, fs: &State<FileStore>) -> Json<Nip96InfoDoc> {
+    // advertise reduced limits when the storage volume is running out of space
+    let max_byte_size = match fs.upload_headroom() {
+        Ok(Some(h)) => h.min(settings.max_upload_bytes),
+        _ => settings.max_upload_bytes,
+    };
+    let mut plans: HashMap<String, Nip96Plan> = settings
+        .plans()
+        .into_iter()
+        .map(|(id, p)| {
+            let days = p.expiration_days.unwrap_or(0) as usize;
+            (
+                id,
+                Nip96Plan {
+                    name: p.name,
+                    // plans which accept API keys can be used without NIP-98
+                    is_nip98_required: p.require_nostr_auth.unwrap_or(false),
+                    url: p.url,
+                    max_byte_size: p.max_upload_bytes.min(max_byte_size),
+                    file_expiration: Some((days, days)),
+                    ..Default::default()
+                },
+            )
+        })
+        .collect();
+    if let Some(anon) = &settings.anonymous_uploads {
+        plans.insert(
+            "anonymous".to_string(),
+            Nip96Plan {
+                name: "Anonymous".to_string(),
+                is_nip98_required: false,
+                max_byte_size: anon.max_upload_bytes.min(max_byte_size),
+                file_expiration: Some((anon.ttl_days as usize, anon.ttl_days as usize)),
+                ..Default::default()
+            },
+        );
+    }
     Json(Nip96InfoDoc {
-        api_url: "/n96".to_string(),
+        api_url: format!("{}/n96", ApiVersion::CURRENT_PREFIX),
         download_url: Some("/".to_string()),
         content_types: Some(vec![
             "image/*".to_string(),
@@ -159,24 +307,111 @@ async fn get_info_doc(settings: &State<Settings>) -> Json<Nip96InfoDoc> {
     })
 }
 
+/// SHA-256 of a received file, as stored in [FileUpload::original_hash]
+async fn hash_file(path: PathBuf) -> Result<Vec<u8>, std::io::Error> {
+    tokio::task::spawn_blocking(move || {
+        let mut hash = Sha256::new();
+        std::io::copy(&mut std::fs::File::open(path)?, &mut hash)?;
+        Ok(hash.finalize().to_vec())
+    })
+    .await?
+}
+
+/// Pubkey of the user managing their files, anonymous requests are rejected and
+/// NIP-98 events must be recent
+fn check_auth(auth: &RequestAuth<Nip98Auth>) -> Result<PublicKey, &'static str> {
+    if let RequestAuth::Nostr(a) = auth {
+        a.check_age(MAX_AUTH_AGE)?;
+    }
+    auth.user().ok_or("Auth header not found")
+}
+
 #[rocket::post("/n96", data = "<form>")]
 async fn upload(
-    auth: Nip98Auth,
+    auth: RequestAuth<Nip98Auth>,
     fs: &State<FileStore>,
     db: &State<Database>,
     settings: &State<Settings>,
     webhook: &State<Option<Webhook>>,
-    form: Form<Nip96Form<'_>>,
+    idempotency: Idempotency,
+    mut form: Form<Nip96Form<'_>>,
 ) -> Nip96Response {
-    if let Some(size) = auth.content_length {
-        if size > settings.max_upload_bytes {
+    // retries return the original response, the auth event id is used as the key
+    // when the event is only valid for a single file (payload tag)
+    let event_key = match &auth {
+        RequestAuth::Nostr(a) if a.payload.is_some() => Some(a.event.id.to_hex()),
+        _ => None,
+    };
+    let request = request_hash(&[
+        &form.size.to_string(),
+        form.media_type.unwrap_or_default(),
+        form.caption.unwrap_or_default(),
+        form.alt.unwrap_or_default(),
+        &form.no_transform.unwrap_or(false).to_string(),
+        &form.public.unwrap_or(false).to_string(),
+    ]);
+    let idempotency = match idempotency.begin(&auth.idempotency_scope(), event_key, request) {
+        Some(IdempotencyState::Done(r)) => {
+            // the form fields match, the retry must also send the same file
+            let original = r.upload.original_hash.as_ref().unwrap_or(&r.upload.id);
+            return match hash_file(form.file.path.clone()).await {
+                Ok(h) if &h == original => Nip96Response::UploadResult(Json(
+                    Nip96UploadResult::from_upload(settings, &r.upload, r.variant.as_ref()),
+                )),
+                Ok(_) => Nip96Response::key_mismatch(),
+                Err(e) => Nip96Response::error(&format!("Could not read file: {}", e)),
+            };
+        }
+        Some(IdempotencyState::InProgress) => {
+            return Nip96Response::error("An upload with this Idempotency-Key is in progress")
+        }
+        Some(IdempotencyState::Mismatch) => return Nip96Response::key_mismatch(),
+        Some(IdempotencyState::New(g)) => Some(g),
+        None => None,
+    };
+
+    if let RequestAuth::Anonymous(a) = &auth {
+        // anonymous uploads must always be checked by the webhook
+        if webhook.is_none() {
+            return Nip96Response::error("Anonymous uploads are not available");
+        }
+        if !a.check_rate_limit() {
+            return Nip96Response::error("Rate limit exceeded");
+        }
+    }
+
+    let plan = match &auth {
+        RequestAuth::Anonymous(_) => None,
+        _ => match get_upload_plan(&auth.pubkey(), db, settings).await {
+            Some(p) => Some(p),
+            None => return Nip96Response::error("No upload plan available"),
+        },
+    };
+    if let (RequestAuth::ApiKey(_), Some(p)) = (&auth, &plan) {
+        if p.require_nostr_auth.unwrap_or(false) {
+            return Nip96Response::error("Upload plan requires nostr auth");
+        }
+    }
+    if let Err(e) = check_file_limit(&auth.pubkey(), plan.as_ref(), form.size, db, settings).await {
+        return Nip96Response::error(&e.to_string());
+    }
+    let max_upload_bytes = match (&plan, &settings.anonymous_uploads) {
+        (Some(p), _) => p.max_upload_bytes.min(settings.max_upload_bytes),
+        (None, Some(a)) => a.max_upload_bytes,
+        (None, None) => settings.max_upload_bytes,
+    };
+    if let Some(size) = auth.content_length() {
+        if size > max_upload_bytes {
             return Nip96Response::error("File too large");
         }
     }
-    if form.size > settings.max_upload_bytes {
+    if form.size > max_upload_bytes {
         return Nip96Response::error("File too large");
     }
-    let file = match form.file.open().await {
+    if let Err(e) = fs.check_free_space(form.size) {
+        return Nip96Response::error(&e.to_string());
+    }
+    let file = match File::open(&form.file.path).await {
         Ok(f) => f,
         Err(e) => return Nip96Response::error(&format!("Could not open file: {}", e)),
     };
@@ -186,40 +421,58 @@ async fn upload(
         return Nip96Response::error("Expiration not supported");
     }
 
-    // account for upload speeds as slow as 1MB/s (8 Mbps)
-    let mbs = form.size / 1.megabytes().as_u64();
-    let max_time = 60.max(mbs);
-    if auth.event.created_at < Timestamp::now().sub(Duration::from_secs(max_time)) {
-        return Nip96Response::error("Auth event timestamp out of range");
+    if let RequestAuth::Nostr(a) = &auth {
+        // account for upload speeds as slow as 1MB/s (8 Mbps)
+        let mbs = form.size / 1.megabytes().as_u64();
+        if let Err(e) = a.check_age(MAX_AUTH_AGE.max(mbs)) {
+            return Nip96Response::error(e);
+        }
     }
 
     // check whitelist
     if let Some(wl) = &settings.whitelist {
-        if !wl.contains(&auth.event.pubkey.to_hex()) {
+        if !matches!(auth, RequestAuth::Anonymous(_)) && !wl.contains(&hex::encode(auth.pubkey())) {
             return Nip96Response::error("Not on whitelist");
         }
     }
-    match fs
-        .put(file, mime_type, !form.no_transform.unwrap_or(false))
-        .await
-    {
+    match fs.put(file, mime_type).await {
         Ok(mut blob) => {
+            if let RequestAuth::Nostr(a) = &auth {
+                if let Err(e) = a.check_payload(settings, &blob.original_hash) {
+                    discard_blob(db, &blob).await;
+                    return Nip96Response::error(e);
+                }
+            }
             blob.upload.name = match &form.caption {
                 Some(c) => c.to_string(),
                 None => "".to_string(),
             };
             blob.upload.alt = form.alt.as_ref().map(|s| s.to_string());
-            let pubkey_vec = auth.event.pubkey.to_bytes().to_vec();
+            if let (RequestAuth::Anonymous(a), Some(anon)) = (&auth, &settings.anonymous_uploads) {
+                info!("Anonymous upload from {}", a.ip);
+                blob.upload.expires =
+                    Some(Utc::now() + chrono::Duration::days(anon.ttl_days as i64));
+            }
+            if let Some(days) = plan.as_ref().and_then(|p| p.expiration_days) {
+                blob.upload.expires = Some(Utc::now() + chrono::Duration::days(days as i64));
+            }
+            let pubkey_vec = auth.pubkey();
+            let delegate = match &auth {
+                RequestAuth::Nostr(a) if a.delegator.is_some() => {
+                    Some(a.event.pubkey.to_bytes().to_vec())
+                }
+                _ => None,
+            };
             if let Some(wh) = webhook.as_ref() {
                 match wh.store_file(&pubkey_vec, blob.clone()).await {
                     Ok(store) => {
                         if !store {
-                            let _ = fs::remove_file(blob.path);
+                            discard_blob(db, &blob).await;
                             return Nip96Response::error("Upload rejected");
                         }
                     }
                     Err(e) => {
-                        let _ = fs::remove_file(blob.path);
+                        discard_blob(db, &blob).await;
                         return Nip96Response::error(&format!(
                             "Internal error, failed to call webhook: {}",
                             e
@@ -229,25 +482,45 @@ async fn upload(
             }
             let user_id = match db.upsert_user(&pubkey_vec).await {
                 Ok(u) => u,
-                Err(e) => return Nip96Response::error(&format!("Could not save user: {}", e)),
-            };
-            let tmp_file = blob.path.clone();
-            if let Err(e) = db.add_file(&blob.upload, user_id).await {
-                error!("{}", e.to_string());
-                let _ = fs::remove_file(tmp_file);
-                if let Some(dbe) = e.as_database_error() {
-                    if let Some(c) = dbe.code() {
-                        if c == "23000" {
-                            return Nip96Response::error("File already exists");
-                        }
-                    }
+                Err(e) => {
+                    discard_blob(db, &blob).await;
+                    return Nip96Response::error(&format!("Could not save user: {}", e));
                 }
+            };
+            if let Err(e) = save_upload(db, &mut blob.upload, user_id, delegate.as_ref()).await {
+                error!("{}", e);
+                discard_blob(db, &blob).await;
                 return Nip96Response::error(&format!("Could not save file (db): {}", e));
             }
-
+            if form.public.unwrap_or(false) {
+                if let Err(e) = db.set_file_public(&blob.upload.id, &pubkey_vec, true).await {
+                    warn!("Failed to mark file as public: {}", e);
+                }
+            }
+            #[cfg(feature = "media-compression")]
+            let variant = queue_processing(
+                fs,
+                db,
+                settings,
+                &mut blob.upload,
+                !form.no_transform.unwrap_or(false),
+                form.file.progress.take(),
+            )
+            .await;
+            #[cfg(not(feature = "media-compression"))]
+            let variant = {
+                if let Some(p) = form.file.progress.take() {
+                    p.done();
+                }
+                None
+            };
+            if let Some(g) = idempotency {
+                g.complete(&blob.upload, variant.as_ref());
+            }
             Nip96Response::UploadResult(Json(Nip96UploadResult::from_upload(
                 settings,
                 &blob.upload,
+                variant.as_ref(),
             )))
         }
         Err(e) => {
@@ -260,30 +533,169 @@ async fn upload(
 #[rocket::delete("/n96/<sha256>")]
 async fn delete(
     sha256: &str,
-    auth: Nip98Auth,
+    auth: RequestAuth<Nip98Auth>,
     fs: &State<FileStore>,
     db: &State<Database>,
+    settings: &State<Settings>,
 ) -> Nip96Response {
-    match delete_file(sha256, &auth.event, fs, db).await {
+    let pubkey = match check_auth(&auth) {
+        Ok(p) => p,
+        Err(e) => return Nip96Response::error(e),
+    };
+    match delete_file(sha256, &pubkey, fs, db, settings).await {
         Ok(()) => Nip96Response::success("File deleted."),
         Err(e) => Nip96Response::error(&format!("Failed to delete file: {}", e)),
     }
 }
 
+/// Delete a list of files, the body is a JSON array of hashes.
+/// NIP-98 events must have a `payload` tag with the hash of the body
+#[rocket::post("/n96/delete", data = "<body>")]
+async fn delete_batch(
+    body: Data<'_>,
+    auth: RequestAuth<Nip98Auth>,
+    fs: &State<FileStore>,
+    db: &State<Database>,
+    settings: &State<Settings>,
+) -> Nip96Response {
+    let pubkey = match check_auth(&auth) {
+        Ok(p) => p,
+        Err(e) => return Nip96Response::error(e),
+    };
+    let body = match body.open(MAX_BATCH_DELETE_BODY.bytes()).into_bytes().await {
+        Ok(b) if b.is_complete() => b.into_inner(),
+        Ok(_) => return Nip96Response::error("Request body too large"),
+        Err(e) => return Nip96Response::error(&format!("Failed to read body: {}", e)),
+    };
+    if let RequestAuth::Nostr(a) = &auth {
+        if let Err(e) = a.check_body(&body) {
+            return Nip96Response::error(e);
+        }
+    }
+    let hashes: Vec<String> = match serde_json::from_slice(&body) {
+        Ok(h) => h,
+        Err(e) => return Nip96Response::error(&format!("Invalid body: {}", e)),
+    };
+    delete_files(&hashes, &pubkey, fs, db, settings).await
+}
+
+/// Delete a list of files by stored or original hash, each file is deleted separately
+async fn delete_files(
+    hashes: &[String],
+    pubkey: &PublicKey,
+    fs: &FileStore,
+    db: &Database,
+    settings: &Settings,
+) -> Nip96Response {
+    if hashes.len() > MAX_BATCH_DELETE {
+        return Nip96Response::error(&format!(
+            "Too many files, max {} per request",
+            MAX_BATCH_DELETE
+        ));
+    }
+    let mut result = Nip96BatchDeleteResult {
+        status: "success".to_string(),
+        ..Default::default()
+    };
+    for hash in hashes {
+        match delete_file(hash, pubkey, fs, db, settings).await {
+            Ok(()) => result.deleted.push(hash.clone()),
+            Err(e) => {
+                result.failed.insert(hash.clone(), e.to_string());
+            }
+        }
+    }
+    if result.failed.is_empty() {
+        Nip96Response::BatchDelete(Json(result))
+    } else {
+        result.status = "error".to_string();
+        result.message = Some(format!(
+            "{} of {} files could not be deleted",
+            result.failed.len(),
+            hashes.len()
+        ));
+        Nip96Response::BatchDeleteFailed(Json(result))
+    }
+}
+
 #[rocket::get("/n96?<page>&<count>")]
 async fn list_files(
-    auth: Nip98Auth,
+    auth: RequestAuth<Nip98Auth>,
+    page: u32,
+    count: u32,
+    db: &State<Database>,
+    settings: &State<Settings>,
+) -> Nip96Response {
+    match check_auth(&auth) {
+        Ok(p) => list_user_files(&p, page, count, false, db, settings).await,
+        Err(e) => Nip96Response::error(e),
+    }
+}
+
+#[rocket::get("/n96/trash?<page>&<count>")]
+async fn list_trash(
+    auth: RequestAuth<Nip98Auth>,
     page: u32,
     count: u32,
     db: &State<Database>,
     settings: &State<Settings>,
 ) -> Nip96Response {
-    let pubkey_vec = auth.event.pubkey.to_bytes().to_vec();
+    match check_auth(&auth) {
+        Ok(p) => list_user_files(&p, page, count, true, db, settings).await,
+        Err(e) => Nip96Response::error(e),
+    }
+}
+
+#[rocket::post("/n96/restore/<sha256>")]
+async fn restore(
+    sha256: &str,
+    auth: RequestAuth<Nip98Auth>,
+    db: &State<Database>,
+) -> Nip96Response {
+    let pubkey = match check_auth(&auth) {
+        Ok(p) => p,
+        Err(e) => return Nip96Response::error(e),
+    };
+    match restore_file(sha256, &pubkey, db).await {
+        Ok(()) => Nip96Response::success("File restored."),
+        Err(e) => Nip96Response::error(&format!("Failed to restore file: {}", e)),
+    }
+}
+
+#[rocket::post("/n96/public/<sha256>?<public>")]
+async fn set_public(
+    sha256: &str,
+    public: bool,
+    auth: RequestAuth<Nip98Auth>,
+    db: &State<Database>,
+) -> Nip96Response {
+    let pubkey = match check_auth(&auth) {
+        Ok(p) => p,
+        Err(e) => return Nip96Response::error(e),
+    };
+    match set_file_public(sha256, &pubkey, public, db).await {
+        Ok(()) => Nip96Response::success("File updated."),
+        Err(e) => Nip96Response::error(&format!("Failed to update file: {}", e)),
+    }
+}
+
+async fn list_user_files(
+    pubkey: &PublicKey,
+    page: u32,
+    count: u32,
+    trash: bool,
+    db: &Database,
+    settings: &Settings,
+) -> Nip96Response {
+    let pubkey_vec = pubkey.to_bytes().to_vec();
     let server_count = count.min(5_000).max(1);
-    match db
-        .list_files(&pubkey_vec, page * server_count, server_count)
-        .await
-    {
+    let (offset, limit) = (page * server_count, server_count);
+    let files = if trash {
+        db.list_trash(&pubkey_vec, offset, limit).await
+    } else {
+        db.list_files(&pubkey_vec, offset, limit).await
+    };
+    match files {
         Ok((files, total)) => Nip96Response::FileList(Json(PagedResult {
             count: server_count,
             page,
@@ -291,7 +703,7 @@ async fn list_files(
             files: files
                 .iter()
                 .map(|f| {
-                    Nip96UploadResult::from_upload(settings, f)
+                    Nip96UploadResult::from_upload(settings, f, None)
                         .nip94_event
                         .unwrap()
                 })
@@ -0,0 +1,324 @@
+use chrono::{DateTime, Duration, Utc};
+use log::warn;
+use nostr::{Timestamp, ToBech32};
+use quick_xml::escape::escape;
+use rocket::http::{ContentType, Cookie, CookieJar, SameSite, Status};
+use rocket::response::Redirect;
+use rocket::serde::json::{serde_json, Json};
+use rocket::serde::Serialize;
+use rocket::{routes, Responder, Route, State};
+
+use crate::auth::nip98::Nip98Auth;
+use crate::auth::session::{SessionAuth, SESSION_COOKIE};
+use crate::db::{Database, FileUpload};
+use crate::filesystem::FileStore;
+use crate::routes::{delete_file, RequestError};
+use crate::settings::Settings;
+
+/// Number of files on each page of `/my`
+const PAGE_SIZE: u32 = 50;
+
+/// Max age of the NIP-98 event used to log in
+const LOGIN_MAX_AGE: u64 = 60;
+
+pub fn portal_routes() -> Vec<Route> {
+    routes![login, logout, my_files, my_login, my_delete]
+}
+
+#[derive(Serialize, Default)]
+#[serde(crate = "rocket::serde")]
+struct PortalResponseBase<T> {
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<T>,
+}
+
+#[derive(Responder)]
+enum PortalResponse<T> {
+    #[response(status = 500)]
+    GenericError(Json<PortalResponseBase<T>>),
+
+    #[response(status = 401)]
+    Unauthorized(Json<PortalResponseBase<T>>),
+
+    #[response(status = 404)]
+    NotFound(()),
+
+    #[response(status = 200)]
+    Ok(Json<PortalResponseBase<T>>),
+}
+
+impl<T> PortalResponse<T> {
+    pub fn error(msg: &str) -> Self {
+        Self::GenericError(Json(PortalResponseBase {
+            status: "error".to_string(),
+            message: Some(msg.to_string()),
+            data: None,
+        }))
+    }
+
+    pub fn unauthorized(msg: &str) -> Self {
+        Self::Unauthorized(Json(PortalResponseBase {
+            status: "error".to_string(),
+            message: Some(msg.to_string()),
+            data: None,
+        }))
+    }
+
+    pub fn success(msg: T) -> Self {
+        Self::Ok(Json(PortalResponseBase {
+            status: "success".to_string(),
+            message: None,
+            data: Some(msg),
+        }))
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Session {
+    pub expires: DateTime<Utc>,
+}
+
+fn portal_enabled(settings: &Settings) -> bool {
+    settings.user_portal.unwrap_or(false)
+}
+
+/// Exchange a NIP-98 event for a session cookie, the event must be recent
+#[rocket::post("/my/login")]
+async fn login(
+    auth: Nip98Auth,
+    cookies: &CookieJar<'_>,
+    db: &State<Database>,
+    settings: &State<Settings>,
+) -> PortalResponse<Session> {
+    if !portal_enabled(settings) {
+        return PortalResponse::NotFound(());
+    }
+    if auth.event.created_at.as_u64() + LOGIN_MAX_AGE < Timestamp::now().as_u64() {
+        return PortalResponse::unauthorized("Auth event is too old");
+    }
+    // sessions outlive the conditions of a delegation
+    if auth.delegator.is_some() {
+        return PortalResponse::unauthorized("Delegated auth can not be used to log in");
+    }
+    let pubkey_vec = auth.pubkey().to_bytes().to_vec();
+    let user_id = match db.upsert_user(&pubkey_vec).await {
+        Ok(u) => u,
+        Err(e) => return PortalResponse::error(&format!("Could not save user: {}", e)),
+    };
+    let token = SessionAuth::generate_token();
+    let hours = settings.portal_session_hours.unwrap_or(24);
+    let expires = Utc::now() + Duration::hours(hours as i64);
+    if let Err(e) = db
+        .add_session(user_id, &SessionAuth::hash_token(&token), expires)
+        .await
+    {
+        return PortalResponse::error(&format!("Could not create session: {}", e));
+    }
+    cookies.add(
+        Cookie::build((SESSION_COOKIE, token))
+            .path("/my")
+            .http_only(true)
+            .secure(settings.public_url.starts_with("https://"))
+            .same_site(SameSite::Strict)
+            .max_age(rocket::time::Duration::hours(hours as i64)),
+    );
+    PortalResponse::success(Session { expires })
+}
+
+#[rocket::post("/my/logout")]
+async fn logout(
+    auth: Option<SessionAuth>,
+    cookies: &CookieJar<'_>,
+    db: &State<Database>,
+) -> Redirect {
+    if let Some(auth) = auth {
+        if let Err(e) = db
+            .delete_session(&SessionAuth::hash_token(&auth.token))
+            .await
+        {
+            warn!("Failed to delete session: {}", e);
+        }
+    }
+    cookies.remove(Cookie::build(SESSION_COOKIE).path("/my"));
+    Redirect::to("/my")
+}
+
+/// Delete a file from a form on `/my`, the session cookie is `SameSite=Strict`
+/// so this cannot be submitted from other sites
+#[rocket::post("/my/delete/<sha256>")]
+async fn my_delete(
+    sha256: &str,
+    auth: SessionAuth,
+    fs: &State<FileStore>,
+    db: &State<Database>,
+    settings: &State<Settings>,
+) -> Result<Redirect, (Status, String)> {
+    if !portal_enabled(settings) {
+        return Err((Status::NotFound, "Not found".to_string()));
+    }
+    match delete_file(sha256, &auth.pubkey, fs, db, settings).await {
+        Ok(()) => Ok(Redirect::to("/my")),
+        Err(e) => match e.downcast_ref::<RequestError>() {
+            Some(r) => Err((r.status, r.message.clone())),
+            None => Err((Status::InternalServerError, e.to_string())),
+        },
+    }
+}
+
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = size as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+fn file_row(settings: &Settings, f: &FileUpload) -> String {
+    let id = hex::encode(&f.id);
+    let url = escape(&format!("{}/{}", settings.public_url, id)).to_string();
+    let name = if f.name.is_empty() {
+        id.clone()
+    } else {
+        escape(&f.name).to_string()
+    };
+    let preview = if f.thumb_mime.is_some() {
+        format!(
+            "<img src=\"{}/thumb/{}\" loading=\"lazy\">",
+            escape(&settings.public_url),
+            id
+        )
+    } else if f.mime_type.starts_with("image/") {
+        format!("<img src=\"{}\" loading=\"lazy\">", url)
+    } else {
+        String::new()
+    };
+    format!(
+        "<tr><td>{}</td><td><a href=\"{}\">{}</a></td><td>{}</td><td>{}</td><td>{}</td>\
+        <td><form method=\"post\" action=\"/my/delete/{}\" \
+        onsubmit=\"return confirm('Delete this file?')\"><button>Delete</button></form></td></tr>",
+        preview,
+        url,
+        name,
+        escape(&f.mime_type),
+        format_size(f.size),
+        f.created.format("%Y-%m-%d %H:%M"),
+        id
+    )
+}
+
+fn page_html(title: &str, body: &str) -> (ContentType, String) {
+    let html = format!(
+        "<!doctype html><html><head><meta charset=\"utf-8\">\
+        <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+        <title>{}</title>\
+        <style>body{{background:#111;color:#eee;font-family:sans-serif;margin:1em}}\
+        a{{color:#eee}}table{{border-collapse:collapse;width:100%}}\
+        td{{padding:4px 8px;border-bottom:1px solid #333}}td img{{height:48px}}\
+        nav a{{margin-right:1em}}</style></head><body>{}</body></html>",
+        title, body
+    );
+    (ContentType::HTML, html)
+}
+
+#[rocket::get("/my?<q>&<page>")]
+async fn my_files(
+    auth: SessionAuth,
+    q: Option<&str>,
+    page: Option<u32>,
+    fs: &State<FileStore>,
+    db: &State<Database>,
+    settings: &State<Settings>,
+) -> Result<(ContentType, String), Status> {
+    if !portal_enabled(settings) {
+        return Err(Status::NotFound);
+    }
+    let page = page.unwrap_or(0);
+    let q = q.map(|q| q.trim()).filter(|q| !q.is_empty());
+    let offset = page.checked_mul(PAGE_SIZE).ok_or(Status::BadRequest)?;
+    let (files, total) = match q {
+        Some(q) => db
+            .search_files(&auth.user.pubkey, q, offset, PAGE_SIZE)
+            .await
+            .map_err(|_| Status::InternalServerError)?,
+        None => db
+            .list_files(&auth.user.pubkey, offset, PAGE_SIZE)
+            .await
+            .map_err(|_| Status::InternalServerError)?,
+    };
+    let rows: String = files
+        .iter()
+        .filter(|f| !fs.is_banned(&f.id))
+        .map(|f| file_row(settings, f))
+        .collect();
+
+    let query = q
+        .map(|q| {
+            format!(
+                "&q={}",
+                url::form_urlencoded::byte_serialize(q.as_bytes()).collect::<String>()
+            )
+        })
+        .unwrap_or_default();
+    let mut nav = String::new();
+    if page > 0 {
+        nav.push_str(&format!(
+            "<a href=\"?page={}{}\">Newer</a>",
+            page - 1,
+            query
+        ));
+    }
+    if (offset as i64) + (PAGE_SIZE as i64) < total {
+        nav.push_str(&format!(
+            "<a href=\"?page={}{}\">Older</a>",
+            page + 1,
+            query
+        ));
+    }
+    let npub = auth.pubkey.to_bech32().unwrap_or_default();
+    let body = format!(
+        "<h1>My uploads</h1><p>{} &middot; {} files \
+        <form method=\"post\" action=\"/my/logout\" style=\"display:inline\"><button>Logout</button></form></p>\
+        <form method=\"get\" action=\"/my\"><input name=\"q\" value=\"{}\" placeholder=\"Search name or type\">\
+        <button>Search</button></form>\
+        <table>{}</table><nav>{}</nav>",
+        npub,
+        total,
+        escape(q.unwrap_or_default()),
+        rows,
+        nav
+    );
+    Ok(page_html("My uploads", &body))
+}
+
+/// Login page for browsers without a session, signs a NIP-98 event with a NIP-07 extension
+#[rocket::get("/my", rank = 2)]
+async fn my_login(settings: &State<Settings>) -> Result<(ContentType, String), Status> {
+    if !portal_enabled(settings) {
+        return Err(Status::NotFound);
+    }
+    let body = format!(
+        "<h1>My uploads</h1><p>Sign in with your nostr extension (NIP-07) to manage your uploads.</p>\
+        <button onclick=\"login()\">Sign in</button><p id=\"msg\"></p>\
+        <script>\
+        async function login(){{\
+        const msg=document.getElementById('msg');\
+        if(!window.nostr){{msg.textContent='No nostr extension found';return}}\
+        try{{\
+        const ev=await window.nostr.signEvent({{kind:27235,created_at:Math.floor(Date.now()/1000),\
+        tags:[['u',{}],['method','POST']],content:''}});\
+        const r=await fetch('/my/login',{{method:'POST',headers:{{authorization:'Nostr '+btoa(JSON.stringify(ev))}}}});\
+        if(r.ok){{location.reload()}}else{{msg.textContent=(await r.json()).message||r.statusText}}\
+        }}catch(e){{msg.textContent=e.message||e}}\
+        }}\
+        </script>",
+        serde_json::to_string(&format!("{}/my/login", settings.public_url)).unwrap()
+    );
+    Ok(page_html("My uploads", &body))
+}
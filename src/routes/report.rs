@@ -0,0 +1,99 @@
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{routes, Responder, Route, State};
+
+use crate::auth::nip98::Nip98Auth;
+use crate::auth::request::RequestAuth;
+use crate::db::Database;
+use crate::routes::parse_file_id;
+
+/// Longest accepted report reason
+const MAX_REASON_LEN: usize = 1024;
+
+pub fn report_routes() -> Vec<Route> {
+    routes![report_file]
+}
+
+#[derive(Serialize, Default)]
+#[serde(crate = "rocket::serde")]
+struct ReportResponseBase {
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+#[derive(Responder)]
+enum ReportResponse {
+    #[response(status = 500)]
+    GenericError(Json<ReportResponseBase>),
+
+    #[response(status = 404)]
+    NotFound(Json<ReportResponseBase>),
+
+    #[response(status = 200)]
+    Ok(Json<ReportResponseBase>),
+}
+
+impl ReportResponse {
+    fn error(msg: &str) -> Self {
+        Self::GenericError(Json(ReportResponseBase {
+            status: "error".to_string(),
+            message: Some(msg.to_string()),
+        }))
+    }
+
+    fn not_found() -> Self {
+        Self::NotFound(Json(ReportResponseBase {
+            status: "error".to_string(),
+            message: Some("File not found".to_string()),
+        }))
+    }
+
+    fn success() -> Self {
+        Self::Ok(Json(ReportResponseBase {
+            status: "success".to_string(),
+            message: None,
+        }))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ReportRequest {
+    pub reason: String,
+}
+
+/// Report a file to the server admins, reports are listed in the admin report queue
+#[rocket::post("/report/<sha256>", data = "<req>")]
+async fn report_file(
+    sha256: &str,
+    auth: RequestAuth<Nip98Auth>,
+    db: &State<Database>,
+    req: Json<ReportRequest>,
+) -> ReportResponse {
+    let pubkey = match auth.user() {
+        Some(p) => p,
+        None => return ReportResponse::error("Auth header not found"),
+    };
+    let reason = req.reason.trim();
+    if reason.is_empty() || reason.len() > MAX_REASON_LEN {
+        return ReportResponse::error("Reason must be between 1 and 1024 bytes");
+    }
+    let id = match parse_file_id(sha256) {
+        Ok(i) => i,
+        Err(_) => return ReportResponse::not_found(),
+    };
+    match db.get_file(&id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return ReportResponse::not_found(),
+        Err(e) => return ReportResponse::error(&format!("Could not get file: {}", e)),
+    }
+    let user_id = match db.upsert_user(&pubkey.to_bytes().to_vec()).await {
+        Ok(u) => u,
+        Err(e) => return ReportResponse::error(&format!("Could not save user: {}", e)),
+    };
+    match db.add_report(&id, user_id, reason).await {
+        Ok(()) => ReportResponse::success(),
+        Err(e) => ReportResponse::error(&format!("Could not save report: {}", e)),
+    }
+}
@@ -1,10 +1,16 @@
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::fs;
+use std::net::{AddrParseError, SocketAddr};
+use std::path::{Path, PathBuf};
+use url::Url;
+
+use crate::api_version::DEPRECATED_AT;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Settings {
-    /// Listen addr:port
-    pub listen: Option<String>,
+    /// Listen addr:port or `unix:/path/to.sock`, can be a list of listeners
+    pub listen: Option<ListenSettings>,
 
     /// Directory to store files
     pub storage_dir: String,
@@ -15,6 +21,10 @@ pub struct Settings {
     /// Maximum support filesize for uploading
     pub max_upload_bytes: u64,
 
+    /// Maximum number of files a user can store, can be overridden per plan.
+    /// Anonymous uploads count as one user
+    pub max_files_per_user: Option<u64>,
+
     /// Public facing url
     pub public_url: String,
 
@@ -30,6 +40,670 @@ pub struct Settings {
     /// Analytics tracking
     pub plausible_url: Option<String>,
 
+    /// Path to a MaxMind GeoLite2 (Country or City) database, used to add
+    /// the country to analytics events and download stats
+    pub geoip_database: Option<PathBuf>,
+
     #[cfg(feature = "void-cat-redirects")]
     pub void_cat_database: Option<String>,
+
+    /// Secondary storage tier for rarely accessed files
+    pub cold_storage: Option<ColdStorageSettings>,
+
+    /// Free space limits for the storage volume
+    pub disk_watermark: Option<DiskWatermarkSettings>,
+
+    /// HTTP date sent in the `Sunset` header of unversioned API routes, must be after
+    /// the deprecation date of the unversioned routes (2026-10-16)
+    pub api_sunset: Option<String>,
+
+    /// Request timeouts and body limits
+    pub request_limits: Option<RequestLimits>,
+
+    /// Require auth events to match the full request url and
+    /// include a payload hash on uploads
+    pub strict_auth: Option<bool>,
+
+    /// Allow uploads without authentication
+    pub anonymous_uploads: Option<AnonymousUploadSettings>,
+
+    /// Upload plans by id, advertised in nip96.json and enforced on upload
+    pub plans: Option<HashMap<String, PlanSettings>>,
+
+    /// Plan id for users which have no plan assigned (default "free")
+    pub default_plan: Option<String>,
+
+    /// Move files which have not been downloaded for this many days to the trash of their owners,
+    /// they are deleted after `trash_retention_days`
+    pub expire_unaccessed_days: Option<u32>,
+
+    /// Per connection download speed limits
+    pub download_throttle: Option<DownloadThrottleSettings>,
+
+    /// Media processing worker pool
+    pub processing: Option<ProcessingSettings>,
+
+    /// Media compression / transcoding parameters
+    pub compression: Option<CompressionSettings>,
+
+    /// Keep deleted files in the trash for this many days so they can be restored,
+    /// 0 deletes files immediately (default 7)
+    pub trash_retention_days: Option<u32>,
+
+    /// External lists of banned file hashes
+    pub blocklist: Option<BlocklistSettings>,
+
+    /// Send DMs to users from the server's nostr key (takedown notices)
+    pub notifications: Option<NotificationSettings>,
+
+    /// Accept SVG uploads, scripts and external references are always removed (default true)
+    pub allow_svg: Option<bool>,
+
+    /// Compute a BLAKE3 digest of uploads in addition to SHA-256 (default false)
+    pub blake3: Option<bool>,
+
+    /// Compress text-like responses (JSON, SVG, subtitles) with brotli / gzip (default true)
+    pub response_compression: Option<bool>,
+
+    /// Show uploads marked as public on the `/feed` gallery (default false)
+    pub public_feed: Option<bool>,
+
+    /// Seconds an upload result is kept for retries with the same `Idempotency-Key` (default 86400)
+    pub idempotency_ttl: Option<u64>,
+
+    /// Enable the `/my` pages where users can browse and delete their uploads (default false)
+    pub user_portal: Option<bool>,
+
+    /// Hours until a `/my` login session expires (default 24)
+    pub portal_session_hours: Option<u32>,
+
+    /// Seconds to wait for uploads and processing jobs to finish on shutdown (default 30)
+    pub shutdown_timeout: Option<u64>,
+
+    /// Certificate for listeners with `tls = true` (`tls` feature)
+    pub tls: Option<TlsSettings>,
+}
+
+impl Settings {
+    /// Configured plans, a single "free" plan using [Settings::max_upload_bytes]
+    /// is used when no plans are configured
+    pub fn plans(&self) -> HashMap<String, PlanSettings> {
+        match &self.plans {
+            Some(p) => p.clone(),
+            None => HashMap::from([(
+                "free".to_string(),
+                PlanSettings {
+                    name: "Free".to_string(),
+                    max_upload_bytes: self.max_upload_bytes,
+                    expiration_days: None,
+                    require_nostr_auth: None,
+                    url: None,
+                    bandwidth_bytes: None,
+                    bandwidth_throttle: None,
+                    max_files: None,
+                    max_storage_bytes: None,
+                },
+            )]),
+        }
+    }
+
+    /// Get the plan for a user, falling back to the default plan
+    pub fn user_plan(&self, plan: Option<&str>) -> Option<PlanSettings> {
+        let mut plans = self.plans();
+        let default_plan = self.default_plan.as_deref().unwrap_or("free");
+        plan.and_then(|p| plans.remove(p))
+            .or_else(|| plans.remove(default_plan))
+    }
+
+    /// Check for mistakes which would otherwise only fail at runtime (eg. on the first upload),
+    /// returns a description of each problem
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        check_writable(&mut errors, "storage_dir", &self.storage_dir);
+        if let Some(cold) = &self.cold_storage {
+            check_writable(&mut errors, "cold_storage.storage_dir", &cold.storage_dir);
+        }
+        if self.max_upload_bytes == 0 {
+            errors.push("max_upload_bytes must be greater than 0".to_string());
+        }
+
+        check_url(
+            &mut errors,
+            "public_url",
+            &self.public_url,
+            &["http", "https"],
+        );
+        if self.public_url.ends_with('/') {
+            errors.push(format!(
+                "public_url '{}' must not end with '/'",
+                self.public_url
+            ));
+        }
+        if let Some(l) = &self.request_limits {
+            if l.upload_buffer_size == Some(0) {
+                errors.push("request_limits.upload_buffer_size must be greater than 0".to_string());
+            }
+            if l.upload_idle_timeout == Some(0) {
+                errors
+                    .push("request_limits.upload_idle_timeout must be greater than 0".to_string());
+            }
+            if l.max_header_bytes == Some(0) {
+                errors.push("request_limits.max_header_bytes must be greater than 0".to_string());
+            }
+            for (path, r) in l.routes.iter().flatten() {
+                if !path.starts_with('/') {
+                    errors.push(format!(
+                        "request_limits.routes '{}' must start with '/'",
+                        path
+                    ));
+                }
+                if r.upload_idle_timeout == Some(0) {
+                    errors.push(format!(
+                        "request_limits.routes '{}' upload_idle_timeout must be greater than 0",
+                        path
+                    ));
+                }
+            }
+        }
+        if let Some(s) = &self.api_sunset {
+            match chrono::DateTime::parse_from_rfc2822(s) {
+                Ok(d) if d.timestamp() <= DEPRECATED_AT => errors.push(format!(
+                    "api_sunset '{}' must be after the deprecation date (2026-10-16)",
+                    s
+                )),
+                Ok(_) => {}
+                Err(_) => errors.push(format!("api_sunset '{}' is not an HTTP date", s)),
+            }
+        }
+        if let Some(u) = &self.webhook_url {
+            check_url(&mut errors, "webhook_url", u, &["http", "https"]);
+        }
+        if let Some(u) = &self.plausible_url {
+            check_url(&mut errors, "plausible_url", u, &["http", "https"]);
+        }
+        if let Some(b) = &self.blocklist {
+            for u in &b.urls {
+                check_url(&mut errors, "blocklist.urls", u, &["http", "https"]);
+            }
+        }
+        if let Some(n) = &self.notifications {
+            if nostr_sdk::Keys::parse(&n.nostr_key).is_err() {
+                errors.push("notifications.nostr_key is not a valid nsec / hex key".to_string());
+            }
+            for r in &n.relays {
+                check_url(&mut errors, "notifications.relays", r, &["ws", "wss"]);
+            }
+        }
+
+        if let Some(p) = &self.vit_model_path {
+            check_exists(&mut errors, "vit_model_path", p);
+        }
+        if let Some(p) = &self.geoip_database {
+            check_exists(&mut errors, "geoip_database", p);
+        }
+
+        if let Some(wl) = &self.whitelist {
+            for pk in wl {
+                if pk.len() != 64 || hex::decode(pk).is_err() {
+                    errors.push(format!("whitelist entry '{}' is not a hex pubkey", pk));
+                }
+            }
+        }
+        if let (Some(_), Some(default_plan)) = (&self.plans, &self.default_plan) {
+            if !self.plans().contains_key(default_plan) {
+                errors.push(format!("default_plan '{}' is not in plans", default_plan));
+            }
+        }
+
+        let listeners = self
+            .listen
+            .as_ref()
+            .map(|l| l.listeners())
+            .unwrap_or_default();
+        for l in &listeners {
+            match l.parse() {
+                Ok(ListenAddr::Unix(_)) if l.mode().is_some() => errors.push(format!(
+                    "listener '{}' sets mode, socket permissions are not supported as the socket \
+                    is forwarded to a loopback port",
+                    l.address()
+                )),
+                Ok(_) => {}
+                Err(e) => errors.push(format!(
+                    "listen address '{}' is invalid: {}",
+                    l.address(),
+                    e
+                )),
+            }
+        }
+        if listeners.iter().any(|l| l.tls()) && self.tls.is_none() {
+            errors.push("listeners with tls = true require the [tls] settings".to_string());
+        }
+        if let Some(tls) = &self.tls {
+            match &tls.acme {
+                Some(acme) if acme.domains.is_empty() => {
+                    errors.push("tls.acme.domains must not be empty".to_string())
+                }
+                Some(_) => {}
+                None => {
+                    check_exists(&mut errors, "tls.cert", Path::new(&tls.cert));
+                    check_exists(&mut errors, "tls.key", Path::new(&tls.key));
+                }
+            }
+        }
+
+        errors
+    }
+}
+
+fn check_url(errors: &mut Vec<String>, name: &str, value: &str, schemes: &[&str]) {
+    match Url::parse(value) {
+        Ok(u) if !schemes.contains(&u.scheme()) => errors.push(format!(
+            "{} '{}' must be a {} url",
+            name,
+            value,
+            schemes.join(" / ")
+        )),
+        Ok(u) if u.host_str().is_none() => errors.push(format!("{} '{}' has no host", name, value)),
+        Ok(_) => {}
+        Err(e) => errors.push(format!("{} '{}' is not a valid url: {}", name, value, e)),
+    }
+}
+
+fn check_exists(errors: &mut Vec<String>, name: &str, path: &Path) {
+    if !path.exists() {
+        errors.push(format!("{} '{}' does not exist", name, path.display()));
+    }
+}
+
+/// Check a directory can be created and written to
+fn check_writable(errors: &mut Vec<String>, name: &str, dir: &str) {
+    let probe = Path::new(dir).join(".route96-write-test");
+    let res = fs::create_dir_all(dir)
+        .and_then(|_| fs::write(&probe, b""))
+        .and_then(|_| fs::remove_file(&probe));
+    if let Err(e) = res {
+        errors.push(format!("{} '{}' is not writable: {}", name, dir, e));
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ListenSettings {
+    One(ListenerSettings),
+    Many(Vec<ListenerSettings>),
+}
+
+impl ListenSettings {
+    pub fn listeners(&self) -> Vec<ListenerSettings> {
+        match self {
+            ListenSettings::One(l) => vec![l.clone()],
+            ListenSettings::Many(l) => l.clone(),
+        }
+    }
+}
+
+/// A listener address or a table with listener options
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ListenerSettings {
+    Address(String),
+    Options(ListenerOptions),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListenerOptions {
+    /// Listen addr:port or `unix:/path/to.sock`
+    pub address: String,
+
+    /// Not supported, unix sockets are forwarded to a loopback TCP port which any local user
+    /// can connect to, so file permissions can not restrict access. Listeners setting this are rejected
+    pub mode: Option<u32>,
+
+    /// Only serve the admin API on this listener, the admin API is removed from all other listeners
+    pub admin_only: Option<bool>,
+
+    /// Serve HTTPS on this listener with the certificate from [Settings::tls]
+    pub tls: Option<bool>,
+}
+
+/// Parsed listener address
+#[derive(Debug, Clone)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl ListenerSettings {
+    pub fn address(&self) -> &str {
+        match self {
+            ListenerSettings::Address(a) => a,
+            ListenerSettings::Options(o) => &o.address,
+        }
+    }
+
+    pub fn mode(&self) -> Option<u32> {
+        match self {
+            ListenerSettings::Address(_) => None,
+            ListenerSettings::Options(o) => o.mode,
+        }
+    }
+
+    pub fn admin_only(&self) -> bool {
+        match self {
+            ListenerSettings::Address(_) => false,
+            ListenerSettings::Options(o) => o.admin_only.unwrap_or(false),
+        }
+    }
+
+    pub fn tls(&self) -> bool {
+        match self {
+            ListenerSettings::Address(_) => false,
+            ListenerSettings::Options(o) => o.tls.unwrap_or(false),
+        }
+    }
+
+    pub fn parse(&self) -> Result<ListenAddr, AddrParseError> {
+        match self.address().strip_prefix("unix:") {
+            Some(path) => Ok(ListenAddr::Unix(PathBuf::from(path))),
+            None => Ok(ListenAddr::Tcp(self.address().parse()?)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsSettings {
+    /// Certificate chain (PEM), written here when ACME is enabled
+    pub cert: String,
+
+    /// Private key (PEM), written here when ACME is enabled
+    pub key: String,
+
+    /// Request and renew the certificate from an ACME CA (Let's Encrypt)
+    pub acme: Option<AcmeSettings>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AcmeSettings {
+    /// Domains on the certificate
+    pub domains: Vec<String>,
+
+    /// Contact email for expiry notices
+    pub email: Option<String>,
+
+    /// ACME directory url (default Let's Encrypt production)
+    pub directory: Option<String>,
+
+    /// File to store the ACME account credentials (default `<cert>.account.json`)
+    pub account: Option<String>,
+
+    /// Renew the certificate when it is older than this many days (default 60)
+    pub renew_after_days: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ColdStorageSettings {
+    /// Directory to store cold files (HDD, mounted bucket etc.)
+    pub storage_dir: String,
+
+    /// Move files to cold storage after this many days without access
+    pub after_days: u32,
+
+    /// How often to run the tiering job in seconds (default 1hr)
+    pub interval: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiskWatermarkSettings {
+    /// Free bytes below which the server advertises reduced upload limits
+    pub soft_bytes: u64,
+
+    /// Free bytes below which all uploads are rejected
+    pub hard_bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestLimits {
+    /// HTTP keep-alive timeout in seconds, 0 disables keep-alive
+    pub keep_alive: Option<u32>,
+
+    /// Abort uploads when no data was received for this many seconds (default 60)
+    pub upload_idle_timeout: Option<u64>,
+
+    /// Abort uploads which take longer than this many seconds to receive (default unlimited)
+    pub read_timeout: Option<u64>,
+
+    /// Buffer size used when streaming uploads to disk (default 64KiB)
+    pub upload_buffer_size: Option<usize>,
+
+    /// Requests with more bytes of headers (names, values and the request target) are
+    /// rejected with `431 Request Header Fields Too Large`
+    pub max_header_bytes: Option<usize>,
+
+    /// Body size limits by data type (json, form, data-form, file, bytes)
+    pub body_limits: Option<HashMap<String, u64>>,
+
+    /// Upload limits by route path as declared, eg. `/upload`, `/n96` or `/multipart/<id>/<part>`,
+    /// these apply to the unversioned and `/v1` routes
+    pub routes: Option<HashMap<String, RouteLimits>>,
+}
+
+/// Overrides of [RequestLimits] for a single route
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouteLimits {
+    pub upload_idle_timeout: Option<u64>,
+
+    pub read_timeout: Option<u64>,
+
+    /// Max size of the request body, lower limits of the plan or data type still apply
+    pub max_body_bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnonymousUploadSettings {
+    /// Maximum file size for anonymous uploads
+    pub max_upload_bytes: u64,
+
+    /// Delete anonymous uploads after this many days
+    pub ttl_days: u32,
+
+    /// Maximum number of uploads per IP per hour
+    pub uploads_per_hour: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlanSettings {
+    /// Display name
+    pub name: String,
+
+    /// Maximum file size for uploads on this plan, capped by [Settings::max_upload_bytes]
+    pub max_upload_bytes: u64,
+
+    /// Delete uploads after this many days, no expiration when not set
+    pub expiration_days: Option<u32>,
+
+    /// Only accept uploads signed with nostr auth events, API keys are rejected
+    pub require_nostr_auth: Option<bool>,
+
+    /// Landing page for this plan
+    pub url: Option<String>,
+
+    /// Monthly download bandwidth allowance for files uploaded by users on this plan
+    pub bandwidth_bytes: Option<u64>,
+
+    /// Throttle downloads to this many bytes/s once the bandwidth allowance is used up,
+    /// downloads are blocked until the next month when not set
+    pub bandwidth_throttle: Option<u64>,
+
+    /// Maximum number of files a user on this plan can store, overrides [Settings::max_files_per_user]
+    pub max_files: Option<u64>,
+
+    /// Total size of the files a user on this plan can store, including their variants
+    /// (thumbnails, transcodes, conversions)
+    pub max_storage_bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DownloadThrottleSettings {
+    /// Maximum download speed in bytes/s for all files
+    pub rate: Option<u64>,
+
+    /// Maximum download speed in bytes/s by mime type, eg. `video/mp4` or `video/*`
+    pub mime_types: Option<HashMap<String, u64>>,
+}
+
+impl DownloadThrottleSettings {
+    /// Speed limit for a mime type, the most specific match is used
+    pub fn rate_for(&self, mime_type: &str) -> Option<u64> {
+        self.mime_types
+            .as_ref()
+            .and_then(|m| match_mime_type(m, mime_type))
+            .or(self.rate)
+    }
+}
+
+/// Lookup a mime type in a map which may contain wildcard entries like `video/*`,
+/// exact matches take precedence
+fn match_mime_type<T: Copy>(map: &HashMap<String, T>, mime_type: &str) -> Option<T> {
+    if let Some(v) = map.get(mime_type) {
+        return Some(*v);
+    }
+    let (t, _) = mime_type.split_once('/')?;
+    map.get(&format!("{}/*", t)).copied()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProcessingSettings {
+    /// Number of files processed concurrently (default number of CPUs)
+    pub workers: Option<usize>,
+
+    /// Max number of jobs waiting for a worker (default 100)
+    pub queue_size: Option<usize>,
+
+    /// Fail jobs which take longer than this many seconds (default 300)
+    pub timeout: Option<u64>,
+
+    /// Number of times a queued background job is tried before giving up (default 5)
+    pub max_attempts: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlocklistSettings {
+    /// URLs of newline delimited sha256 lists
+    pub urls: Vec<String>,
+
+    /// How often to reload the lists in seconds (default 1hr)
+    pub interval: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NotificationSettings {
+    /// Server nostr private key (nsec / hex)
+    pub nostr_key: String,
+
+    /// Relays to publish DMs to
+    pub relays: Vec<String>,
+
+    /// Send NIP-17 private messages instead of NIP-04 DMs
+    pub nip17: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VideoCodec {
+    #[default]
+    H264,
+    Av1,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    #[default]
+    Webp,
+    Avif,
+}
+
+impl ImageFormat {
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            ImageFormat::Webp => "image/webp",
+            ImageFormat::Avif => "image/avif",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Webp => "webp",
+            ImageFormat::Avif => "avif",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CompressionSettings {
+    /// Image output formats in order of preference, the next format is tried
+    /// when encoding fails (default `["webp"]`)
+    pub image_formats: Option<Vec<ImageFormat>>,
+
+    /// Image encoder quality 0-100 (default 80)
+    pub image_quality: Option<f32>,
+
+    /// AVIF encoder quality 0-100, overrides `image_quality` for AVIF output
+    pub avif_quality: Option<f32>,
+
+    /// Scale images down to fit within this width
+    pub max_image_width: Option<u32>,
+
+    /// Scale images down to fit within this height
+    pub max_image_height: Option<u32>,
+
+    /// Codec used when transcoding videos (default h264)
+    pub video_codec: Option<VideoCodec>,
+
+    /// Constant rate factor for video encoding, lower is better quality (default 23)
+    pub video_crf: Option<u8>,
+
+    /// Encoder preset for h264 (default medium)
+    pub video_preset: Option<String>,
+
+    /// Scale videos down to fit within this width
+    pub max_video_width: Option<u32>,
+
+    /// Scale videos down to fit within this height
+    pub max_video_height: Option<u32>,
+
+    /// Max width / height of preview images generated for images, videos and PDFs (default 640)
+    pub thumbnail_size: Option<u32>,
+
+    /// Enable or disable compression by mime type, eg. `{ "image/gif" = false, "video/*" = true }`,
+    /// by default only images are compressed
+    pub mime_types: Option<HashMap<String, bool>>,
+}
+
+impl CompressionSettings {
+    /// Check if files of this mime type should be compressed
+    pub fn is_enabled(&self, mime_type: &str) -> bool {
+        self.mime_types
+            .as_ref()
+            .and_then(|m| match_mime_type(m, mime_type))
+            .unwrap_or(mime_type.starts_with("image/"))
+    }
+
+    /// Image output formats in order of preference
+    pub fn image_formats(&self) -> Vec<ImageFormat> {
+        match &self.image_formats {
+            Some(f) if !f.is_empty() => f.clone(),
+            _ => vec![ImageFormat::Webp],
+        }
+    }
+
+    /// Encoder quality 0-100 for an image format
+    pub fn image_quality(&self, format: ImageFormat) -> f32 {
+        let q = match format {
+            ImageFormat::Avif => self.avif_quality.or(self.image_quality),
+            ImageFormat::Webp => self.image_quality,
+        };
+        q.unwrap_or(80.0).clamp(0.0, 100.0)
+    }
 }
@@ -0,0 +1,107 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{info, warn};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{async_trait, Orbit, Rocket};
+use tokio::sync::Notify;
+
+use crate::filesystem::FileStore;
+use crate::stats::DownloadStats;
+
+/// Tracks in-flight uploads and processing jobs so they can finish before the server exits
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    draining: Arc<AtomicBool>,
+    active: Arc<AtomicUsize>,
+    idle: Arc<Notify>,
+}
+
+/// Marks a unit of work as running until dropped
+pub struct ActiveGuard(Shutdown);
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        if self.0.active.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Server is shutting down, no new work should be started
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Start a unit of work, [None] when the server is shutting down
+    pub fn begin(&self) -> Option<ActiveGuard> {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        let guard = ActiveGuard(self.clone());
+        if self.is_draining() {
+            return None;
+        }
+        Some(guard)
+    }
+
+    /// Stop accepting new work and wait for running work to complete,
+    /// returns the number of tasks still running after `timeout`
+    pub async fn drain(&self, timeout: Duration) -> usize {
+        self.draining.store(true, Ordering::SeqCst);
+        let wait = async {
+            loop {
+                let idle = self.idle.notified();
+                if self.active.load(Ordering::SeqCst) == 0 {
+                    break;
+                }
+                idle.await;
+            }
+        };
+        let _ = tokio::time::timeout(timeout, wait).await;
+        self.active.load(Ordering::SeqCst)
+    }
+}
+
+/// Drains uploads and processing jobs on shutdown, then writes pending stats
+/// and removes temporary files
+pub struct GracefulShutdown {
+    fs: FileStore,
+    stats: DownloadStats,
+    timeout: Duration,
+}
+
+impl GracefulShutdown {
+    pub fn new(fs: FileStore, stats: DownloadStats, timeout: Duration) -> Self {
+        Self { fs, stats, timeout }
+    }
+}
+
+#[async_trait]
+impl Fairing for GracefulShutdown {
+    fn info(&self) -> Info {
+        Info {
+            name: "Graceful shutdown",
+            kind: Kind::Shutdown,
+        }
+    }
+
+    async fn on_shutdown(&self, _rocket: &Rocket<Orbit>) {
+        info!(
+            "Shutting down, waiting up to {:?} for uploads and jobs",
+            self.timeout
+        );
+        let running = self.fs.shutdown().drain(self.timeout).await;
+        if running > 0 {
+            warn!("{} uploads / jobs did not finish before shutdown", running);
+        }
+        if let Err(e) = self.stats.flush().await {
+            warn!("Failed to write download stats: {}", e);
+        }
+        self.fs.clean_temp();
+    }
+}
@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use anyhow::Error;
+use log::error;
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+use tokio::task::JoinHandle;
+
+use crate::db::Database;
+
+#[derive(Clone, Copy, Default)]
+struct PendingStats {
+    downloads: u64,
+    bytes: u64,
+}
+
+/// Counts file downloads and bytes served in memory and writes them to the database in batches
+#[derive(Clone)]
+pub struct DownloadStats {
+    db: Database,
+    pending: Arc<Mutex<HashMap<Vec<u8>, PendingStats>>>,
+    /// Downloads by country code since startup
+    countries: Arc<Mutex<HashMap<String, u64>>>,
+    interval: Duration,
+}
+
+impl DownloadStats {
+    pub fn new(db: Database) -> Self {
+        Self {
+            db,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            countries: Arc::new(Mutex::new(HashMap::new())),
+            interval: Duration::from_secs(60),
+        }
+    }
+
+    /// Count a download of a file
+    pub fn track(&self, id: &[u8]) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.entry(id.to_vec()).or_default().downloads += 1;
+    }
+
+    /// Count bytes of a file sent to a client
+    pub fn track_bytes(&self, id: &[u8], bytes: u64) {
+        if bytes == 0 {
+            return;
+        }
+        let mut pending = self.pending.lock().unwrap();
+        pending.entry(id.to_vec()).or_default().bytes += bytes;
+    }
+
+    /// Serve at most `len` bytes of `inner`, counting the bytes which were actually read
+    pub fn reader<R>(&self, id: &[u8], inner: R, len: u64) -> SentBytesReader<R> {
+        SentBytesReader {
+            inner,
+            remaining: len,
+            sent: 0,
+            id: id.to_vec(),
+            stats: self.clone(),
+        }
+    }
+
+    /// Count a download from a country
+    pub fn track_country(&self, country: &str) {
+        let mut countries = self.countries.lock().unwrap();
+        *countries.entry(country.to_string()).or_default() += 1;
+    }
+
+    /// Downloads by country code since startup
+    pub fn countries(&self) -> HashMap<String, u64> {
+        self.countries.lock().unwrap().clone()
+    }
+
+    pub fn start(&self) -> JoinHandle<()> {
+        let stats = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(stats.interval).await;
+                if let Err(e) = stats.flush().await {
+                    error!("Failed to write download stats: {}", e);
+                }
+            }
+        })
+    }
+
+    /// Write pending download counts to the database
+    pub async fn flush(&self) -> Result<(), Error> {
+        let batch = std::mem::take(&mut *self.pending.lock().unwrap());
+        let mut iter = batch.into_iter();
+        while let Some((id, mut stats)) = iter.next() {
+            let res = match self.db.add_downloads(&id, stats.downloads).await {
+                Ok(()) => {
+                    stats.downloads = 0;
+                    self.db.add_bandwidth(&id, stats.bytes).await
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = res {
+                // keep the counts which were not written for the next run
+                let mut pending = self.pending.lock().unwrap();
+                for (id, stats) in std::iter::once((id, stats)).chain(iter) {
+                    let e = pending.entry(id).or_default();
+                    e.downloads += stats.downloads;
+                    e.bytes += stats.bytes;
+                }
+                return Err(e.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Body of a download, the bytes read are added to the bandwidth of the file when dropped
+/// so aborted downloads only count what was sent
+pub struct SentBytesReader<R> {
+    inner: R,
+    remaining: u64,
+    sent: u64,
+    id: Vec<u8>,
+    stats: DownloadStats,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for SentBytesReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.remaining == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        let allowed = this.remaining.min(buf.remaining() as u64) as usize;
+        let mut limited = buf.take(allowed);
+        let res = Pin::new(&mut this.inner).poll_read(cx, &mut limited);
+        let n = limited.filled().len();
+        if let Poll::Ready(Ok(())) = res {
+            // SAFETY: the bytes were initialized by the inner reader
+            unsafe {
+                buf.assume_init(n);
+            }
+            buf.advance(n);
+            this.remaining -= n as u64;
+            this.sent += n as u64;
+        }
+        res
+    }
+}
+
+/// Responses set the body size up front, seeking is only passed through
+impl<R: AsyncSeek + Unpin> AsyncSeek for SentBytesReader<R> {
+    fn start_seek(mut self: Pin<&mut Self>, position: io::SeekFrom) -> io::Result<()> {
+        Pin::new(&mut self.inner).start_seek(position)
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Pin::new(&mut self.inner).poll_complete(cx)
+    }
+}
+
+impl<R> Drop for SentBytesReader<R> {
+    fn drop(&mut self) {
+        self.stats.track_bytes(&self.id, self.sent);
+    }
+}
@@ -0,0 +1,358 @@
+use anyhow::{bail, Result};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::{Reader, Writer};
+
+const SVG_NS: &str = "http://www.w3.org/2000/svg";
+const XLINK_NS: &str = "http://www.w3.org/1999/xlink";
+
+/// Elements which are removed together with their children
+const BLOCKED_ELEMENTS: [&str; 13] = [
+    "script",
+    "foreignobject",
+    "iframe",
+    "frame",
+    "embed",
+    "object",
+    "applet",
+    "meta",
+    "link",
+    "base",
+    "form",
+    "handler",
+    "listener",
+];
+
+/// Data URIs which can be used in `<image href="...">`
+const ALLOWED_DATA_URIS: [&str; 4] = [
+    "data:image/png",
+    "data:image/jpeg",
+    "data:image/gif",
+    "data:image/webp",
+];
+
+/// Number of bytes read from the start of an upload to detect SVG documents
+pub const SNIFF_LEN: usize = 8192;
+
+/// MIME type is `image/svg+xml`, ignoring parameters and case
+pub fn is_svg_mime(mime_type: &str) -> bool {
+    mime_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .eq_ignore_ascii_case("image/svg+xml")
+}
+
+/// Detect an SVG document from the start of a file, the first element after the XML
+/// declaration, doctype and comments is `<svg>`.
+///
+/// When `head` is only the start of the file and ends before the first element,
+/// the file is treated as an SVG so it is checked by [sanitize_svg].
+pub fn sniff_svg(head: &[u8], truncated: bool) -> bool {
+    let head = head.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(head);
+    let mut reader = Reader::from_reader(head);
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) | Ok(Event::Empty(e)) => return is_svg_root(&e),
+            Ok(Event::Text(e)) if e.iter().all(|b| b.is_ascii_whitespace()) => {}
+            Ok(Event::Decl(_))
+            | Ok(Event::DocType(_))
+            | Ok(Event::PI(_))
+            | Ok(Event::Comment(_)) => {}
+            Ok(Event::Eof) | Err(_) => return truncated,
+            Ok(_) => return false,
+        }
+    }
+}
+
+/// Strip scripts, event handlers and external references from an SVG document.
+///
+/// Anything which could not be parsed as XML is rejected.
+pub fn sanitize_svg(input: &[u8]) -> Result<Vec<u8>> {
+    let mut reader = Reader::from_reader(input);
+    let mut writer = Writer::new(Vec::with_capacity(input.len()));
+
+    let mut has_root = false;
+    let mut skip_depth = 0usize;
+    let mut in_style = false;
+    loop {
+        let ev = reader.read_event()?;
+        if skip_depth > 0 {
+            match ev {
+                Event::Start(_) => skip_depth += 1,
+                Event::End(_) => skip_depth -= 1,
+                Event::Eof => bail!("Unexpected end of document"),
+                _ => {}
+            }
+            continue;
+        }
+        match ev {
+            Event::Start(e) | Event::Empty(e) if !has_root && !is_svg_root(&e) => {
+                bail!("Document is not an SVG");
+            }
+            Event::Start(e) => {
+                has_root = true;
+                match sanitize_element(&e)? {
+                    Some(e) => {
+                        in_style = element_name(&e) == "style";
+                        writer.write_event(Event::Start(e))?;
+                    }
+                    None => skip_depth = 1,
+                }
+            }
+            Event::Empty(e) => {
+                has_root = true;
+                if let Some(e) = sanitize_element(&e)? {
+                    writer.write_event(Event::Empty(e))?;
+                }
+            }
+            Event::End(e) => {
+                in_style = false;
+                writer.write_event(Event::End(e))?;
+            }
+            Event::Text(e) => {
+                if !in_style || is_safe_css(&String::from_utf8_lossy(&e)) {
+                    writer.write_event(Event::Text(e))?;
+                }
+            }
+            Event::CData(e) => {
+                if !in_style || is_safe_css(&String::from_utf8_lossy(&e)) {
+                    writer.write_event(Event::CData(e))?;
+                }
+            }
+            Event::Decl(e) => writer.write_event(Event::Decl(e))?,
+            // doctypes can declare entities, stylesheets can be loaded with processing instructions
+            Event::DocType(_) | Event::PI(_) | Event::Comment(_) => {}
+            Event::Eof => break,
+        }
+    }
+    if !has_root {
+        bail!("Document is not an SVG");
+    }
+    Ok(writer.into_inner())
+}
+
+fn is_svg_root(e: &BytesStart) -> bool {
+    element_name(e) == "svg"
+}
+
+fn element_name(e: &BytesStart) -> String {
+    String::from_utf8_lossy(e.local_name().as_ref()).to_lowercase()
+}
+
+/// Copy an element keeping only safe attributes, returns [None] when the element should be removed
+fn sanitize_element(e: &BytesStart) -> Result<Option<BytesStart<'static>>> {
+    let name = element_name(e);
+    // only elements in the default (svg) namespace are allowed
+    let prefixed = e.name().prefix().is_some_and(|p| p.as_ref() != b"svg");
+    if prefixed || BLOCKED_ELEMENTS.contains(&name.as_str()) {
+        return Ok(None);
+    }
+
+    let mut out = BytesStart::new(String::from_utf8_lossy(e.name().as_ref()).into_owned());
+    for attr in e.attributes() {
+        let attr = attr?;
+        let raw_key = String::from_utf8_lossy(attr.key.as_ref());
+        let key = raw_key.to_lowercase();
+        let local_key = String::from_utf8_lossy(attr.key.local_name().as_ref()).to_lowercase();
+        let value =
+            quick_xml::escape::unescape(&String::from_utf8_lossy(&attr.value))?.into_owned();
+
+        // animations can be used to set the href or event handlers
+        if (name == "set" || name.starts_with("animate")) && local_key == "attributename" {
+            let target = value.trim().to_lowercase();
+            if target.ends_with("href") || target.starts_with("on") {
+                return Ok(None);
+            }
+        }
+        if is_safe_attribute(&name, &key, &local_key, &value) {
+            // re-escape the value, single quoted values may contain double quotes
+            out.push_attribute((raw_key.as_ref(), value.as_str()));
+        }
+    }
+    Ok(Some(out))
+}
+
+fn is_safe_attribute(element: &str, key: &str, local_key: &str, value: &str) -> bool {
+    if key == "xmlns" {
+        return value == SVG_NS;
+    }
+    if let Some(prefix) = key.split_once(':').map(|(p, _)| p) {
+        match prefix {
+            "xmlns" => return value == SVG_NS || value == XLINK_NS,
+            "xlink" | "xml" => {}
+            _ => return false,
+        }
+    }
+    if local_key.starts_with("on") || local_key == "src" {
+        return false;
+    }
+    let compact: String = value
+        .chars()
+        .filter(|c| !c.is_whitespace() && !c.is_control())
+        .collect::<String>()
+        .to_lowercase();
+    if compact.contains("javascript:") || compact.contains("vbscript:") {
+        return false;
+    }
+    if local_key == "href" {
+        let is_image = element == "image" || element == "feimage";
+        return compact.starts_with('#')
+            || (is_image && ALLOWED_DATA_URIS.iter().any(|u| compact.starts_with(u)));
+    }
+    is_safe_css(value)
+}
+
+/// Check a stylesheet / attribute value does not load external resources
+fn is_safe_css(css: &str) -> bool {
+    let css = css
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_lowercase();
+    if css.contains('\\')
+        || css.contains("@import")
+        || css.contains("expression(")
+        || css.contains("javascript:")
+    {
+        return false;
+    }
+    // only references to elements in the same document are allowed
+    css.match_indices("url(").all(|(i, m)| {
+        css[i + m.len()..]
+            .trim_start_matches(['"', '\''])
+            .starts_with('#')
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sanitize(svg: &str) -> String {
+        String::from_utf8(sanitize_svg(svg.as_bytes()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn removes_scripts() {
+        let out = sanitize(
+            r#"<svg xmlns="http://www.w3.org/2000/svg"><script>alert(1)</script><rect width="1"/></svg>"#,
+        );
+        assert!(!out.contains("script"));
+        assert!(!out.contains("alert"));
+        assert!(out.contains(r#"<rect width="1"/>"#));
+    }
+
+    #[test]
+    fn removes_event_handlers() {
+        let out = sanitize(r#"<svg onload="alert(1)"><rect OnClick="alert(2)" width="1"/></svg>"#);
+        assert!(!out.to_lowercase().contains("onload"));
+        assert!(!out.to_lowercase().contains("onclick"));
+        assert!(out.contains(r#"width="1""#));
+    }
+
+    #[test]
+    fn removes_script_urls() {
+        let out = sanitize(r#"<svg><a href="javascript:alert(1)"><text>x</text></a></svg>"#);
+        assert!(!out.contains("javascript"));
+        // entities and whitespace inside the scheme
+        let out = sanitize(r#"<svg><a href="java&#x09;script:alert(1)"/></svg>"#);
+        assert!(!out.contains("alert"));
+    }
+
+    #[test]
+    fn removes_external_references() {
+        let out = sanitize(r#"<svg><image href="https://example.com/x.png"/></svg>"#);
+        assert!(!out.contains("example.com"));
+        let out =
+            sanitize(r##"<svg><use href="#a"/><image href="data:image/png;base64,AA=="/></svg>"##);
+        assert!(out.contains(r##"href="#a""##));
+        assert!(out.contains("data:image/png"));
+        let out = sanitize(r#"<svg><use href="data:image/svg+xml;base64,AA=="/></svg>"#);
+        assert!(!out.contains("data:"));
+    }
+
+    #[test]
+    fn checks_xlink_namespace() {
+        let out = sanitize(
+            r##"<svg xmlns:xlink="http://www.w3.org/1999/xlink"><a xlink:href="javascript:alert(1)"/><use xlink:href="#a"/></svg>"##,
+        );
+        assert!(!out.contains("javascript"));
+        assert!(out.contains(r##"xlink:href="#a""##));
+        let out = sanitize(
+            r#"<svg xmlns:h="http://www.w3.org/1999/xhtml"><h:script>alert(1)</h:script></svg>"#,
+        );
+        assert!(!out.contains("alert"));
+        assert!(!out.contains("xhtml"));
+    }
+
+    #[test]
+    fn removes_animated_handlers() {
+        let out = sanitize(r#"<svg><set attributeName="onclick" to="alert(1)"/></svg>"#);
+        assert!(!out.contains("alert"));
+        let out =
+            sanitize(r#"<svg><animate attributeName="href" values="https://example.com"/></svg>"#);
+        assert!(!out.contains("example.com"));
+    }
+
+    #[test]
+    fn removes_external_styles() {
+        let out = sanitize(r#"<svg><style>rect{fill:url(https://example.com/x)}</style></svg>"#);
+        assert!(!out.contains("example.com"));
+        let out = sanitize(r#"<svg><style>@import 'https://example.com/x.css';</style></svg>"#);
+        assert!(!out.contains("example.com"));
+        let out = sanitize(
+            r#"<svg><style><![CDATA[rect{fill:url(https://example.com/x)}]]></style></svg>"#,
+        );
+        assert!(!out.contains("example.com"));
+        let out = sanitize(r#"<svg><rect style="fill:url( 'https://example.com/x')"/></svg>"#);
+        assert!(!out.contains("example.com"));
+        let out = sanitize(r##"<svg><style>rect{fill:url(#grad)}</style></svg>"##);
+        assert!(out.contains("url(#grad)"));
+    }
+
+    #[test]
+    fn removes_doctype_entities() {
+        let out = sanitize(
+            r#"<?xml version="1.0"?><!DOCTYPE svg [<!ENTITY x SYSTEM "file:///etc/passwd">]><svg>&x;</svg>"#,
+        );
+        assert!(!out.contains("ENTITY"));
+        assert!(!out.contains("passwd"));
+    }
+
+    #[test]
+    fn rejects_non_svg() {
+        assert!(sanitize_svg(b"<html><script>alert(1)</script></html>").is_err());
+        assert!(sanitize_svg(b"not xml").is_err());
+        assert!(sanitize_svg(b"<svg><rect></svg>").is_err());
+        assert!(sanitize_svg(b"<svg><script>").is_err());
+    }
+
+    #[test]
+    fn sniffs_svg() {
+        assert!(sniff_svg(b"<svg/>", false));
+        assert!(sniff_svg(
+            b"\xEF\xBB\xBF<?xml version=\"1.0\"?>\n<!-- c --><!DOCTYPE svg><SVG>",
+            false
+        ));
+        assert!(!sniff_svg(b"<html><svg/></html>", false));
+        assert!(!sniff_svg(b"text<svg/>", false));
+        assert!(!sniff_svg(b"\x89PNG\r\n", false));
+    }
+
+    #[test]
+    fn sniffs_truncated_head() {
+        // the head ends before the first element, treated as SVG only when the file is longer
+        let head = b"<?xml version=\"1.0\"?><!-- a long comment";
+        assert!(sniff_svg(head, true));
+        assert!(!sniff_svg(head, false));
+        assert!(sniff_svg(b"<?xml version=\"1.0\"?>\n", true));
+    }
+
+    #[test]
+    fn svg_mime() {
+        assert!(is_svg_mime("image/svg+xml"));
+        assert!(is_svg_mime("Image/SVG+XML; charset=utf-8"));
+        assert!(!is_svg_mime("image/png"));
+    }
+}
@@ -0,0 +1,86 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+/// Token bucket rate limiter for a byte stream
+pub struct ThrottledReader<R> {
+    inner: R,
+    /// Bytes per second
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<R> ThrottledReader<R> {
+    pub fn new(inner: R, rate: u64) -> Self {
+        let rate = rate.max(1);
+        Self {
+            inner,
+            rate,
+            // allow an initial burst of 1s worth of data
+            tokens: rate as f64,
+            last: Instant::now(),
+            sleep: None,
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ThrottledReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if let Some(s) = self.sleep.as_mut() {
+                ready!(s.as_mut().poll(cx));
+                self.sleep = None;
+            }
+            self.refill();
+            if self.tokens >= 1.0 {
+                break;
+            }
+            // wait until there is enough budget for a reasonable chunk
+            let want = buf.remaining().min(self.rate as usize / 10).max(1) as f64;
+            let wait = (want - self.tokens) / self.rate as f64;
+            self.sleep = Some(Box::pin(tokio::time::sleep(Duration::from_secs_f64(wait))));
+        }
+
+        let allowed = (self.tokens as usize).min(buf.remaining());
+        let mut limited = buf.take(allowed);
+        let res = Pin::new(&mut self.inner).poll_read(cx, &mut limited);
+        let n = limited.filled().len();
+        if let Poll::Ready(Ok(())) = res {
+            // SAFETY: the bytes were initialized by the inner reader
+            unsafe {
+                buf.assume_init(n);
+            }
+            buf.advance(n);
+            self.tokens -= n as f64;
+        }
+        res
+    }
+}
+
+impl<R: AsyncSeek + Unpin> AsyncSeek for ThrottledReader<R> {
+    fn start_seek(mut self: Pin<&mut Self>, position: io::SeekFrom) -> io::Result<()> {
+        Pin::new(&mut self.inner).start_seek(position)
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Pin::new(&mut self.inner).poll_complete(cx)
+    }
+}
@@ -0,0 +1,77 @@
+use std::time::Duration;
+
+use anyhow::Error;
+use log::{error, info, warn};
+use tokio::task::JoinHandle;
+
+use crate::db::{Database, StorageClass};
+use crate::filesystem::FileStore;
+use crate::settings::Settings;
+
+/// Number of candidates loaded at once
+const BATCH_SIZE: u32 = 100;
+
+/// Moves files which have not been accessed recently into cold storage
+pub struct StorageTiering {
+    db: Database,
+    fs: FileStore,
+    after_days: u32,
+    interval: Duration,
+}
+
+impl StorageTiering {
+    pub fn new(settings: &Settings, db: Database, fs: FileStore) -> Option<Self> {
+        settings.cold_storage.as_ref().map(|c| Self {
+            db,
+            fs,
+            after_days: c.after_days,
+            interval: Duration::from_secs(c.interval.unwrap_or(3600)),
+        })
+    }
+
+    pub fn start(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = self.run_once().await {
+                    error!("Storage tiering failed: {}", e);
+                }
+                tokio::time::sleep(self.interval).await;
+            }
+        })
+    }
+
+    async fn run_once(&self) -> Result<(), Error> {
+        // files which could not be moved stay candidates, skip past them
+        // so they are retried on the next run without blocking the others
+        let mut failed = 0;
+        loop {
+            let files = self
+                .db
+                .list_tiering_candidates(self.after_days, failed, BATCH_SIZE)
+                .await?;
+            for f in &files {
+                let id_hex = hex::encode(&f.id);
+                if let Err(e) = self.fs.move_to_cold(&f.id).await {
+                    warn!("Failed to move {} to cold storage: {}", id_hex, e);
+                    failed += 1;
+                    continue;
+                }
+                self.db.set_storage_class(&f.id, StorageClass::Cold).await?;
+                info!("Moved {} to cold storage", id_hex);
+            }
+            if files.len() < BATCH_SIZE as usize {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Move a file back into hot storage after it was accessed
+    pub async fn rehydrate(db: &Database, fs: &FileStore, id: &Vec<u8>) -> Result<(), Error> {
+        if fs.rehydrate(id).await? {
+            db.set_storage_class(id, StorageClass::Hot).await?;
+            info!("Rehydrated {} from cold storage", hex::encode(id));
+        }
+        Ok(())
+    }
+}
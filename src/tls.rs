@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Error};
+use instant_acme::{
+    Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, LetsEncrypt,
+    NewAccount, NewOrder, OrderStatus,
+};
+use log::{error, info};
+use rcgen::{CertificateParams, DistinguishedName, KeyPair};
+use rocket::config::TlsConfig;
+use rocket::serde::json::serde_json;
+use rocket::{routes, Route, State};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::settings::{AcmeSettings, TlsSettings};
+
+/// Pending HTTP-01 challenges, token -> key authorization
+#[derive(Clone, Default)]
+pub struct AcmeChallenges(Arc<RwLock<HashMap<String, String>>>);
+
+pub fn acme_routes() -> Vec<Route> {
+    routes![acme_challenge]
+}
+
+#[rocket::get("/.well-known/acme-challenge/<token>")]
+async fn acme_challenge(token: &str, challenges: &State<AcmeChallenges>) -> Option<String> {
+    challenges.0.read().unwrap().get(token).cloned()
+}
+
+/// Rocket TLS config using the certificate files
+pub fn tls_config(settings: &TlsSettings) -> TlsConfig {
+    TlsConfig::from_paths(&settings.cert, &settings.key)
+}
+
+/// Requests and renews the certificate from an ACME CA using HTTP-01 challenges,
+/// the challenges are answered on the plain HTTP listeners
+#[derive(Clone)]
+pub struct CertManager {
+    tls: TlsSettings,
+    acme: AcmeSettings,
+    challenges: AcmeChallenges,
+    /// Incremented each time a new certificate is written, 0 until a certificate exists
+    issued: Arc<watch::Sender<u64>>,
+}
+
+impl CertManager {
+    pub fn new(tls: &TlsSettings, acme: &AcmeSettings) -> Self {
+        let exists = Path::new(&tls.cert).exists() && Path::new(&tls.key).exists();
+        let (issued, _) = watch::channel(if exists { 1 } else { 0 });
+        Self {
+            tls: tls.clone(),
+            acme: acme.clone(),
+            challenges: AcmeChallenges::default(),
+            issued: Arc::new(issued),
+        }
+    }
+
+    pub fn challenges(&self) -> AcmeChallenges {
+        self.challenges.clone()
+    }
+
+    /// Notified when a new certificate was written
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.issued.subscribe()
+    }
+
+    pub fn start(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                let next = if self.needs_renewal() {
+                    match self.issue().await {
+                        Ok(()) => {
+                            info!("New certificate for {}", self.acme.domains.join(", "));
+                            self.issued.send_modify(|v| *v += 1);
+                            Duration::from_secs(43200)
+                        }
+                        Err(e) => {
+                            error!("Failed to request certificate: {}", e);
+                            Duration::from_secs(3600)
+                        }
+                    }
+                } else {
+                    Duration::from_secs(43200)
+                };
+                tokio::time::sleep(next).await;
+            }
+        })
+    }
+
+    fn needs_renewal(&self) -> bool {
+        let max_age = Duration::from_secs(self.acme.renew_after_days.unwrap_or(60) as u64 * 86400);
+        match Path::new(&self.tls.cert)
+            .metadata()
+            .and_then(|m| m.modified())
+        {
+            Ok(modified) => SystemTime::now()
+                .duration_since(modified)
+                .is_ok_and(|age| age > max_age),
+            Err(_) => true,
+        }
+    }
+
+    fn account_path(&self) -> PathBuf {
+        match &self.acme.account {
+            Some(a) => PathBuf::from(a),
+            None => PathBuf::from(format!("{}.account.json", self.tls.cert)),
+        }
+    }
+
+    /// Load the ACME account or register a new one
+    async fn account(&self) -> Result<Account, Error> {
+        let path = self.account_path();
+        if path.exists() {
+            let creds: AccountCredentials = serde_json::from_slice(&tokio::fs::read(&path).await?)?;
+            return Ok(Account::from_credentials(creds).await?);
+        }
+        let contact: Vec<String> = self
+            .acme
+            .email
+            .iter()
+            .map(|e| format!("mailto:{}", e))
+            .collect();
+        let contact: Vec<&str> = contact.iter().map(|c| c.as_str()).collect();
+        let (account, creds) = Account::create(
+            &NewAccount {
+                contact: &contact,
+                terms_of_service_agreed: true,
+                only_return_existing: false,
+            },
+            self.acme
+                .directory
+                .as_deref()
+                .unwrap_or(LetsEncrypt::Production.url()),
+            None,
+        )
+        .await?;
+        tokio::fs::write(&path, serde_json::to_vec(&creds)?).await?;
+        info!(
+            "Registered ACME account, credentials saved to {}",
+            path.display()
+        );
+        Ok(account)
+    }
+
+    async fn issue(&self) -> Result<(), Error> {
+        let account = self.account().await?;
+        let identifiers: Vec<Identifier> = self
+            .acme
+            .domains
+            .iter()
+            .map(|d| Identifier::Dns(d.clone()))
+            .collect();
+        let mut order = account
+            .new_order(&NewOrder {
+                identifiers: &identifiers,
+            })
+            .await?;
+
+        let mut tokens = Vec::new();
+        for authz in order.authorizations().await? {
+            match authz.status {
+                AuthorizationStatus::Pending => {}
+                AuthorizationStatus::Valid => continue,
+                s => bail!("Authorization for {:?} is {:?}", authz.identifier, s),
+            }
+            let challenge = match authz
+                .challenges
+                .iter()
+                .find(|c| c.r#type == ChallengeType::Http01)
+            {
+                Some(c) => c,
+                None => bail!("No HTTP-01 challenge for {:?}", authz.identifier),
+            };
+            let key_auth = order.key_authorization(challenge);
+            self.challenges
+                .0
+                .write()
+                .unwrap()
+                .insert(challenge.token.clone(), key_auth.as_str().to_string());
+            tokens.push(challenge.token.clone());
+            order.set_challenge_ready(&challenge.url).await?;
+        }
+
+        let mut delay = Duration::from_millis(500);
+        let status = loop {
+            tokio::time::sleep(delay).await;
+            let status = order.refresh().await?.status;
+            if status != OrderStatus::Pending || delay > Duration::from_secs(60) {
+                break status;
+            }
+            delay *= 2;
+        };
+        {
+            let mut challenges = self.challenges.0.write().unwrap();
+            for t in &tokens {
+                challenges.remove(t);
+            }
+        }
+        if status != OrderStatus::Ready {
+            bail!("Order is {:?}", status);
+        }
+
+        let mut params = CertificateParams::new(self.acme.domains.clone())?;
+        params.distinguished_name = DistinguishedName::new();
+        let key = KeyPair::generate()?;
+        let csr = params.serialize_request(&key)?;
+        order.finalize(csr.der()).await?;
+        let mut chain = None;
+        for _ in 0..30 {
+            chain = order.certificate().await?;
+            if chain.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        let chain = match chain {
+            Some(c) => c,
+            None => bail!("Certificate was not issued"),
+        };
+
+        tokio::fs::write(&self.tls.key, key.serialize_pem()).await?;
+        std::fs::set_permissions(&self.tls.key, std::fs::Permissions::from_mode(0o600))?;
+        tokio::fs::write(&self.tls.cert, chain).await?;
+        Ok(())
+    }
+}
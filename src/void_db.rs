@@ -37,6 +37,7 @@ impl VoidFile {
     }
 }
 
+#[derive(Clone)]
 pub struct VoidCatDb {
     pub pool: PgPool,
 }
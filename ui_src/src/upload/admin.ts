@@ -11,7 +11,7 @@ export class Route96 {
   }
 
   async getSelf() {
-    const rsp = await this.#req("/admin/self", "GET");
+    const rsp = await this.#req("/v1/admin/self", "GET");
     const data =
       await this.#handleResponse<AdminResponse<{ is_admin: boolean }>>(rsp);
     return data;
@@ -19,7 +19,7 @@ export class Route96 {
 
   async listFiles(page = 0, count = 10) {
     const rsp = await this.#req(
-      `/admin/files?page=${page}&count=${count}`,
+      `/v1/admin/files?page=${page}&count=${count}`,
       "GET",
     );
     const data = await this.#handleResponse<AdminResponseFileList>(rsp);